@@ -0,0 +1,254 @@
+//! Color glyphs drawn as vector layers. ttf-parser 0.18 doesn't read `COLR` or `CPAL`, so
+//! the version 0 layer records and the first palette are read from the raw tables here.
+//! The gradients of `COLR` version 1 aren't supported, and those glyphs are drawn from
+//! their version 0 layers if the font has them, or otherwise in the text color
+
+use crate::printpdf::{lopdf::content::Operation, lopdf::Object, Color, PdfLayer, Pt, Rgb};
+use cosmic_text::rustybuzz::ttf_parser::{self, GlyphId, Tag};
+
+/// whether the font has color glyphs, as `COLR` layers or `sbix` or `CBDT` bitmaps
+pub fn has_color_glyphs(face: &ttf_parser::Face) -> bool {
+    let tables = face.tables();
+    face.raw_face().table(Tag::from_bytes(b"COLR")).is_some()
+        || tables.sbix.is_some()
+        || tables.cbdt.is_some()
+}
+
+/// the outlines a `COLR` glyph is drawn with, bottom layer first, each with its color from
+/// the first palette, or None for the text color. None if it isn't a color glyph
+pub fn layers(face: &ttf_parser::Face, glyph_id: GlyphId) -> Option<Vec<(GlyphId, Option<Rgb>)>> {
+    let colr = face.raw_face().table(Tag::from_bytes(b"COLR"))?;
+    let base_glyphs = u16_at(colr, 2)? as usize;
+    let base_records = u32_at(colr, 4)? as usize;
+    let layer_records = u32_at(colr, 8)? as usize;
+
+    // six-byte base glyph records of the glyph, its first layer and how many it has
+    let record = (0..base_glyphs)
+        .map(|i| base_records + i * 6)
+        .find(|&record| u16_at(colr, record) == Some(glyph_id.0))?;
+    let first = u16_at(colr, record + 2)? as usize;
+    let count = u16_at(colr, record + 4)? as usize;
+
+    let cpal = face.raw_face().table(Tag::from_bytes(b"CPAL"));
+    (first..first + count)
+        .map(|layer| {
+            // four-byte layer records of the outline's glyph and its palette entry
+            let layer = layer_records + layer * 4;
+            let outline = GlyphId(u16_at(colr, layer)?);
+            let entry = u16_at(colr, layer + 2)?;
+            Some((outline, cpal.and_then(|cpal| palette_color(cpal, entry))))
+        })
+        .collect()
+}
+
+/// the color of the entry in the first palette, or None for 0xFFFF, which is the text color.
+/// Translucent colors are drawn opaque
+fn palette_color(cpal: &[u8], entry: u16) -> Option<Rgb> {
+    if entry >= u16_at(cpal, 2)? {
+        return None;
+    }
+    let color_records = u32_at(cpal, 8)? as usize;
+    let first = u16_at(cpal, 12)? as usize;
+    let record = color_records + (first + entry as usize) * 4;
+    let [blue, green, red, _alpha] = cpal.get(record..record + 4)?.try_into().ok()?;
+    Some(Rgb::new(
+        red as f32 / 255.0,
+        green as f32 / 255.0,
+        blue as f32 / 255.0,
+        None,
+    ))
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(
+        data.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+/// draw the layers of a `COLR` glyph with its origin at `origin`, or otherwise its outline
+/// in the text color. `scale` is points per font unit
+pub fn draw(
+    layer: &mut PdfLayer,
+    face: &ttf_parser::Face,
+    glyph_id: GlyphId,
+    origin: (Pt, Pt),
+    scale: f32,
+    foreground: Color,
+) {
+    let Some(layers) = layers(face, glyph_id) else {
+        fill(layer, face, glyph_id, origin, scale, foreground);
+        return;
+    };
+    for (outline, color) in layers {
+        let color = color.map_or_else(|| foreground.clone(), Color::Rgb);
+        fill(layer, face, outline, origin, scale, color);
+    }
+}
+
+fn fill(
+    layer: &mut PdfLayer,
+    face: &ttf_parser::Face,
+    glyph_id: GlyphId,
+    origin: (Pt, Pt),
+    scale: f32,
+    color: Color,
+) {
+    let mut path = GlyphPath {
+        origin,
+        scale,
+        current: (0.0, 0.0),
+        operations: vec![],
+    };
+    if face.outline_glyph(glyph_id, &mut path).is_none() {
+        return;
+    }
+    layer.set_fill_color(color);
+    for operation in path.operations {
+        layer.add_operation(operation);
+    }
+    layer.add_operation(Operation::new("f", vec![]));
+}
+
+/// a glyph outline as path operations, in points from the origin
+struct GlyphPath {
+    origin: (Pt, Pt),
+    /// points per font unit
+    scale: f32,
+    /// the end of the last segment, in font units, for turning quadratic curves into cubics
+    current: (f32, f32),
+    operations: Vec<Operation>,
+}
+
+impl GlyphPath {
+    fn push(&mut self, operator: &str, points: &[(f32, f32)]) {
+        let operands = points
+            .iter()
+            .flat_map(|&(x, y)| {
+                [
+                    Object::Real(self.origin.0 .0 + x * self.scale),
+                    Object::Real(self.origin.1 .0 + y * self.scale),
+                ]
+            })
+            .collect();
+        self.operations.push(Operation::new(operator, operands));
+        if let Some(&last) = points.last() {
+            self.current = last;
+        }
+    }
+}
+
+impl ttf_parser::OutlineBuilder for GlyphPath {
+    fn move_to(&mut self, x: f32, y: f32) {
+        self.push("m", &[(x, y)]);
+    }
+
+    fn line_to(&mut self, x: f32, y: f32) {
+        self.push("l", &[(x, y)]);
+    }
+
+    fn quad_to(&mut self, x1: f32, y1: f32, x: f32, y: f32) {
+        let (x0, y0) = self.current;
+        let c1 = (x0 + (x1 - x0) * 2.0 / 3.0, y0 + (y1 - y0) * 2.0 / 3.0);
+        let c2 = (x + (x1 - x) * 2.0 / 3.0, y + (y1 - y) * 2.0 / 3.0);
+        self.push("c", &[c1, c2, (x, y)]);
+    }
+
+    fn curve_to(&mut self, x1: f32, y1: f32, x2: f32, y2: f32, x: f32, y: f32) {
+        self.push("c", &[(x1, y1), (x2, y2), (x, y)]);
+    }
+
+    fn close(&mut self) {
+        self.operations.push(Operation::new("h", vec![]));
+    }
+}
+
+#[test]
+fn test_colr_layers() {
+    let font = std::fs::read("example/assets/fonts/PT_Sans/PTSans-Regular.ttf").unwrap();
+    let face = ttf_parser::Face::parse(&font, 0).unwrap();
+    let [o, i] = ['o', 'i'].map(|c| face.glyph_index(c).unwrap());
+
+    // "o" is drawn as a red "o" under an "i" in the text color
+    let mut colr = vec![];
+    for value in [0u16, 1] {
+        colr.extend(value.to_be_bytes());
+    }
+    colr.extend(14u32.to_be_bytes());
+    colr.extend(20u32.to_be_bytes());
+    colr.extend(2u16.to_be_bytes());
+    for value in [o.0, 0, 2, o.0, 0, i.0, 0xFFFF] {
+        colr.extend(value.to_be_bytes());
+    }
+    // one palette of one color, stored as blue, green, red and alpha
+    let mut cpal = vec![];
+    for value in [0u16, 1, 1, 1] {
+        cpal.extend(value.to_be_bytes());
+    }
+    cpal.extend(14u32.to_be_bytes());
+    cpal.extend(0u16.to_be_bytes());
+    cpal.extend([0, 0, 255, 255]);
+
+    // the font with the two tables added to its sorted table directory
+    let tables = u16_at(&font, 4).unwrap() as usize;
+    let mut records: Vec<([u8; 4], Vec<u8>)> = (0..tables)
+        .map(|i| {
+            let record = 12 + i * 16;
+            let offset = u32_at(&font, record + 8).unwrap() as usize;
+            let length = u32_at(&font, record + 12).unwrap() as usize;
+            let tag = font[record..record + 4].try_into().unwrap();
+            (tag, font[offset..offset + length].to_vec())
+        })
+        .collect();
+    records.extend([(*b"COLR", colr), (*b"CPAL", cpal)]);
+    records.sort_by_key(|(tag, _)| *tag);
+    let mut colored = font[..4].to_vec();
+    colored.extend((records.len() as u16).to_be_bytes());
+    colored.extend([0; 6]);
+    let mut offset = 12 + records.len() * 16;
+    for (tag, data) in &records {
+        colored.extend(tag);
+        colored.extend(0u32.to_be_bytes());
+        colored.extend((offset as u32).to_be_bytes());
+        colored.extend((data.len() as u32).to_be_bytes());
+        offset += (data.len() + 3) / 4 * 4;
+    }
+    for (_, data) in &records {
+        colored.extend(data);
+        colored.resize((colored.len() + 3) / 4 * 4, 0);
+    }
+
+    let face = ttf_parser::Face::parse(&colored, 0).unwrap();
+    assert!(has_color_glyphs(&face));
+    let red = Rgb::new(1.0, 0.0, 0.0, None);
+    assert_eq!(layers(&face, o), Some(vec![(o, Some(red)), (i, None)]));
+    assert_eq!(layers(&face, i), None);
+
+    let mut layer = PdfLayer::new("text");
+    let foreground = Color::Rgb(Rgb::new(0.0, 0.0, 1.0, None));
+    draw(&mut layer, &face, o, (Pt(10.0), Pt(20.0)), 0.01, foreground);
+    let stream = crate::printpdf::lopdf::Stream::from(layer);
+    let content = crate::printpdf::lopdf::content::Content::decode(&stream.content).unwrap();
+    let fills: Vec<Vec<f32>> = content
+        .operations
+        .iter()
+        .filter(|operation| operation.operator == "rg")
+        .map(|operation| {
+            let operands = operation.operands.iter();
+            operands
+                .map(|operand| operand.as_float().unwrap())
+                .collect()
+        })
+        .collect();
+    assert_eq!(fills, vec![vec![1.0, 0.0, 0.0], vec![0.0, 0.0, 1.0]]);
+    let filled = content
+        .operations
+        .iter()
+        .filter(|operation| operation.operator == "f");
+    assert_eq!(filled.count(), 2);
+}
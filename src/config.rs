@@ -0,0 +1,330 @@
+use cosmic_text::Family;
+
+use crate::printpdf::{Mm, PageLabelStyle};
+
+/// Settings for the book as a whole
+pub struct BookConfig {
+    /// Title shown in the document metadata and the page header
+    pub title: String,
+    /// Subtitle, set below the title on the title page
+    pub subtitle: Option<String>,
+    /// Authors, shown on the title page and in the document metadata
+    pub authors: Vec<String>,
+    /// Publisher, set at the bottom of the title page
+    pub publisher: Option<String>,
+    /// Copyright page on the verso of the title page
+    pub copyright: Option<CopyrightConfig>,
+    /// Dedication, on its own page after the copyright page
+    pub dedication: Option<String>,
+    /// List of numbered figures, with their page numbers, in the front matter
+    pub list_of_figures: bool,
+    /// Page number style for the front matter (title page, TOC, preface)
+    pub front_matter_numbering: PageLabelStyle,
+    /// Page number style for the chapters. Restarts at 1 for the first chapter
+    pub body_numbering: PageLabelStyle,
+    /// Where the page number is placed in the footer
+    pub footer: FooterLayout,
+    /// How chapter opening pages are laid out
+    pub chapters: ChapterConfig,
+    /// Dimensions of the print-on-demand cover
+    pub print_cover: PrintCoverConfig,
+    /// Font family for math symbols, ideally an OpenType math font, whose MATH table sets
+    /// the sizes and spacing of formulas. Latin letters and digits are set in the serif font
+    pub math_font: String,
+    /// Quotes, dashes and non-breaking spaces in the text
+    pub typography: TypographyConfig,
+    /// Fonts for each family, and the fonts to fall back to for characters they don't have
+    pub fonts: FontConfig,
+    /// Language of the text, as a BCP 47 tag, eg "en-US" or "he". Used for picking fonts
+    pub language: String,
+    /// Base direction of paragraphs and headings, unless set with `{dir=rtl}` or `{dir=ltr}`
+    /// at the end of the block
+    pub direction: TextDirection,
+    /// How lines run on the page, unless set for a chapter with `{writing-mode=vertical-rl}`
+    /// or `{writing-mode=horizontal-tb}` after its title. The title pages are always horizontal
+    pub writing_mode: WritingMode,
+}
+
+impl Default for BookConfig {
+    fn default() -> Self {
+        Self {
+            title: String::new(),
+            subtitle: None,
+            authors: vec![],
+            publisher: None,
+            copyright: None,
+            dedication: None,
+            list_of_figures: false,
+            front_matter_numbering: PageLabelStyle::LowerRoman,
+            body_numbering: PageLabelStyle::Decimal,
+            footer: FooterLayout::Centered,
+            chapters: ChapterConfig::default(),
+            print_cover: PrintCoverConfig::default(),
+            math_font: "DejaVu Math TeX Gyre".to_owned(),
+            typography: TypographyConfig::default(),
+            fonts: FontConfig::default(),
+            language: "en-US".to_owned(),
+            direction: TextDirection::Auto,
+            writing_mode: WritingMode::Horizontal,
+        }
+    }
+}
+
+/// Direction of the text in a paragraph as a whole. Runs of the other direction within it
+/// are reordered by the Unicode bidirectional algorithm. Right-to-left paragraphs are
+/// aligned to the right
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TextDirection {
+    /// from the first letter with a direction, eg right-to-left for a paragraph of Hebrew
+    Auto,
+    LeftToRight,
+    RightToLeft,
+}
+
+impl TextDirection {
+    /// the invisible mark that sets the direction when it starts a paragraph
+    pub fn mark(self) -> Option<char> {
+        match self {
+            TextDirection::Auto => None,
+            TextDirection::LeftToRight => Some('\u{200E}'),
+            TextDirection::RightToLeft => Some('\u{200F}'),
+        }
+    }
+}
+
+/// Which way lines run on the page, for the book as a whole or a single chapter
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WritingMode {
+    /// lines run left to right, from the top of the page down
+    Horizontal,
+    /// lines run top to bottom, in columns from the right of the page to the left, as in
+    /// Japanese. Chinese and Japanese characters stand upright and other text is turned on
+    /// its side, along with figures, code and formulas. A book written this way is read
+    /// from right to left
+    VerticalRightToLeft,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FooterLayout {
+    /// Page numbers are centered on every page
+    Centered,
+    /// Page numbers sit on the outer edge: right on recto (odd) pages, left on verso (even) pages
+    Outer,
+}
+
+#[derive(Clone, Default)]
+pub struct CopyrightConfig {
+    /// Year of the copyright notice
+    pub year: Option<u32>,
+    /// eg "First edition"
+    pub edition: Option<String>,
+    pub isbn: Option<String>,
+    /// License text, set below the notice
+    pub license: Option<String>,
+}
+
+/// How chapters open: their label and title, which page they start on, and how their
+/// first paragraph is set
+pub struct ChapterConfig {
+    /// Label set above the chapter title, followed by the chapter number. eg "Chapter 3"
+    pub label: Option<String>,
+    /// Distance from the top of the page to the chapter label
+    pub title_drop: Mm,
+    /// Always start chapters on a recto (odd) page, inserting a blank verso page if needed
+    pub recto_start: bool,
+    /// Number of lines the first letter of a chapter drops through, as a drop cap, with the
+    /// text wrapped around it. 0 turns this off
+    pub drop_cap_lines: usize,
+    /// Set the first line of a chapter in small caps
+    pub first_line_small_caps: bool,
+}
+
+impl Default for ChapterConfig {
+    fn default() -> Self {
+        Self {
+            label: Some("Chapter".to_owned()),
+            title_drop: Mm(80.0),
+            recto_start: false,
+            drop_cap_lines: 0,
+            first_line_small_caps: false,
+        }
+    }
+}
+
+/// Punctuation and spacing applied to the text before it's laid out, and how lines of
+/// Chinese and Japanese are broken and spaced
+pub struct TypographyConfig {
+    /// Turn straight quotes into curly quotes, `--` and `---` into en and em dashes,
+    /// and `...` into an ellipsis
+    pub smart_punctuation: bool,
+    /// Quotation marks used by smart punctuation, or None for the usual ones of the book's
+    /// language
+    pub quotes: Option<QuoteStyle>,
+    /// Keep units on the same line as the number before them, eg "10 ms"
+    pub nbsp_before_units: bool,
+    /// Keep words of up to this many letters, eg "a" or "I", on the same line as the
+    /// word after them. 0 turns this off
+    pub nbsp_after_short_words: usize,
+    /// Make the spaces in inline code non-breaking, so it isn't split across lines
+    pub nbsp_in_code: bool,
+    /// Kinsoku shori: keep closing brackets, small kana and punctuation such as `。` from
+    /// starting a line, and opening brackets from ending one
+    pub kinsoku: bool,
+    /// Set a thin space between Chinese or Japanese text and Latin letters or digits
+    pub cjk_latin_spacing: bool,
+    /// Take out the blank half of full-width punctuation next to other punctuation,
+    /// so that eg `。」` is one and a half ems wide rather than two
+    pub compress_punctuation: bool,
+}
+
+impl Default for TypographyConfig {
+    fn default() -> Self {
+        Self {
+            smart_punctuation: true,
+            quotes: None,
+            nbsp_before_units: true,
+            nbsp_after_short_words: 1,
+            nbsp_in_code: true,
+            kinsoku: true,
+            cjk_latin_spacing: false,
+            compress_punctuation: true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QuoteStyle {
+    /// “double” and ‘single’
+    English,
+    /// ‘single’ and “double”
+    British,
+    /// „double“ and ‚single‘
+    German,
+    /// « double » and ‹ single ›, with narrow no-break spaces inside the quotes and before `;:!?`
+    French,
+}
+
+impl QuoteStyle {
+    /// the usual quotes for a BCP 47 language tag, English ones if it isn't known
+    pub fn for_language(language: &str) -> Self {
+        let lower = language.to_ascii_lowercase();
+        match lower.split(['-', '_']).next().unwrap_or_default() {
+            "de" => QuoteStyle::German,
+            "fr" => QuoteStyle::French,
+            "en" if lower.starts_with("en-gb") => QuoteStyle::British,
+            _ => QuoteStyle::English,
+        }
+    }
+}
+
+/// Font families by name, in the order they're tried for each character. The first
+/// font of each family is its default
+#[derive(Clone)]
+pub struct FontConfig {
+    pub serif: Vec<String>,
+    pub sans_serif: Vec<String>,
+    pub monospace: Vec<String>,
+    /// Tried after the family's own fonts
+    pub emoji: Vec<String>,
+    /// Chinese, Japanese and Korean fonts, tried after the family's own fonts and before emoji
+    pub cjk: Vec<String>,
+    /// Load the fonts installed on the system, as well as those in `assets/fonts`.
+    /// Characters that no font in the chain has may then be set in any of them,
+    /// so turn this off for the same output on every machine
+    pub system_fonts: bool,
+    /// Axis values for variable fonts in the body text, which is serif
+    pub body: FontAxes,
+    /// Axis values for variable fonts in headings, which are sans-serif
+    pub headings: FontAxes,
+    /// Axis values for variable fonts in code, which is monospace
+    pub code: FontAxes,
+}
+
+/// Where to instance a variable font. Static fonts ignore these
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FontAxes {
+    /// `wght` of regular text. Bold text is as much heavier than this as it is
+    /// than the regular weight of 400
+    pub weight: f32,
+    /// `wdth`, as a percentage of the normal width
+    pub width: f32,
+    /// `opsz`, or None for 12 for text smaller than 18pt and 24 for larger display text.
+    /// Every optical size is a font of its own in the PDF, so it doesn't follow each size
+    pub optical_size: Option<f32>,
+}
+
+impl FontAxes {
+    /// the `opsz` for text of the size
+    pub fn optical_size(&self, font_size: f32) -> f32 {
+        self.optical_size
+            .unwrap_or(if font_size < 18.0 { 12.0 } else { 24.0 })
+    }
+}
+
+impl Default for FontAxes {
+    fn default() -> Self {
+        Self {
+            weight: 400.0,
+            width: 100.0,
+            optical_size: None,
+        }
+    }
+}
+
+impl Default for FontConfig {
+    fn default() -> Self {
+        Self {
+            serif: vec!["PT Serif".to_owned()],
+            sans_serif: vec!["PT Sans".to_owned()],
+            monospace: vec!["Fira Code".to_owned()],
+            emoji: vec!["Noto Color Emoji".to_owned()],
+            cjk: vec!["Noto Sans CJK SC".to_owned()],
+            system_fonts: true,
+            body: FontAxes::default(),
+            headings: FontAxes::default(),
+            code: FontAxes::default(),
+        }
+    }
+}
+
+impl FontConfig {
+    /// the fonts to try, in order, for text in the family
+    pub fn chain<'a>(&'a self, family: Family<'a>) -> Vec<&'a str> {
+        let own = match family {
+            Family::Name(name) => vec![name],
+            Family::SansSerif => self.sans_serif.iter().map(String::as_str).collect(),
+            Family::Monospace => self.monospace.iter().map(String::as_str).collect(),
+            Family::Serif | Family::Cursive | Family::Fantasy => {
+                self.serif.iter().map(String::as_str).collect()
+            }
+        };
+        let fallback = self.cjk.iter().chain(&self.emoji).map(String::as_str);
+        own.into_iter().chain(fallback).collect()
+    }
+
+    /// the axis values for text in the family
+    pub fn axes(&self, family: Family) -> FontAxes {
+        match family {
+            Family::Serif => self.body,
+            Family::SansSerif => self.headings,
+            Family::Monospace => self.code,
+            _ => FontAxes::default(),
+        }
+    }
+}
+
+pub struct PrintCoverConfig {
+    /// Thickness of one sheet (two pages) of the interior paper, used for the spine width
+    pub paper_thickness: Mm,
+    /// Extra artwork around the outside of the cover, trimmed off after printing
+    pub bleed: Mm,
+}
+
+impl Default for PrintCoverConfig {
+    fn default() -> Self {
+        Self {
+            paper_thickness: Mm(0.1),
+            bleed: Mm(3.0),
+        }
+    }
+}
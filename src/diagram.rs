@@ -0,0 +1,1133 @@
+//! Diagrams from text, for ```` ```dot ```` and ```` ```sequence ```` code blocks.
+//!
+//! Both are laid out here and written as SVG, which is then embedded like any other
+//! SVG figure. Labels use the generic `sans-serif` family, which resolves to the book's
+//! sans-serif font. Widths are measured by the caller, in pixels at [`FONT_SIZE`].
+
+use std::{collections::HashMap, fmt::Write};
+
+/// font size of labels, in pixels
+pub const FONT_SIZE: f32 = 14.0;
+const LINE_HEIGHT: f32 = 18.0;
+const MARGIN: f32 = 10.0;
+
+/// measures the width of a line of text, in pixels
+pub type Measure<'a> = dyn FnMut(&str) -> f32 + 'a;
+
+/// Lays out a Graphviz DOT graph, eg `digraph { a -> b [label="poll"] }`, as an SVG.
+///
+/// Supports node and edge statements, `node`/`edge` defaults, subgraphs (which are flattened),
+/// `rankdir=LR`, and the `label`, `shape` and `style=dashed` attributes.
+/// Nodes are ranked along the longest path and ordered to reduce edge crossings.
+/// Fails on anything else, such as HTML labels or ports, describing what it couldn't read
+pub fn dot_to_svg(source: &str, measure: &mut Measure) -> Result<String, String> {
+    let graph = parse_dot(source)?;
+    Ok(layout_graph(&graph, measure))
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct Graph {
+    directed: bool,
+    left_to_right: bool,
+    nodes: Vec<GraphNode>,
+    edges: Vec<GraphEdge>,
+}
+
+#[derive(Debug, PartialEq)]
+struct GraphNode {
+    id: String,
+    label: String,
+    shape: Shape,
+}
+
+#[derive(Debug, PartialEq)]
+struct GraphEdge {
+    from: usize,
+    to: usize,
+    label: Option<String>,
+    dashed: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Shape {
+    Box,
+    Ellipse,
+    Circle,
+    DoubleCircle,
+    Diamond,
+    /// just the label
+    Plain,
+}
+
+impl Shape {
+    fn parse(shape: &str) -> Self {
+        match shape {
+            "box" | "rect" | "rectangle" | "square" => Shape::Box,
+            "circle" => Shape::Circle,
+            "doublecircle" => Shape::DoubleCircle,
+            "diamond" => Shape::Diamond,
+            "plaintext" | "plain" | "none" => Shape::Plain,
+            _ => Shape::Ellipse,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Id(String),
+    Sym(&'static str),
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut chars = source.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            '#' => while chars.next_if(|&c| c != '\n').is_some() {},
+            '/' if chars.peek() == Some(&'/') => while chars.next_if(|&c| c != '\n').is_some() {},
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut prev = ' ';
+                for c in chars.by_ref() {
+                    if prev == '*' && c == '/' {
+                        break;
+                    }
+                    prev = c;
+                }
+            }
+            '{' => tokens.push(Token::Sym("{")),
+            '}' => tokens.push(Token::Sym("}")),
+            '[' => tokens.push(Token::Sym("[")),
+            ']' => tokens.push(Token::Sym("]")),
+            '=' => tokens.push(Token::Sym("=")),
+            ';' => tokens.push(Token::Sym(";")),
+            ',' => tokens.push(Token::Sym(",")),
+            '-' if chars.next_if_eq(&'>').is_some() => tokens.push(Token::Sym("->")),
+            '-' if chars.next_if_eq(&'-').is_some() => tokens.push(Token::Sym("--")),
+            '"' => {
+                let mut id = String::new();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' => match chars.next() {
+                            // graphviz's centered, left and right justified line breaks
+                            Some('n' | 'l' | 'r') => id.push('\n'),
+                            Some(c) => id.push(c),
+                            None => break,
+                        },
+                        c => id.push(c),
+                    }
+                }
+                tokens.push(Token::Id(id));
+            }
+            c if c.is_alphanumeric() || matches!(c, '_' | '.' | '-') => {
+                let mut id = c.to_string();
+                while let Some(c) =
+                    chars.next_if(|&c| c.is_alphanumeric() || matches!(c, '_' | '.'))
+                {
+                    id.push(c);
+                }
+                tokens.push(Token::Id(id));
+            }
+            c => return Err(format!("unexpected {c:?}")),
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_dot(source: &str) -> Result<Graph, String> {
+    let mut tokens = tokenize(source)?.into_iter().peekable();
+    let next_id = |tokens: &mut std::iter::Peekable<std::vec::IntoIter<Token>>| match tokens.next()
+    {
+        Some(Token::Id(id)) => Ok(id),
+        token => Err(format!("expected an identifier, found {token:?}")),
+    };
+
+    let mut graph = Graph::default();
+    let mut kind = next_id(&mut tokens)?;
+    if kind == "strict" {
+        kind = next_id(&mut tokens)?;
+    }
+    graph.directed = match kind.as_str() {
+        "digraph" => true,
+        "graph" => false,
+        _ => return Err(format!("expected graph or digraph, found {kind:?}")),
+    };
+    if let Some(Token::Id(_)) = tokens.peek() {
+        tokens.next();
+    }
+    if tokens.next() != Some(Token::Sym("{")) {
+        return Err("expected { after the graph name".to_owned());
+    }
+
+    let mut node_defaults = HashMap::new();
+    let mut edge_defaults = HashMap::new();
+    let mut ids = HashMap::new();
+    let mut depth = 0;
+
+    loop {
+        let token = tokens.next().ok_or("unclosed graph")?;
+        let id = match token {
+            Token::Sym("}") if depth == 0 => break,
+            Token::Sym("}") => {
+                depth -= 1;
+                continue;
+            }
+            Token::Sym("{") => {
+                depth += 1;
+                continue;
+            }
+            Token::Sym(";" | ",") => continue,
+            Token::Sym(sym) => return Err(format!("unexpected {sym}")),
+            Token::Id(id) => id,
+        };
+
+        match id.as_str() {
+            "subgraph" => {
+                if let Some(Token::Id(_)) = tokens.peek() {
+                    tokens.next();
+                }
+                continue;
+            }
+            "graph" | "node" | "edge" if tokens.peek() == Some(&Token::Sym("[")) => {
+                let attrs = parse_attrs(&mut tokens)?;
+                match id.as_str() {
+                    "graph" => {
+                        graph.left_to_right |= attrs.get("rankdir").map_or(false, |dir| dir == "LR")
+                    }
+                    "node" => node_defaults.extend(attrs),
+                    _ => edge_defaults.extend(attrs),
+                }
+                continue;
+            }
+            _ => {}
+        }
+
+        if tokens.next_if_eq(&Token::Sym("=")).is_some() {
+            let value = next_id(&mut tokens)?;
+            if id == "rankdir" {
+                graph.left_to_right = value == "LR";
+            }
+            continue;
+        }
+
+        let mut chain = vec![id];
+        while tokens
+            .next_if(|t| matches!(t, Token::Sym("->" | "--")))
+            .is_some()
+        {
+            chain.push(next_id(&mut tokens)?);
+        }
+        let attrs = match tokens.peek() {
+            Some(Token::Sym("[")) => parse_attrs(&mut tokens)?,
+            _ => HashMap::new(),
+        };
+
+        let mut node = |graph: &mut Graph, id: &str| -> usize {
+            *ids.entry(id.to_owned()).or_insert_with(|| {
+                let label = node_defaults.get("label").cloned();
+                let shape = node_defaults
+                    .get("shape")
+                    .map_or(Shape::Ellipse, |s| Shape::parse(s));
+                graph.nodes.push(GraphNode {
+                    id: id.to_owned(),
+                    label: label.unwrap_or_else(|| id.to_owned()),
+                    shape,
+                });
+                graph.nodes.len() - 1
+            })
+        };
+
+        if let [id] = chain.as_slice() {
+            let index = node(&mut graph, id);
+            let node = &mut graph.nodes[index];
+            if let Some(label) = attrs.get("label") {
+                node.label = label.clone();
+            }
+            if let Some(shape) = attrs.get("shape") {
+                node.shape = Shape::parse(shape);
+            }
+            continue;
+        }
+
+        let attr = |key: &str| attrs.get(key).or_else(|| edge_defaults.get(key));
+        for pair in chain.windows(2) {
+            let from = node(&mut graph, &pair[0]);
+            let to = node(&mut graph, &pair[1]);
+            graph.edges.push(GraphEdge {
+                from,
+                to,
+                label: attr("label").cloned(),
+                dashed: attr("style").map_or(false, |s| s == "dashed" || s == "dotted"),
+            });
+        }
+    }
+
+    Ok(graph)
+}
+
+fn parse_attrs(
+    tokens: &mut std::iter::Peekable<std::vec::IntoIter<Token>>,
+) -> Result<HashMap<String, String>, String> {
+    let mut attrs = HashMap::new();
+    tokens.next(); // [
+    loop {
+        match tokens.next() {
+            Some(Token::Sym("]")) => return Ok(attrs),
+            Some(Token::Sym("," | ";")) => {}
+            Some(Token::Id(key)) => {
+                let value = match tokens.next_if_eq(&Token::Sym("=")) {
+                    Some(_) => match tokens.next() {
+                        Some(Token::Id(value)) => value,
+                        token => {
+                            return Err(format!("expected a value for {key}, found {token:?}"))
+                        }
+                    },
+                    None => "true".to_owned(),
+                };
+                attrs.insert(key, value);
+            }
+            token => return Err(format!("unexpected {token:?} in attributes")),
+        }
+    }
+}
+
+/// a node or a point that a long edge passes through, within a rank
+struct Vertex {
+    /// size across the ranks, and along them
+    cross: f32,
+    main: f32,
+    /// position across the ranks, and of the rank
+    cross_pos: f32,
+    main_pos: f32,
+}
+
+fn layout_graph(graph: &Graph, measure: &mut Measure) -> String {
+    let n = graph.nodes.len();
+    let sizes: Vec<(f32, f32)> = graph
+        .nodes
+        .iter()
+        .map(|node| node_size(node, measure))
+        .collect();
+
+    // reverse the edges that close a cycle, so that the ranks are well defined
+    let edges: Vec<(usize, usize)> = graph
+        .edges
+        .iter()
+        .map(|e| (e.from, e.to))
+        .filter(|(from, to)| from != to)
+        .collect();
+    let reversed = back_edges(n, &edges);
+    let oriented: Vec<(usize, usize)> = edges
+        .iter()
+        .zip(&reversed)
+        .map(|(&(from, to), &rev)| if rev { (to, from) } else { (from, to) })
+        .collect();
+
+    // rank each node by the longest path to it
+    let mut rank = vec![0; n];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &(from, to) in &oriented {
+            if rank[to] < rank[from] + 1 {
+                rank[to] = rank[from] + 1;
+                changed = true;
+            }
+        }
+    }
+
+    let mut vertices: Vec<Vertex> = sizes
+        .iter()
+        .map(|&(w, h)| {
+            let (cross, main) = if graph.left_to_right { (h, w) } else { (w, h) };
+            Vertex {
+                cross,
+                main,
+                cross_pos: 0.0,
+                main_pos: 0.0,
+            }
+        })
+        .collect();
+    let mut vertex_rank = rank.clone();
+
+    // long edges pass through a point on every rank they cross
+    let chains: Vec<Vec<usize>> = oriented
+        .iter()
+        .map(|&(from, to)| {
+            let mut chain = vec![from];
+            for r in rank[from] + 1..rank[to] {
+                vertices.push(Vertex {
+                    cross: 10.0,
+                    main: 0.0,
+                    cross_pos: 0.0,
+                    main_pos: 0.0,
+                });
+                vertex_rank.push(r);
+                chain.push(vertices.len() - 1);
+            }
+            chain.push(to);
+            chain
+        })
+        .collect();
+
+    let ranks = vertex_rank.iter().max().map_or(0, |r| r + 1);
+    let mut layers: Vec<Vec<usize>> = vec![vec![]; ranks];
+    for (v, &r) in vertex_rank.iter().enumerate() {
+        layers[r].push(v);
+    }
+
+    let mut preds = vec![vec![]; vertices.len()];
+    let mut succs = vec![vec![]; vertices.len()];
+    for chain in &chains {
+        for pair in chain.windows(2) {
+            succs[pair[0]].push(pair[1]);
+            preds[pair[1]].push(pair[0]);
+        }
+    }
+
+    // reduce crossings by sorting each rank by the mean position of its neighbours
+    let mut order = vec![0.0; vertices.len()];
+    let set_order = |layers: &Vec<Vec<usize>>, order: &mut Vec<f32>| {
+        for layer in layers {
+            for (i, &v) in layer.iter().enumerate() {
+                order[v] = i as f32;
+            }
+        }
+    };
+    set_order(&layers, &mut order);
+    for sweep in 0..8 {
+        let down = sweep % 2 == 0;
+        let range: Vec<usize> = if down {
+            (1..ranks).collect()
+        } else {
+            (0..ranks.saturating_sub(1)).rev().collect()
+        };
+        for r in range {
+            let neighbours = if down { &preds } else { &succs };
+            let key: HashMap<usize, f32> = layers[r]
+                .iter()
+                .map(|&v| {
+                    (
+                        v,
+                        mean(neighbours[v].iter().map(|&u| order[u])).unwrap_or(order[v]),
+                    )
+                })
+                .collect();
+            layers[r].sort_by(|a, b| key[a].total_cmp(&key[b]));
+            set_order(&layers, &mut order);
+        }
+    }
+
+    let has_labels = graph.edges.iter().any(|e| e.label.is_some());
+    let rank_sep = if has_labels { 60.0 } else { 40.0 };
+    let node_sep = 24.0;
+
+    // ranks are stacked along the main axis
+    let mut main = MARGIN;
+    for layer in &layers {
+        let size = layer.iter().map(|&v| vertices[v].main).fold(0.0, f32::max);
+        for &v in layer {
+            vertices[v].main_pos = main + size / 2.0;
+        }
+        main += size + rank_sep;
+    }
+
+    // then each node is pulled towards its neighbours, without overlapping the one before it
+    for layer in &layers {
+        let mut min = MARGIN;
+        for &v in layer {
+            vertices[v].cross_pos = min + vertices[v].cross / 2.0;
+            min += vertices[v].cross + node_sep;
+        }
+    }
+    for sweep in 0..4 {
+        let down = sweep % 2 == 0;
+        let range: Vec<usize> = if down {
+            (1..ranks).collect()
+        } else {
+            (0..ranks.saturating_sub(1)).rev().collect()
+        };
+        for r in range {
+            let neighbours = if down { &preds } else { &succs };
+            let mut min = f32::NEG_INFINITY;
+            for &v in &layers[r] {
+                let wanted = mean(neighbours[v].iter().map(|&u| vertices[u].cross_pos))
+                    .unwrap_or(vertices[v].cross_pos);
+                let half = vertices[v].cross / 2.0;
+                let pos = wanted.max(min + half);
+                vertices[v].cross_pos = pos;
+                min = pos + half + node_sep;
+            }
+        }
+    }
+    let shift = vertices
+        .iter()
+        .map(|v| v.cross_pos - v.cross / 2.0)
+        .fold(f32::INFINITY, f32::min)
+        - MARGIN;
+    for v in &mut vertices {
+        v.cross_pos -= shift;
+    }
+
+    let point = |v: &Vertex| {
+        if graph.left_to_right {
+            (v.main_pos, v.cross_pos)
+        } else {
+            (v.cross_pos, v.main_pos)
+        }
+    };
+    let centers: Vec<(f32, f32)> = vertices.iter().map(point).collect();
+
+    let mut width = vertices
+        .iter()
+        .map(|v| v.cross_pos + v.cross / 2.0)
+        .fold(0.0, f32::max)
+        + MARGIN;
+    let mut height = (main - rank_sep).max(MARGIN) + MARGIN;
+    if graph.left_to_right {
+        std::mem::swap(&mut width, &mut height);
+    }
+
+    let mut svg = String::new();
+    let mut extra_width: f32 = 0.0;
+    let mut edges_svg = String::new();
+    let mut chains = chains.into_iter();
+    for edge in &graph.edges {
+        let (from, to) = (edge.from, edge.to);
+        let mut points: Vec<(f32, f32)> = if from == to {
+            // loop out of the right hand side and back
+            let (x, y) = centers[from];
+            let (w, h) = sizes[from];
+            let right = x + w / 2.0;
+            extra_width = extra_width.max(right + 30.0 - width);
+            vec![
+                (right - 4.0, y - h / 4.0),
+                (right + 24.0, y - h / 2.0),
+                (right + 24.0, y + h / 2.0),
+                (right - 4.0, y + h / 4.0),
+            ]
+        } else {
+            let chain = chains.next().unwrap();
+            let mut points: Vec<(f32, f32)> = chain.iter().map(|&v| centers[v]).collect();
+            if chain[0] != from {
+                points.reverse();
+            }
+            let last = points.len() - 1;
+            points[0] = clip(points[0], sizes[from], graph.nodes[from].shape, points[1]);
+            points[last] = clip(
+                points[last],
+                sizes[to],
+                graph.nodes[to].shape,
+                points[last - 1],
+            );
+            points
+        };
+
+        let dash = if edge.dashed {
+            r#" stroke-dasharray="6 4""#
+        } else {
+            ""
+        };
+        if graph.directed {
+            let tip = points.pop().unwrap();
+            let base = arrow_base(*points.last().unwrap(), tip);
+            points.push(base);
+            edges_svg += &polyline(&points, dash);
+            edges_svg += &arrow_head(*points.last().unwrap(), tip);
+        } else {
+            edges_svg += &polyline(&points, dash);
+        }
+
+        if let Some(label) = &edge.label {
+            // beside the middle of the edge
+            let mid = points.len() / 2;
+            let (a, b) = (points[mid.saturating_sub(1)], points[mid]);
+            let (x, y) = ((a.0 + b.0) / 2.0 + 6.0, (a.1 + b.1) / 2.0);
+            let label_width = label.lines().map(&mut *measure).fold(0.0, f32::max);
+            extra_width = extra_width.max(x + label_width + MARGIN - width);
+            edges_svg += &text(label, x, y, "start");
+        }
+    }
+    width += extra_width;
+
+    writeln!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width:.1}" height="{height:.1}" viewBox="0 0 {width:.1} {height:.1}">"#
+    )
+    .unwrap();
+    svg += r#"<g fill="none" stroke="black" stroke-width="1.2">"#;
+    for (i, node) in graph.nodes.iter().enumerate() {
+        svg += &node_shape(node.shape, centers[i], sizes[i]);
+    }
+    svg += &edges_svg;
+    for (i, node) in graph.nodes.iter().enumerate() {
+        svg += &text(&node.label, centers[i].0, centers[i].1, "middle");
+    }
+    svg += "</g></svg>\n";
+    svg
+}
+
+/// marks the edges that close a cycle, found with a depth first search
+fn back_edges(n: usize, edges: &[(usize, usize)]) -> Vec<bool> {
+    #[derive(Clone, Copy, PartialEq)]
+    enum State {
+        New,
+        OnStack,
+        Done,
+    }
+
+    fn visit(v: usize, edges: &[(usize, usize)], state: &mut [State], back: &mut [bool]) {
+        state[v] = State::OnStack;
+        for (i, &(from, to)) in edges.iter().enumerate() {
+            if from != v {
+                continue;
+            }
+            match state[to] {
+                State::New => visit(to, edges, state, back),
+                State::OnStack => back[i] = true,
+                State::Done => {}
+            }
+        }
+        state[v] = State::Done;
+    }
+
+    let mut state = vec![State::New; n];
+    let mut back = vec![false; edges.len()];
+    for v in 0..n {
+        if state[v] == State::New {
+            visit(v, edges, &mut state, &mut back);
+        }
+    }
+    back
+}
+
+fn mean(values: impl Iterator<Item = f32>) -> Option<f32> {
+    let (sum, count) = values.fold((0.0, 0), |(sum, count), v| (sum + v, count + 1));
+    (count > 0).then(|| sum / count as f32)
+}
+
+fn node_size(node: &GraphNode, measure: &mut Measure) -> (f32, f32) {
+    let lines = node.label.lines().count().max(1) as f32;
+    let text_width = node.label.lines().map(measure).fold(0.0, f32::max);
+    let (w, h) = (text_width + 24.0, lines * LINE_HEIGHT + 12.0);
+    match node.shape {
+        Shape::Box | Shape::Plain => (w, h),
+        // an ellipse around the label's box
+        Shape::Ellipse => (w * 1.3, h * 1.4),
+        Shape::Circle => {
+            let d = w.max(h) * 1.1;
+            (d, d)
+        }
+        Shape::DoubleCircle => {
+            let d = w.max(h) * 1.1 + 8.0;
+            (d, d)
+        }
+        Shape::Diamond => (w * 1.6, h * 1.8),
+    }
+}
+
+/// the point where the line from the center of the node towards `toward` leaves the node
+fn clip(center: (f32, f32), (w, h): (f32, f32), shape: Shape, toward: (f32, f32)) -> (f32, f32) {
+    let (dx, dy) = (toward.0 - center.0, toward.1 - center.1);
+    if dx == 0.0 && dy == 0.0 {
+        return center;
+    }
+    let (rx, ry) = (w / 2.0, h / 2.0);
+    let t = match shape {
+        Shape::Ellipse | Shape::Circle | Shape::DoubleCircle => {
+            1.0 / ((dx / rx).powi(2) + (dy / ry).powi(2)).sqrt()
+        }
+        Shape::Diamond => 1.0 / (dx.abs() / rx + dy.abs() / ry),
+        Shape::Box | Shape::Plain => (rx / dx.abs()).min(ry / dy.abs()),
+    };
+    (center.0 + dx * t, center.1 + dy * t)
+}
+
+const ARROW_LENGTH: f32 = 9.0;
+
+/// where the line stops, short of the tip of the arrow head
+fn arrow_base(from: (f32, f32), tip: (f32, f32)) -> (f32, f32) {
+    let (dx, dy) = (tip.0 - from.0, tip.1 - from.1);
+    let len = (dx * dx + dy * dy).sqrt().max(f32::EPSILON);
+    (
+        tip.0 - dx / len * ARROW_LENGTH,
+        tip.1 - dy / len * ARROW_LENGTH,
+    )
+}
+
+fn arrow_head(base: (f32, f32), tip: (f32, f32)) -> String {
+    let (dx, dy) = (tip.0 - base.0, tip.1 - base.1);
+    let len = (dx * dx + dy * dy).sqrt().max(f32::EPSILON);
+    let (nx, ny) = (-dy / len * 4.0, dx / len * 4.0);
+    format!(
+        r#"<polygon points="{:.1},{:.1} {:.1},{:.1} {:.1},{:.1}" fill="black"/>"#,
+        tip.0,
+        tip.1,
+        base.0 + nx,
+        base.1 + ny,
+        base.0 - nx,
+        base.1 - ny
+    )
+}
+
+fn polyline(points: &[(f32, f32)], extra: &str) -> String {
+    let points: Vec<String> = points
+        .iter()
+        .map(|(x, y)| format!("{x:.1},{y:.1}"))
+        .collect();
+    format!(r#"<polyline points="{}"{extra}/>"#, points.join(" "))
+}
+
+fn node_shape(shape: Shape, (x, y): (f32, f32), (w, h): (f32, f32)) -> String {
+    let (rx, ry) = (w / 2.0, h / 2.0);
+    match shape {
+        Shape::Box => format!(
+            r#"<rect x="{:.1}" y="{:.1}" width="{w:.1}" height="{h:.1}"/>"#,
+            x - rx,
+            y - ry
+        ),
+        Shape::Ellipse | Shape::Circle => {
+            format!(r#"<ellipse cx="{x:.1}" cy="{y:.1}" rx="{rx:.1}" ry="{ry:.1}"/>"#)
+        }
+        Shape::DoubleCircle => format!(
+            r#"<ellipse cx="{x:.1}" cy="{y:.1}" rx="{rx:.1}" ry="{ry:.1}"/><ellipse cx="{x:.1}" cy="{y:.1}" rx="{:.1}" ry="{:.1}"/>"#,
+            rx - 4.0,
+            ry - 4.0
+        ),
+        Shape::Diamond => format!(
+            r#"<polygon points="{x:.1},{:.1} {:.1},{y:.1} {x:.1},{:.1} {:.1},{y:.1}"/>"#,
+            y - ry,
+            x + rx,
+            y + ry,
+            x - rx
+        ),
+        Shape::Plain => String::new(),
+    }
+}
+
+/// a label, with each line vertically centered around `y`
+fn text(label: &str, x: f32, y: f32, anchor: &str) -> String {
+    let lines: Vec<&str> = label.lines().collect();
+    let top = y - (lines.len() as f32 - 1.0) * LINE_HEIGHT / 2.0;
+    let mut svg = String::new();
+    for (i, line) in lines.iter().enumerate() {
+        // the baseline sits about a third of the font size below the middle of the line
+        let baseline = top + i as f32 * LINE_HEIGHT + FONT_SIZE * 0.35;
+        write!(
+            svg,
+            r#"<text x="{x:.1}" y="{baseline:.1}" font-family="sans-serif" font-size="{FONT_SIZE}" text-anchor="{anchor}" fill="black" stroke="none">{}</text>"#,
+            escape(line)
+        )
+        .unwrap();
+    }
+    svg
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Lays out a sequence diagram as an SVG. Each line is one of
+///
+/// - `participant Name`, to fix the order of the participants
+/// - `A->B: message`, with `-->` for a dashed line and `->>` for an open arrow head
+/// - `Note left of A: text`, `Note right of A: text` or `Note over A,B: text`
+///
+/// Participants are otherwise added in the order they are first mentioned, and the text
+/// after the colon may be left out. Fails on any other line, describing it
+pub fn sequence_to_svg(source: &str, measure: &mut Measure) -> Result<String, String> {
+    let diagram = parse_sequence(source)?;
+    Ok(layout_sequence(&diagram, measure))
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct Sequence {
+    participants: Vec<String>,
+    steps: Vec<Step>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Step {
+    Message {
+        from: usize,
+        to: usize,
+        text: String,
+        dashed: bool,
+        open: bool,
+    },
+    /// a note spanning the participants, or beside one if `first != last`
+    Note {
+        first: usize,
+        last: usize,
+        text: String,
+        side: NoteSide,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum NoteSide {
+    Left,
+    Right,
+    Over,
+}
+
+fn parse_sequence(source: &str) -> Result<Sequence, String> {
+    let mut diagram = Sequence::default();
+    let participant = |diagram: &mut Sequence, name: &str| -> usize {
+        let name = name.trim();
+        match diagram.participants.iter().position(|p| p == name) {
+            Some(i) => i,
+            None => {
+                diagram.participants.push(name.to_owned());
+                diagram.participants.len() - 1
+            }
+        }
+    };
+
+    for line in source.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(name) = line
+            .strip_prefix("participant ")
+            .or_else(|| line.strip_prefix("actor "))
+        {
+            participant(&mut diagram, name);
+            continue;
+        }
+
+        let (head, text) = line.split_once(':').unwrap_or((line, ""));
+        let text = text.trim().replace("\\n", "\n");
+
+        if let Some(rest) = head
+            .strip_prefix("Note ")
+            .or_else(|| head.strip_prefix("note "))
+        {
+            let (side, names) = if let Some(names) = rest.strip_prefix("left of ") {
+                (NoteSide::Left, names)
+            } else if let Some(names) = rest.strip_prefix("right of ") {
+                (NoteSide::Right, names)
+            } else if let Some(names) = rest.strip_prefix("over ") {
+                (NoteSide::Over, names)
+            } else {
+                return Err(format!("expected left of, right of or over in {line:?}"));
+            };
+            let mut names = names.split(',');
+            let first = participant(&mut diagram, names.next().unwrap());
+            let last = names
+                .next()
+                .map_or(first, |name| participant(&mut diagram, name));
+            diagram.steps.push(Step::Note {
+                first: first.min(last),
+                last: first.max(last),
+                text,
+                side,
+            });
+            continue;
+        }
+
+        let (from, arrow, to) = ["-->>", "->>", "-->", "->"]
+            .into_iter()
+            .find_map(|arrow| {
+                let (from, to) = head.split_once(arrow)?;
+                Some((from, arrow, to))
+            })
+            .ok_or_else(|| format!("expected an arrow in {line:?}"))?;
+        let from = participant(&mut diagram, from);
+        let to = participant(&mut diagram, to);
+        diagram.steps.push(Step::Message {
+            from,
+            to,
+            text,
+            dashed: arrow.starts_with("--"),
+            open: arrow.ends_with(">>"),
+        });
+    }
+    Ok(diagram)
+}
+
+fn layout_sequence(diagram: &Sequence, measure: &mut Measure) -> String {
+    let n = diagram.participants.len();
+    let mut text_width = |text: &str| text.lines().map(&mut *measure).fold(0.0, f32::max);
+
+    let boxes: Vec<f32> = diagram
+        .participants
+        .iter()
+        .map(|p| text_width(p) + 20.0)
+        .collect();
+    // distance between the lifelines of neighbouring participants
+    let mut gaps: Vec<f32> = boxes
+        .windows(2)
+        .map(|pair| (pair[0] / 2.0 + pair[1] / 2.0 + 20.0).max(100.0))
+        .collect();
+    let mut left = MARGIN + boxes.first().map_or(0.0, |b| b / 2.0);
+    let mut right = MARGIN + boxes.last().map_or(0.0, |b| b / 2.0);
+
+    // make room for every message and note
+    let widen = |gaps: &mut Vec<f32>, first: usize, last: usize, needed: f32| {
+        let have: f32 = gaps[first..last].iter().sum();
+        if have < needed {
+            gaps[last - 1] += needed - have;
+        }
+    };
+    for step in &diagram.steps {
+        match step {
+            Step::Message { from, to, text, .. } if from == to => {
+                let needed = text_width(text) + 50.0;
+                if *from + 1 < n {
+                    widen(&mut gaps, *from, *from + 1, needed);
+                } else {
+                    right = right.max(needed + MARGIN);
+                }
+            }
+            Step::Message { from, to, text, .. } => {
+                let needed = text_width(text) + 20.0;
+                widen(&mut gaps, *from.min(to), *from.max(to), needed);
+            }
+            Step::Note {
+                first, last, text, ..
+            } if first != last => {
+                widen(&mut gaps, *first, *last, text_width(text) + 20.0);
+            }
+            Step::Note {
+                first, text, side, ..
+            } => {
+                let needed = text_width(text) + 30.0;
+                match side {
+                    NoteSide::Left if *first == 0 => left = left.max(needed + MARGIN),
+                    NoteSide::Left => widen(&mut gaps, first - 1, *first, needed + 10.0),
+                    NoteSide::Right if first + 1 == n => right = right.max(needed + MARGIN),
+                    NoteSide::Right => widen(&mut gaps, *first, first + 1, needed + 10.0),
+                    NoteSide::Over => {
+                        let half = needed / 2.0;
+                        if *first == 0 {
+                            left = left.max(half + MARGIN);
+                        }
+                        if first + 1 == n {
+                            right = right.max(half + MARGIN);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    let mut xs = vec![left];
+    for gap in &gaps {
+        xs.push(xs.last().unwrap() + gap);
+    }
+    let width = xs.last().copied().unwrap_or(left) + right;
+
+    let box_height = LINE_HEIGHT + 12.0;
+    let mut body = String::new();
+    let mut y = MARGIN + box_height + 20.0;
+    for step in &diagram.steps {
+        match step {
+            Step::Message {
+                from,
+                to,
+                text: label,
+                dashed,
+                open,
+            } => {
+                let self_message = from == to;
+                let lines = label.lines().count().max(1) as f32;
+                y += lines * LINE_HEIGHT;
+                let dash = if *dashed {
+                    r#" stroke-dasharray="6 4""#
+                } else {
+                    ""
+                };
+                let (x1, x2) = (xs[*from], xs[*to]);
+                let (points, tip, label_x, anchor) = if self_message {
+                    let points = vec![(x1, y), (x1 + 30.0, y), (x1 + 30.0, y + 20.0)];
+                    (points, (x1, y + 20.0), x1 + 36.0, "start")
+                } else {
+                    (vec![(x1, y)], (x2, y), (x1 + x2) / 2.0, "middle")
+                };
+                let label_y = y - lines * LINE_HEIGHT / 2.0 - 2.0;
+                body += &text(label, label_x, label_y, anchor);
+
+                let base = arrow_base(*points.last().unwrap(), tip);
+                let mut line = points;
+                if *open {
+                    line.push(tip);
+                    body += &polyline(&line, dash);
+                    let (dx, dy) = (tip.0 - base.0, tip.1 - base.1);
+                    let (nx, ny) = (-dy / ARROW_LENGTH * 4.0, dx / ARROW_LENGTH * 4.0);
+                    body += &polyline(
+                        &[(base.0 + nx, base.1 + ny), tip, (base.0 - nx, base.1 - ny)],
+                        "",
+                    );
+                } else {
+                    line.push(base);
+                    body += &polyline(&line, dash);
+                    body += &arrow_head(base, tip);
+                }
+                y += if self_message { 36.0 } else { 16.0 };
+            }
+            Step::Note {
+                first,
+                last,
+                text: label,
+                side,
+            } => {
+                let lines = label.lines().count().max(1) as f32;
+                let note_width = text_width(label) + 20.0;
+                let note_height = lines * LINE_HEIGHT + 10.0;
+                let (x1, x2) = (xs[*first], xs[*last]);
+                let note_x = match side {
+                    NoteSide::Left => x1 - 10.0 - note_width,
+                    NoteSide::Right => x1 + 10.0,
+                    NoteSide::Over => {
+                        let span = (x2 - x1 + 30.0).max(note_width);
+                        (x1 + x2) / 2.0 - span / 2.0
+                    }
+                };
+                let note_width = match side {
+                    NoteSide::Over => (x2 - x1 + 30.0).max(note_width),
+                    _ => note_width,
+                };
+                write!(
+                    body,
+                    r##"<rect x="{note_x:.1}" y="{y:.1}" width="{note_width:.1}" height="{note_height:.1}" fill="#fff8c4"/>"##
+                )
+                .unwrap();
+                body += &text(
+                    label,
+                    note_x + note_width / 2.0,
+                    y + note_height / 2.0,
+                    "middle",
+                );
+                y += note_height + 16.0;
+            }
+        }
+    }
+    let bottom = y + 10.0;
+    let height = bottom + box_height + MARGIN;
+
+    let mut svg = String::new();
+    writeln!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width:.1}" height="{height:.1}" viewBox="0 0 {width:.1} {height:.1}">"#
+    )
+    .unwrap();
+    svg += r#"<g fill="none" stroke="black" stroke-width="1.2">"#;
+    for (i, name) in diagram.participants.iter().enumerate() {
+        let x = xs[i];
+        let top = MARGIN + box_height;
+        write!(
+            svg,
+            r#"<polyline points="{x:.1},{top:.1} {x:.1},{bottom:.1}" stroke-dasharray="4 4" stroke="gray"/>"#
+        )
+        .unwrap();
+        for box_y in [MARGIN, bottom] {
+            write!(
+                svg,
+                r#"<rect x="{:.1}" y="{box_y:.1}" width="{:.1}" height="{box_height:.1}" fill="white"/>"#,
+                x - boxes[i] / 2.0,
+                boxes[i]
+            )
+            .unwrap();
+            svg += &text(name, x, box_y + box_height / 2.0, "middle");
+        }
+    }
+    svg += &body;
+    svg += "</g></svg>\n";
+    svg
+}
+
+#[test]
+fn test_parse_dot() {
+    let graph = parse_dot(
+        r#"digraph tasks {
+            rankdir=LR
+            node [shape=box]
+            // states of a task
+            idle [label="Idle"]
+            idle -> running -> done [label="poll"]
+            running -> idle [style=dashed]
+        }"#,
+    )
+    .unwrap();
+    assert!(graph.directed && graph.left_to_right);
+    let labels: Vec<&str> = graph.nodes.iter().map(|n| n.label.as_str()).collect();
+    assert_eq!(labels, vec!["Idle", "running", "done"]);
+    assert!(graph.nodes.iter().all(|n| n.shape == Shape::Box));
+    assert_eq!(
+        graph.edges,
+        vec![
+            GraphEdge {
+                from: 0,
+                to: 1,
+                label: Some("poll".to_owned()),
+                dashed: false
+            },
+            GraphEdge {
+                from: 1,
+                to: 2,
+                label: Some("poll".to_owned()),
+                dashed: false
+            },
+            GraphEdge {
+                from: 1,
+                to: 0,
+                label: None,
+                dashed: true
+            },
+        ]
+    );
+    assert!(parse_dot("digraph { a:n -> b }").is_err());
+    assert!(parse_dot("digraph { a [label=<<b>A</b>>] }").is_err());
+    assert_eq!(
+        back_edges(3, &[(0, 1), (1, 2), (1, 0)]),
+        vec![false, false, true]
+    );
+}
+
+#[test]
+fn test_parse_sequence() {
+    let diagram = parse_sequence(
+        "participant Executor\n\
+         Executor->Future: poll\n\
+         Future-->Executor: Pending\n\
+         Note over Future: registers the waker\n\
+         Future->Executor",
+    )
+    .unwrap();
+    assert_eq!(diagram.participants, vec!["Executor", "Future"]);
+    assert_eq!(
+        diagram.steps[1],
+        Step::Message {
+            from: 1,
+            to: 0,
+            text: "Pending".to_owned(),
+            dashed: true,
+            open: false,
+        }
+    );
+    assert_eq!(
+        diagram.steps[2],
+        Step::Note {
+            first: 1,
+            last: 1,
+            text: "registers the waker".to_owned(),
+            side: NoteSide::Over,
+        }
+    );
+    assert_eq!(
+        diagram.steps[3],
+        Step::Message {
+            from: 1,
+            to: 0,
+            text: String::new(),
+            dashed: false,
+            open: false,
+        }
+    );
+    assert!(parse_sequence("Executor polls Future").is_err());
+}
@@ -0,0 +1,151 @@
+use crate::printpdf::{Mm, Pt};
+
+/// Per-image sizing and placement, set either through the image title
+/// (`![cap](img.png "width=50%")`) or an attribute block directly after
+/// the image (`![cap](img.png){width=40mm align=right}`)
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImageOptions {
+    pub width: Option<ImageWidth>,
+    /// resolution of the image, which sets its natural size. Defaults to 96dpi
+    pub dpi: Option<f32>,
+    pub align: ImageAlign,
+    /// set the alt text as a caption below the image
+    pub caption: bool,
+    /// label for cross-references, eg `fig:event-loop` from `#fig:event-loop`
+    pub label: Option<String>,
+    pub placement: Placement,
+}
+
+impl Default for ImageOptions {
+    fn default() -> Self {
+        Self {
+            width: None,
+            dpi: None,
+            align: ImageAlign::Center,
+            caption: true,
+            label: None,
+            placement: Placement::Float,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ImageWidth {
+    /// fraction of the text width
+    Relative(f32),
+    Absolute(Mm),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageAlign {
+    Left,
+    Center,
+    Right,
+}
+
+/// Where a figure goes relative to the text around it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Placement {
+    /// where it appears in the text if it fits, otherwise at the top of the next page
+    Float,
+    /// exactly where it appears in the text, starting a new page if it doesn't fit
+    Here,
+    /// at the top of a page
+    Top,
+    /// on a page of its own
+    Page,
+}
+
+impl ImageOptions {
+    /// Parses space separated `key=value` options and a `#label`, eg `#fig:loop width=50% nocaption`.
+    ///
+    /// Returns `None` if anything isn't a known option, so that a regular
+    /// image title isn't mistaken for options.
+    pub fn parse(options: &str) -> Option<Self> {
+        let mut parsed = Self::default();
+        for option in options.split_whitespace() {
+            if let Some(label) = option.strip_prefix('#') {
+                parsed.label = Some(label.to_owned());
+                continue;
+            }
+            match option.split_once('=') {
+                Some(("width", width)) => parsed.width = Some(parse_width(width)?),
+                Some(("dpi", dpi)) => parsed.dpi = Some(dpi.parse().ok()?),
+                Some(("align", "left")) => parsed.align = ImageAlign::Left,
+                Some(("align", "center")) => parsed.align = ImageAlign::Center,
+                Some(("align", "right")) => parsed.align = ImageAlign::Right,
+                Some(("placement", "float")) => parsed.placement = Placement::Float,
+                Some(("placement", "here")) => parsed.placement = Placement::Here,
+                Some(("placement", "top")) => parsed.placement = Placement::Top,
+                Some(("placement", "page")) => parsed.placement = Placement::Page,
+                None if option == "nocaption" => parsed.caption = false,
+                _ => return None,
+            }
+        }
+        Some(parsed)
+    }
+}
+
+/// `50%`, or an absolute length in `mm`, `cm`, `in`, `pt` or `px` (at 96 per inch)
+fn parse_width(width: &str) -> Option<ImageWidth> {
+    if let Some(percent) = width.strip_suffix('%') {
+        return Some(ImageWidth::Relative(percent.parse::<f32>().ok()? / 100.0));
+    }
+
+    let split = width.find(|c: char| c.is_ascii_alphabetic())?;
+    let (value, unit) = width.split_at(split);
+    let value: f32 = value.parse().ok()?;
+    let length = match unit {
+        "mm" => Mm(value),
+        "cm" => Mm(value * 10.0),
+        "in" => Mm(value * 25.4),
+        "pt" => Pt(value).into(),
+        "px" => Mm(value * 25.4 / 96.0),
+        _ => return None,
+    };
+    Some(ImageWidth::Absolute(length))
+}
+
+#[test]
+fn test_parse_image_options() {
+    assert_eq!(
+        ImageOptions::parse("#fig:loop width=50% align=right placement=top nocaption"),
+        Some(ImageOptions {
+            width: Some(ImageWidth::Relative(0.5)),
+            dpi: None,
+            align: ImageAlign::Right,
+            caption: false,
+            label: Some("fig:loop".to_owned()),
+            placement: Placement::Top,
+        })
+    );
+    assert_eq!(
+        ImageOptions::parse("width=4cm dpi=300").unwrap().width,
+        Some(ImageWidth::Absolute(Mm(40.0)))
+    );
+    assert_eq!(ImageOptions::parse("The event loop"), None);
+}
+
+/// Finds the figure references in the text, eg `[@fig:event-loop]`,
+/// returning the byte range of each reference and its label
+pub fn find_references(text: &str) -> Vec<(std::ops::Range<usize>, &str)> {
+    let mut references = vec![];
+    let mut offset = 0;
+    while let Some(start) = text[offset..].find("[@fig:") {
+        let start = offset + start;
+        let Some(len) = text[start..].find(']') else {
+            break;
+        };
+        references.push((start..start + len + 1, &text[start + 2..start + len]));
+        offset = start + len + 1;
+    }
+    references
+}
+
+#[test]
+fn test_find_references() {
+    assert_eq!(
+        find_references("as seen in [@fig:a] and [@fig:b-c]."),
+        vec![(11..19, "fig:a"), (24..34, "fig:b-c")]
+    );
+}
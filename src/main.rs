@@ -1,47 +1,116 @@
-use std::{collections::HashSet, ffi::OsStr, io::Read, path::Path};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    ffi::OsStr,
+    io::Read,
+    path::Path,
+};
 
+mod color_glyph;
+mod config;
+mod diagram;
+mod figure;
+mod math;
 mod pdf;
 #[allow(dead_code)]
 mod printpdf;
+mod typography;
 // mod toc;
 
 use comrak::{
-    nodes::{AstNode, NodeValue},
+    nodes::{AstNode, ListDelimType, ListType, NodeLink, NodeValue},
     parse_document, Arena, ComrakOptions,
 };
+use config::{
+    BookConfig, CopyrightConfig, FooterLayout, TextDirection, TypographyConfig, WritingMode,
+};
 use cosmic_text::{fontdb::Database, Attrs, Family, FontSystem, Style, Weight};
+use figure::{find_references, ImageOptions};
 use indexmap::IndexMap;
-use pdf::{Document, Fonts, Paragraph};
-use printpdf::{PdfDocument, Pt};
+use pdf::{Art, ChapterOpener, Decoration, Document, Figure, Fonts, Paragraph};
+use printpdf::{Image, Mm, PageLabel, PdfDocument, Pt, Svg};
 use syntect::{highlighting::ThemeSet, parsing::SyntaxSet};
 // use toc::TocNode;
 
 fn main() {
-    pretty_env_logger::init();
+    // warnings about the book are shown unless `RUST_LOG` asks otherwise
+    let mut logger = pretty_env_logger::formatted_builder();
+    logger.filter_level(log::LevelFilter::Warn);
+    if let Ok(filters) = std::env::var("RUST_LOG") {
+        logger.parse_filters(&filters);
+    }
+    logger.init();
+
+    let config = BookConfig {
+        title: "Async Rust: Deep Dive".to_owned(),
+        authors: vec!["Conrad Ludgate".to_owned()],
+        copyright: Some(CopyrightConfig {
+            year: Some(2023),
+            ..Default::default()
+        }),
+        footer: FooterLayout::Outer,
+        ..Default::default()
+    };
 
-    let doc = PdfDocument::empty("Async Rust: Deep Dive");
+    let doc = PdfDocument::empty(config.title.clone())
+        .with_author(config.authors.join(", "))
+        .with_right_to_left(config.writing_mode == WritingMode::VerticalRightToLeft);
 
+    let fallback = config.fonts.clone();
     let mut font_db = Database::new();
     font_db.load_fonts_dir("assets/fonts");
 
-    font_db.set_monospace_family("Fira Code");
-    font_db.set_sans_serif_family("PT Sans");
-    font_db.set_serif_family("PT Serif");
-    font_db.load_system_fonts();
+    font_db.set_monospace_family(&fallback.monospace[0]);
+    font_db.set_sans_serif_family(&fallback.sans_serif[0]);
+    font_db.set_serif_family(&fallback.serif[0]);
+    if fallback.system_fonts {
+        font_db.load_system_fonts();
+    }
+
+    let font_system = FontSystem::new_with_locale_and_db(config.language.clone(), font_db);
 
-    let font_system = FontSystem::new_with_locale_and_db("en-US".to_owned(), font_db);
+    // usvg has its own font database, for text within SVG figures
+    let mut svg_options = usvg::Options::default();
+    let svg_fonts = &mut svg_options.fontdb;
+    svg_fonts.load_fonts_dir("assets/fonts");
+    svg_fonts.set_monospace_family(&fallback.monospace[0]);
+    svg_fonts.set_sans_serif_family(&fallback.sans_serif[0]);
+    svg_fonts.set_serif_family(&fallback.serif[0]);
+    svg_options.font_family = fallback.sans_serif[0].clone();
 
+    let direction = config.direction;
     let mut doc = Document {
+        config,
         fonts: Fonts {
             font_system,
             fonts: HashSet::new(),
+            glyph_text: HashMap::new(),
+            fallback,
+            source: None,
+            line_starts: vec![],
+            warned: HashSet::new(),
         },
         pdf: doc,
         pages: vec![],
         paragraph: Default::default(),
         syntax: SyntaxSet::load_defaults_nonewlines(),
         theme: ThemeSet::load_defaults(),
+        svg_options,
         images: 0,
+        chapter: 0,
+        numbering: vec![],
+        numbered_figures: false,
+        figure_numbers: HashMap::new(),
+        figures: vec![],
+        span_data: vec![],
+        links: vec![],
+        list_of_figures: None,
+        floats: VecDeque::new(),
+        direction,
+        // the title pages are always horizontal
+        writing_mode: WritingMode::Horizontal,
+        chapter_opening: false,
+        list_indent: Mm(0.0),
+        list_marker: None,
     };
 
     let mut custom = ThemeSet::load_from_folder("assets/themes").unwrap();
@@ -50,42 +119,127 @@ fn main() {
     // let toc_arena = Arena::new();
     let ast_arena = Arena::new();
 
-    let chapters = parse_documents(&ast_arena);
+    let front_matter = parse_sections(&ast_arena, "front", &doc.config);
+    let chapters = parse_documents(&ast_arena, &doc.config);
+    let back_matter = parse_sections(&ast_arena, "back", &doc.config);
     // let _toc = dbg!(TocNode::build(&toc_arena, &chapters));
 
-    for (_, &node) in chapters.iter() {
-        doc.end_last_paragraph();
-        doc.new_page();
-        let mut node = node;
-        loop {
+    // openers are taken out first, so that their images aren't numbered as figures
+    let openers: Vec<ChapterOpener> = chapters
+        .values()
+        .map(|documents| doc.take_chapter_opener(documents[0].1))
+        .collect();
+    let (figures, figure_numbers) = number_figures(&chapters);
+    let cover = load_cover("cover", &doc.svg_options);
+    doc.figure_numbers = figure_numbers;
+
+    if let Some(cover) = cover.clone() {
+        doc.start_numbering(PageLabel {
+            style: None,
+            prefix: Some("Cover".to_owned()),
+            start: None,
+        });
+        doc.write_cover(cover);
+    }
+
+    doc.start_numbering(PageLabel::new(doc.config.front_matter_numbering));
+    doc.write_title_page();
+    doc.write_copyright_page();
+    doc.write_dedication();
+    if doc.config.list_of_figures && figures > 0 {
+        doc.reserve_list_of_figures(figures);
+    }
+    for (path, node) in front_matter {
+        doc.new_section();
+        doc.fonts.source = Some((path, 1));
+        doc.render_ast_node(
+            node,
+            State {
+                weight: Weight::NORMAL,
+                style: Style::Normal,
+                heading: 0,
+                decoration: Decoration::default(),
+            },
+        );
+    }
+
+    // chapter one restarts the page count at 1
+    doc.start_numbering(PageLabel::new(doc.config.body_numbering));
+    for (opener, documents) in openers.into_iter().zip(chapters.into_values()) {
+        doc.fonts.source = Some((documents[0].0.clone(), 1));
+        doc.write_chapter_opener(opener);
+        for (path, node) in documents {
+            doc.fonts.source = Some((path, 1));
             doc.render_ast_node(
                 node,
                 State {
                     weight: Weight::NORMAL,
                     style: Style::Normal,
                     heading: 0,
+                    decoration: Decoration::default(),
                 },
             );
-            let Some(n) = node.next_sibling() else { break };
-            node = n;
         }
     }
+    for (path, node) in back_matter {
+        doc.new_section();
+        doc.fonts.source = Some((path, 1));
+        doc.render_ast_node(
+            node,
+            State {
+                weight: Weight::NORMAL,
+                style: Style::Normal,
+                heading: 0,
+                decoration: Decoration::default(),
+            },
+        );
+    }
     doc.end_last_paragraph();
+    doc.flush_floats();
+    doc.fonts.source = None;
 
+    doc.write_list_of_figures();
+    doc.write_links();
     doc.write_extras();
 
+    // print-on-demand cover, with the back cover, spine and front cover on one sheet
+    let print_cover = cover.map(|front| {
+        let back = load_cover("back-cover", &doc.svg_options);
+        doc.print_cover(front, back)
+    });
+
     let Document { mut fonts, pdf, .. } = doc;
 
     let data = pdf
-        .save_to_bytes(fonts.fonts, &mut fonts.font_system)
+        .save_to_bytes(fonts.fonts, &fonts.glyph_text, &mut fonts.font_system)
         .unwrap();
     std::fs::write("test_pages.pdf", data).unwrap();
+
+    if let Some((cover, cover_fonts)) = print_cover {
+        let data = cover
+            .save_to_bytes(cover_fonts, &fonts.glyph_text, &mut fonts.font_system)
+            .unwrap();
+        std::fs::write("test_cover.pdf", data).unwrap();
+    }
     // dbg!(chapters);
 }
 
-fn parse_documents<'a>(arena: &'a Arena<AstNode<'a>>) -> IndexMap<String, &'a AstNode<'a>> {
-    let mut chapters = IndexMap::<String, &AstNode>::new();
-    let options = ComrakOptions::default();
+fn markdown_options(typography: &TypographyConfig) -> ComrakOptions {
+    let mut options = ComrakOptions::default();
+    options.extension.strikethrough = true;
+    options.extension.superscript = true;
+    options.parse.smart = typography.smart_punctuation;
+    options
+}
+
+/// parses the markdown files in each directory of `chapters/` as one chapter,
+/// returning each file's path and document by chapter
+fn parse_documents<'a>(
+    arena: &'a Arena<AstNode<'a>>,
+    config: &BookConfig,
+) -> IndexMap<String, Vec<(String, &'a AstNode<'a>)>> {
+    let mut chapters = IndexMap::<String, Vec<(String, &AstNode)>>::new();
+    let options = markdown_options(&config.typography);
 
     let mut buffer = String::new();
     let mut chapter = String::new();
@@ -98,29 +252,336 @@ fn parse_documents<'a>(arena: &'a Arena<AstNode<'a>>) -> IndexMap<String, &'a As
             let mut file = std::fs::File::open(entry.path()).unwrap();
             buffer.clear();
             file.read_to_string(&mut buffer).unwrap();
-            let node = parse_document(arena, &buffer, &options);
+            let node = parse_document(arena, &math::protect(&buffer), &options);
+            typography::apply(node, &config.typography, &config.language);
 
+            let path = entry.path().display().to_string();
             chapters
                 .entry(chapter.clone())
-                .and_modify(|n| n.insert_after(node))
-                .or_insert(node);
+                .or_default()
+                .push((path, node));
         }
     }
 
     chapters
 }
 
+/// parses each markdown file in `dir` as its own section, such as the
+/// preface in `front/` or the acknowledgements in `back/`, along with its path
+fn parse_sections<'a>(
+    arena: &'a Arena<AstNode<'a>>,
+    dir: &str,
+    config: &BookConfig,
+) -> Vec<(String, &'a AstNode<'a>)> {
+    let options = markdown_options(&config.typography);
+
+    let mut sections = vec![];
+    if !Path::new(dir).is_dir() {
+        return sections;
+    }
+
+    for entry in walkdir::WalkDir::new(dir).sort_by_file_name() {
+        let entry = entry.unwrap();
+        if entry.file_type().is_file() && entry.path().extension() == Some(OsStr::new("md")) {
+            let buffer = std::fs::read_to_string(entry.path()).unwrap();
+            let node = parse_document(arena, &math::protect(&buffer), &options);
+            typography::apply(node, &config.typography, &config.language);
+            sections.push((entry.path().display().to_string(), node));
+        }
+    }
+
+    sections
+}
+
 #[derive(Clone, Copy)]
 struct State {
     weight: Weight,
     style: Style,
     heading: u8,
+    decoration: Decoration,
 }
 
 // const NBSP: char = '\u{A0}';
 static NBSP_STR: &str = "\u{A0}";
 
+/// how much further in from both margins each level of a list is
+const LIST_INDENT: Mm = Mm(8.0);
+
+/// loads an image from assets/images. SVGs are kept as vectors
+fn load_image(url: &str, svg_options: &usvg::Options) -> Art {
+    let path = Path::new("assets/images").join(url);
+    if path.extension() == Some(OsStr::new("svg")) {
+        let svg = std::fs::read_to_string(path).unwrap();
+        return Art::Svg(Svg::parse_with_options(&svg, svg_options).unwrap());
+    }
+
+    Art::Image(Image::from_bytes(std::fs::read(path).unwrap()).unwrap())
+}
+
+/// loads `{name}.svg`, `{name}.png` or `{name}.jpg` from the book root, if present
+fn load_cover(name: &str, svg_options: &usvg::Options) -> Option<Art> {
+    let svg = Path::new(name).with_extension("svg");
+    if svg.is_file() {
+        let svg = std::fs::read_to_string(svg).unwrap();
+        return Some(Art::Svg(
+            Svg::parse_with_options(&svg, svg_options).unwrap(),
+        ));
+    }
+
+    ["png", "jpg", "jpeg"]
+        .into_iter()
+        .map(|ext| Path::new(name).with_extension(ext))
+        .find(|path| path.is_file())
+        .map(|path| Art::Image(Image::from_bytes(std::fs::read(path).unwrap()).unwrap()))
+}
+
+/// image options from an attribute block directly after the image, eg `![](a.png){width=50%}`,
+/// or otherwise from the image title. The attribute block is removed from the text
+fn image_options<'a>(node: &'a AstNode<'a>, link: &NodeLink) -> ImageOptions {
+    let options = peek_image_options(node, link);
+    if let Some(next) = node.next_sibling() {
+        if let NodeValue::Text(text) = &mut next.data.borrow_mut().value {
+            if let Some((_, rest)) = split_attrs(text) {
+                *text = rest.to_owned();
+            }
+        }
+    }
+    options
+}
+
+/// like `image_options`, but leaves the attribute block in place
+fn peek_image_options<'a>(node: &'a AstNode<'a>, link: &NodeLink) -> ImageOptions {
+    if let Some(next) = node.next_sibling() {
+        if let NodeValue::Text(text) = &next.data.borrow().value {
+            if let Some((options, _)) = split_attrs(text) {
+                return options;
+            }
+        }
+    }
+
+    ImageOptions::parse(&link.title).unwrap_or_default()
+}
+
+/// splits a leading attribute block off the text, returning the options and the remaining text.
+/// Text that only looks like one, eg `{see below}`, is left alone
+fn split_attrs(text: &str) -> Option<(ImageOptions, &str)> {
+    let attrs = text.strip_prefix('{')?;
+    let (attrs, rest) = attrs.split_once('}')?;
+    let options = ImageOptions::parse(attrs)?;
+    Some((options, rest))
+}
+
+/// numbers the captioned figures in each chapter before anything is rendered, so that
+/// references can point forwards. Returns the number of figures and the numbers by label
+fn number_figures<'a>(
+    chapters: &IndexMap<String, Vec<(String, &'a AstNode<'a>)>>,
+) -> (usize, HashMap<String, String>) {
+    let mut count = 0;
+    let mut numbers = HashMap::new();
+    for (chapter, documents) in chapters.values().enumerate() {
+        let mut images = 0;
+        let descendants = documents
+            .iter()
+            .flat_map(|(path, d)| d.descendants().map(move |node| (path, node)));
+        for (path, descendant) in descendants {
+            let options = match &descendant.data.borrow().value {
+                NodeValue::Image(link) => peek_image_options(descendant, link),
+                _ => continue,
+            };
+            if !options.caption {
+                continue;
+            }
+            images += 1;
+            count += 1;
+            if let Some(label) = options.label {
+                // references go to the first figure with the label
+                if numbers.contains_key(&label) {
+                    let line = descendant.data.borrow().start_line;
+                    log::warn!("{path}:{line}: duplicate figure label #{label}");
+                    continue;
+                }
+                numbers.insert(label, format!("{}.{images}", chapter + 1));
+            }
+        }
+    }
+    (count, numbers)
+}
+
+/// parses the attribute block at the end of a paragraph or heading, eg `{dir=rtl}`.
+/// The attribute block is removed from the text if `parse` knows it
+fn take_block_attrs<'a, T>(
+    node: &'a AstNode<'a>,
+    parse: impl FnOnce(&str) -> Option<T>,
+) -> Option<T> {
+    let last = node.last_child()?;
+    let mut data = last.data.borrow_mut();
+    let NodeValue::Text(text) = &mut data.value else {
+        return None;
+    };
+    let (rest, attrs) = text.trim_end().rsplit_once('{')?;
+    let value = parse(attrs.strip_suffix('}')?)?;
+    *text = rest.trim_end().to_owned();
+    Some(value)
+}
+
+/// the direction set by a `{dir=rtl}`, `{dir=ltr}` or `{dir=auto}` attribute block at the end
+/// of a paragraph or heading
+fn block_direction<'a>(node: &'a AstNode<'a>) -> Option<TextDirection> {
+    take_block_attrs(node, |attrs| match attrs {
+        "dir=rtl" => Some(TextDirection::RightToLeft),
+        "dir=ltr" => Some(TextDirection::LeftToRight),
+        "dir=auto" => Some(TextDirection::Auto),
+        _ => None,
+    })
+}
+
+/// the writing mode set by a `{writing-mode=vertical-rl}` or `{writing-mode=horizontal-tb}`
+/// attribute block after a chapter title
+fn chapter_writing_mode<'a>(heading: &'a AstNode<'a>) -> Option<WritingMode> {
+    take_block_attrs(heading, |attrs| match attrs {
+        "writing-mode=vertical-rl" => Some(WritingMode::VerticalRightToLeft),
+        "writing-mode=horizontal-tb" => Some(WritingMode::Horizontal),
+        _ => None,
+    })
+}
+
+/// whether the text is only an attribute block, eg `{width=50%}`
+fn is_attrs(text: &str) -> bool {
+    text.starts_with('{') && text.trim_end().ends_with('}')
+}
+
+/// concatenates all the text within a node, ignoring any styling
+fn collect_text<'a>(node: &'a AstNode<'a>, text: &mut String) {
+    match &node.data.borrow().value {
+        NodeValue::Text(t) => text.push_str(t),
+        NodeValue::Code(code) => text.push_str(&code.literal),
+        NodeValue::SoftBreak | NodeValue::LineBreak => text.push(' '),
+        _ => {}
+    }
+    for child in node.children() {
+        collect_text(child, text)
+    }
+}
+
 impl Document {
+    /// the line the block's text starts on, for warnings about it
+    fn set_source_line<'a>(&mut self, node: &'a AstNode<'a>) {
+        if let Some((_, line)) = &mut self.fonts.source {
+            let data = node.data.borrow();
+            *line = data.start_line as usize;
+            // the code in a fenced block starts on the line after the fence
+            if matches!(&data.value, NodeValue::CodeBlock(code) if code.fenced) {
+                *line += 1;
+            }
+        }
+    }
+
+    /// Takes the chapter's first heading, along with an image paragraph and a blockquote
+    /// epigraph directly following it, out of the AST for the chapter opener
+    fn take_chapter_opener<'a>(&self, node: &'a AstNode<'a>) -> ChapterOpener {
+        // only a heading that leads the chapter, ignoring any front matter, is its title
+        let heading = node
+            .children()
+            .find(|child| !matches!(child.data.borrow().value, NodeValue::FrontMatter(_)))
+            .filter(
+                |child| matches!(&child.data.borrow().value, NodeValue::Heading(h) if h.level == 1),
+            );
+
+        let mut title = None;
+        let mut writing_mode = None;
+        let mut image = None;
+        let mut epigraph = None;
+
+        if let Some(heading) = heading {
+            writing_mode = chapter_writing_mode(heading);
+            let mut text = String::new();
+            collect_text(heading, &mut text);
+            title = Some(math::unprotect(&text));
+
+            let mut next = heading.next_sibling();
+            heading.detach();
+
+            if let Some(paragraph) = next {
+                let mut children = paragraph.children();
+                let (child, rest) = (children.next(), children.next());
+                // the image may only be followed by its attribute block
+                let only_attrs = rest.map_or(true, |rest| {
+                    rest.next_sibling().is_none()
+                        && matches!(&rest.data.borrow().value, NodeValue::Text(t) if is_attrs(t))
+                });
+                if let (Some(child), true) = (child, only_attrs) {
+                    if let NodeValue::Image(link) = &child.data.borrow().value {
+                        let options = image_options(child, link);
+                        image = Some((load_image(&link.url, &self.svg_options), options));
+                    }
+                }
+                if image.is_some() {
+                    next = paragraph.next_sibling();
+                    paragraph.detach();
+                }
+            }
+
+            if let Some(quote) = next {
+                if let NodeValue::BlockQuote = quote.data.borrow().value {
+                    let mut p = Paragraph::default();
+                    // lists and quotes within the epigraph are set as lines of text
+                    let paragraphs = quote
+                        .descendants()
+                        .filter(|child| matches!(child.data.borrow().value, NodeValue::Paragraph));
+                    for (i, child) in paragraphs.enumerate() {
+                        if i > 0 {
+                            p.write_line_break();
+                        }
+                        let state = State {
+                            weight: Weight::NORMAL,
+                            style: Style::Italic,
+                            heading: 0,
+                            decoration: Decoration::default(),
+                        };
+                        for_each_inline(child, state, |inline, state| {
+                            p.render_ast_text(inline, state)
+                        });
+                    }
+                    epigraph = Some(p);
+                }
+                if epigraph.is_some() {
+                    quote.detach();
+                }
+            }
+        }
+
+        ChapterOpener {
+            title,
+            writing_mode,
+            epigraph,
+            image,
+        }
+    }
+
+    /// body text, with figure references such as `[@fig:loop]` turned into
+    /// links reading "Figure 3.2"
+    fn write_text(&mut self, text: &str, attrs: Attrs) {
+        let mut last = 0;
+        for (range, label) in find_references(text) {
+            // unknown references are left as they are written
+            let Some(number) = self.figure_numbers.get(label).cloned() else {
+                log::warn!(
+                    "{}unknown figure reference [@{label}]",
+                    self.fonts.location(self.paragraph.line_starts.len())
+                );
+                continue;
+            };
+            self.write_body(&text[last..range.start], attrs);
+            let link = self.figure_link(number.clone());
+            self.write_body(
+                &format!("Figure {number}"),
+                attrs.metadata(attrs.metadata | link),
+            );
+            last = range.end;
+        }
+        self.write_body(&text[last..], attrs);
+    }
+
     fn render_ast_node<'a>(&mut self, node: &'a AstNode<'a>, mut state: State) {
         match &node.data.borrow().value {
             NodeValue::Document => {
@@ -130,34 +591,91 @@ impl Document {
             }
             NodeValue::FrontMatter(_) => todo!("FrontMatter(_)"),
             NodeValue::BlockQuote => todo!("BlockQuote"),
-            NodeValue::List(_) => todo!("List(_)"),
-            NodeValue::Item(_) => todo!("Item(_)"),
+            NodeValue::List(_) => {
+                self.end_last_paragraph();
+                self.chapter_opening = false;
+                let indent = self.list_indent;
+                self.list_indent = indent + LIST_INDENT;
+                for child in node.children() {
+                    self.render_ast_node(child, state)
+                }
+                self.end_last_paragraph();
+                self.list_indent = indent;
+            }
+            NodeValue::Item(item) => {
+                self.end_last_paragraph();
+                self.list_marker = Some(match item.list_type {
+                    ListType::Bullet => "•".to_owned(),
+                    ListType::Ordered => {
+                        // items are numbered on from the first, whatever they're written as
+                        let start = match &node.parent().unwrap().data.borrow().value {
+                            NodeValue::List(list) => list.start,
+                            _ => item.start,
+                        };
+                        let number = start + node.preceding_siblings().count() - 1;
+                        match item.delimiter {
+                            ListDelimType::Period => format!("{number}."),
+                            ListDelimType::Paren => format!("{number})"),
+                        }
+                    }
+                });
+                for child in node.children() {
+                    self.render_ast_node(child, state)
+                }
+                self.list_marker = None;
+            }
             NodeValue::DescriptionList => todo!("DescriptionList"),
             NodeValue::DescriptionItem(_) => todo!("DescriptionItem(_)"),
             NodeValue::DescriptionTerm => todo!("DescriptionTerm"),
             NodeValue::DescriptionDetails => todo!("DescriptionDetails"),
+            NodeValue::CodeBlock(code) if matches!(code.info.as_str(), "dot" | "sequence") => {
+                self.end_last_paragraph();
+                self.set_source_line(node);
+                self.write_diagram(&code.info, &code.literal);
+            }
             NodeValue::CodeBlock(code) => {
                 self.end_last_paragraph();
+                self.set_source_line(node);
                 self.write_code(&code.info, &code.literal, Pt(10.0), Pt(12.0));
             }
             NodeValue::HtmlBlock(_) => todo!("HtmlBlock(_)"),
             NodeValue::Paragraph => {
                 self.end_last_paragraph();
+                self.set_source_line(node);
+                let direction = block_direction(node).unwrap_or(self.config.direction);
                 state = State {
                     weight: Weight::NORMAL,
                     style: Style::Normal,
                     heading: 0,
+                    decoration: Decoration::default(),
                 };
-                for child in node.children() {
-                    self.render_ast_node(child, state)
+
+                // a paragraph that is just `$$…$$` is a display formula
+                let mut text = String::new();
+                collect_text(node, &mut text);
+                if let Some(formula) = math::display_formula(&text) {
+                    self.write_display_math(formula);
+                } else {
+                    self.paragraph.set_direction(direction);
+                    self.paragraph.indent = self.list_indent;
+                    if let Some(marker) = self.list_marker.take() {
+                        let serif = Attrs::new().family(Family::Serif);
+                        self.paragraph.write_marker(&marker, serif);
+                    }
+                    for_each_inline(node, state, |child, state| {
+                        self.render_ast_node(child, state)
+                    });
                 }
             }
             NodeValue::Heading(heading) => {
                 self.end_last_paragraph();
+                self.set_source_line(node);
+                self.direction = block_direction(node).unwrap_or(self.config.direction);
                 state.heading = heading.level;
                 for child in node.children() {
                     self.render_ast_node(child, state)
                 }
+                self.direction = self.config.direction;
             }
             NodeValue::ThematicBreak => todo!("ThematicBreak"),
             NodeValue::FootnoteDefinition(_) => todo!("FootnoteDefinition(_)"),
@@ -166,15 +684,22 @@ impl Document {
             NodeValue::TableCell => todo!("TableCell"),
             NodeValue::Text(text) => {
                 if state.heading == 0 {
-                    self.write_body(
-                        text,
+                    let attrs = state.decoration.apply(
                         Attrs::new()
                             .family(Family::Serif)
                             .style(state.style)
                             .weight(state.weight),
                     );
+
+                    let mut last = 0;
+                    for (range, formula) in math::find_formulas(text) {
+                        self.write_text(&text[last..range.start], attrs);
+                        self.write_inline_math(formula, attrs);
+                        last = range.end;
+                    }
+                    self.write_text(&text[last..], attrs);
                 } else {
-                    self.write_header(text, state.heading);
+                    self.write_header(&math::unprotect(text), state.heading);
                 }
             }
             NodeValue::TaskItem { .. } => todo!("TaskItem"),
@@ -183,50 +708,78 @@ impl Document {
             }
             NodeValue::Code(code) => {
                 self.write_body(
-                    &code.literal.replace(' ', NBSP_STR),
-                    Attrs::new()
-                        .family(Family::Monospace)
-                        .style(state.style)
-                        .weight(state.weight)
-                        .scaling(0.9),
+                    &if self.config.typography.nbsp_in_code {
+                        code.literal.replace(' ', NBSP_STR)
+                    } else {
+                        code.literal.clone()
+                    },
+                    state.decoration.apply(
+                        Attrs::new()
+                            .family(Family::Monospace)
+                            .style(state.style)
+                            .weight(state.weight)
+                            .scaling(0.9),
+                    ),
                 );
             }
             NodeValue::HtmlInline(_) => unimplemented!("inline html not supported"),
             NodeValue::Emph => {
                 state.style = Style::Italic;
-                for child in node.children() {
+                for_each_inline(node, state, |child, state| {
                     self.render_ast_node(child, state)
-                }
+                });
             }
             NodeValue::Strong => {
                 state.weight = Weight::BOLD;
-                for child in node.children() {
+                for_each_inline(node, state, |child, state| {
                     self.render_ast_node(child, state)
-                }
+                });
+            }
+            NodeValue::Strikethrough => {
+                state.decoration = state.decoration.with(Decoration::STRIKETHROUGH);
+                for_each_inline(node, state, |child, state| {
+                    self.render_ast_node(child, state)
+                });
+            }
+            NodeValue::Superscript => {
+                state.decoration = state.decoration.with(Decoration::SUPERSCRIPT);
+                for_each_inline(node, state, |child, state| {
+                    self.render_ast_node(child, state)
+                });
             }
-            NodeValue::Strikethrough => todo!("Strikethrough"),
-            NodeValue::Superscript => todo!("Superscript"),
             NodeValue::Link(_) => todo!("Link(_)"),
             NodeValue::Image(image) => {
-                let img = image::io::Reader::open(Path::new("assets/images").join(&image.url))
-                    .unwrap()
-                    .decode()
-                    .unwrap();
+                let art = load_image(&image.url, &self.svg_options);
+                let options = image_options(node, image);
+                let number = self.next_figure_number(&options);
 
-                let mut p = Paragraph::default();
-                for child in node.children() {
-                    p.render_ast_text(
-                        child,
-                        State {
-                            weight: Weight::NORMAL,
-                            style: Style::Normal,
-                            heading: 0,
-                        },
-                    );
+                let mut caption = Paragraph::default();
+                if let Some(number) = &number {
+                    let bold = Attrs::new().family(Family::Serif).weight(Weight::BOLD);
+                    caption.write_body(&format!("Figure {number}: "), bold);
                 }
+                let state = State {
+                    weight: Weight::NORMAL,
+                    style: Style::Normal,
+                    heading: 0,
+                    decoration: Decoration::default(),
+                };
+                for_each_inline(node, state, |child, state| {
+                    caption.render_ast_text(child, state)
+                });
+
+                let mut title = String::new();
+                collect_text(node, &mut title);
+                let title = math::unprotect(&title);
 
                 self.end_last_paragraph();
-                self.add_image(p, &img);
+                self.add_image(Figure {
+                    art,
+                    caption,
+                    title,
+                    options,
+                    number,
+                });
             }
             NodeValue::FootnoteReference(_) => todo!("FootnoteReference(_)"),
         }
@@ -260,11 +813,13 @@ impl Paragraph {
             NodeValue::TableCell => todo!("TableCell"),
             NodeValue::Text(text) => {
                 self.write_body(
-                    text,
-                    Attrs::new()
-                        .family(Family::Serif)
-                        .style(state.style)
-                        .weight(state.weight),
+                    &math::unprotect(text),
+                    state.decoration.apply(
+                        Attrs::new()
+                            .family(Family::Serif)
+                            .style(state.style)
+                            .weight(state.weight),
+                    ),
                 );
             }
             NodeValue::TaskItem { .. } => todo!("TaskItem"),
@@ -275,25 +830,90 @@ impl Paragraph {
             NodeValue::HtmlInline(_) => unimplemented!("inline html not supported"),
             NodeValue::Emph => {
                 state.style = Style::Italic;
-                for child in node.children() {
+                for_each_inline(node, state, |child, state| {
                     self.render_ast_text(child, state)
-                }
+                });
             }
             NodeValue::Strong => {
                 state.weight = Weight::BOLD;
-                for child in node.children() {
+                for_each_inline(node, state, |child, state| {
                     self.render_ast_text(child, state)
-                }
+                });
+            }
+            NodeValue::Strikethrough => {
+                state.decoration = state.decoration.with(Decoration::STRIKETHROUGH);
+                for_each_inline(node, state, |child, state| {
+                    self.render_ast_text(child, state)
+                });
+            }
+            NodeValue::Superscript => {
+                state.decoration = state.decoration.with(Decoration::SUPERSCRIPT);
+                for_each_inline(node, state, |child, state| {
+                    self.render_ast_text(child, state)
+                });
             }
-            NodeValue::Strikethrough => todo!("Strikethrough"),
-            NodeValue::Superscript => todo!("Superscript"),
             NodeValue::Link(_) => todo!("Link(_)"),
             NodeValue::Image(_) => todo!("Image(_)"),
             NodeValue::FootnoteReference(_) => todo!("FootnoteReference(_)"),
         }
+    }
+}
 
-        for child in node.children() {
-            self.render_ast_text(child, state)
+/// calls `render` with each inline child of the node, where html tags such as
+/// `<sub>…</sub>` or `<u>…</u>` decorate the nodes between them
+fn for_each_inline<'a>(
+    node: &'a AstNode<'a>,
+    mut state: State,
+    mut render: impl FnMut(&'a AstNode<'a>, State),
+) {
+    for child in node.children() {
+        let tag = match &child.data.borrow().value {
+            NodeValue::HtmlInline(html) => html_decoration(html),
+            _ => None,
+        };
+        match tag {
+            Some((decoration, true)) => state.decoration = state.decoration.with(decoration),
+            Some((decoration, false)) => state.decoration = state.decoration.without(decoration),
+            None => render(child, state),
         }
     }
 }
+
+/// the decoration that an inline html tag starts, or ends if it is a closing tag
+fn html_decoration(html: &str) -> Option<(Decoration, bool)> {
+    let tag = html.strip_prefix('<')?.strip_suffix('>')?;
+    let (tag, open) = match tag.strip_prefix('/') {
+        Some(tag) => (tag, false),
+        None => (tag, true),
+    };
+    let decoration = match tag.trim() {
+        "u" | "ins" => Decoration::UNDERLINE,
+        "s" | "del" | "strike" => Decoration::STRIKETHROUGH,
+        "sup" => Decoration::SUPERSCRIPT,
+        "sub" => Decoration::SUBSCRIPT,
+        _ => return None,
+    };
+    Some((decoration, open))
+}
+
+#[test]
+fn test_split_attrs() {
+    let (options, rest) = split_attrs("{width=50%} and more").unwrap();
+    assert_eq!(options.width, Some(figure::ImageWidth::Relative(0.5)));
+    assert_eq!(rest, " and more");
+    assert!(split_attrs("{see below}").is_none());
+    assert!(split_attrs("{unclosed").is_none());
+}
+
+#[test]
+fn test_html_decoration() {
+    assert_eq!(
+        html_decoration("<sub>"),
+        Some((Decoration::SUBSCRIPT, true))
+    );
+    assert_eq!(
+        html_decoration("</u>"),
+        Some((Decoration::UNDERLINE, false))
+    );
+    assert_eq!(html_decoration("<span class=\"x\">"), None);
+}
@@ -0,0 +1,1167 @@
+//! LaTeX math formulas, parsed into nodes and laid out as boxes of glyphs, rules and strokes.
+//!
+//! Spacing and sizes come from the constants in the math font's MATH table, or TeX's
+//! rules of thumb if it doesn't have one.
+
+use std::ops::Range;
+
+use cosmic_text::{
+    fontdb, rustybuzz::ttf_parser, Attrs, AttrsList, Family, FontSystem, LayoutLine, ShapeLine,
+    Stretch, Style, Weight,
+};
+
+use crate::printpdf::Pt;
+
+/// TeX's classes of atoms, which decide the spacing between neighbouring atoms
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Class {
+    Ord,
+    /// large operators, such as `\sum` and `\lim`
+    Op,
+    /// binary operators, such as `+`
+    Bin,
+    /// relations, such as `=`
+    Rel,
+    Open,
+    Close,
+    Punct,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Node {
+    /// a single symbol. ASCII letters are set in italics
+    Char(char, Class),
+    /// upright text, such as `\text{if }` or `\log`
+    Text(String, Class),
+    Group(Vec<Node>),
+    Frac(Box<Node>, Box<Node>),
+    Sqrt(Box<Node>),
+    Scripts {
+        base: Box<Node>,
+        sup: Option<Box<Node>>,
+        sub: Option<Box<Node>>,
+    },
+    /// `\left( … \right)`, with the delimiters stretched to fit. `.` is an empty delimiter
+    Delimited(char, Vec<Node>, char),
+    /// horizontal space, in ems
+    Space(f32),
+}
+
+impl Node {
+    fn class(&self) -> Class {
+        match self {
+            Node::Char(_, class) | Node::Text(_, class) => *class,
+            Node::Scripts { base, .. } => base.class(),
+            _ => Class::Ord,
+        }
+    }
+
+    /// whether scripts are set above and below in display style, as with `\sum_{i=0}^n`
+    fn takes_limits(&self) -> bool {
+        match self {
+            Node::Char(c, Class::Op) => !matches!(c, '∫' | '∬' | '∮'),
+            Node::Text(name, Class::Op) => {
+                matches!(
+                    name.as_str(),
+                    "lim" | "max" | "min" | "sup" | "inf" | "det" | "gcd"
+                )
+            }
+            _ => false,
+        }
+    }
+}
+
+/// parses a LaTeX math formula, such as `\frac{n(n+1)}{2}`. Fails on commands that aren't
+/// supported and on mistakes such as unclosed groups, describing what went wrong
+pub fn parse(formula: &str) -> Result<Vec<Node>, String> {
+    let mut parser = Parser {
+        chars: formula.chars().peekable(),
+        right: false,
+    };
+    let nodes = parser.list()?;
+    if parser.right {
+        return Err("\\right without a matching \\left".to_owned());
+    }
+    if let Some(c) = parser.chars.next() {
+        return Err(format!("unexpected {c:?}"));
+    }
+    Ok(nodes)
+}
+
+/// removes `\tag{…}` from a display formula, returning the formula and the tag
+pub fn split_tag(formula: &str) -> (String, Option<String>) {
+    let Some(start) = formula.find("\\tag{") else {
+        return (formula.to_owned(), None);
+    };
+    // an unclosed tag is left for the parser to complain about
+    let Some(len) = formula[start..].find('}') else {
+        return (formula.to_owned(), None);
+    };
+    let tag = formula[start + 5..start + len].to_owned();
+    let formula = format!("{}{}", &formula[..start], &formula[start + len + 1..]);
+    (formula, Some(tag))
+}
+
+/// marks around an inline formula, in place of its dollar signs, once `protect` has
+/// taken it out of the markdown
+pub const INLINE_START: char = '\u{E000}';
+pub const INLINE_END: char = '\u{E001}';
+/// marks around a display formula, in place of its `$$`
+pub const DISPLAY_START: char = '\u{E002}';
+pub const DISPLAY_END: char = '\u{E003}';
+
+/// Takes the formulas out of the markdown source before it's parsed, so that `\{` isn't read
+/// as an escaped brace or `a*b*c` as emphasis. Each `$…$` or `$$…$$` is swapped for marks
+/// around its source with the ASCII punctuation escaped, which markdown gives back verbatim.
+/// Newlines are kept, so everything stays on the same line.
+///
+/// Like pandoc, the opening `$` must be followed by a non-space and the closing `$`
+/// preceded by one and not followed by a digit, so that prices like $5 aren't mistaken for
+/// math. Code blocks and code spans are left alone
+pub fn protect(markdown: &str) -> String {
+    let mut protected = String::with_capacity(markdown.len());
+    // lines of the paragraph or heading being read, as formulas may run over several
+    let mut block = String::new();
+    // the ``` or ~~~ of the fenced code block we're in
+    let mut fence: Option<&str> = None;
+    let mut indented_code = false;
+
+    for line in markdown.split_inclusive('\n') {
+        let trimmed = line.trim_start();
+        if let Some(open) = fence {
+            if trimmed.starts_with(open) {
+                fence = None;
+            }
+            protected.push_str(line);
+            continue;
+        }
+        let blank = trimmed.is_empty();
+        let indented = line.starts_with("    ") || line.starts_with('\t');
+        if indented_code && (blank || indented) {
+            protected.push_str(line);
+            continue;
+        }
+        indented_code = false;
+
+        if blank || (block.is_empty() && indented) {
+            // an indented line after a blank one starts a code block
+            protect_text(&block, &mut protected);
+            block.clear();
+            indented_code = !blank;
+            protected.push_str(line);
+        } else if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            protect_text(&block, &mut protected);
+            block.clear();
+            let len = trimmed.len() - trimmed.trim_start_matches(['`', '~']).len();
+            fence = Some(&trimmed[..len]);
+            protected.push_str(line);
+        } else if trimmed.starts_with('#') {
+            // headings are a line on their own
+            protect_text(&block, &mut protected);
+            block.clear();
+            protect_text(line, &mut protected);
+        } else {
+            block.push_str(line);
+        }
+    }
+    protect_text(&block, &mut protected);
+    protected
+}
+
+/// the formulas in a paragraph or heading, outside of its code spans
+fn protect_text(text: &str, protected: &mut String) {
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        let skip = match c {
+            // escapes, such as `\$`, are left for markdown
+            '\\' => 1 + rest[1..].chars().next().map_or(0, char::len_utf8),
+            '`' => {
+                let ticks = rest.len() - rest.trim_start_matches('`').len();
+                let fence = &rest[..ticks];
+                // a code span ends at the next run of exactly as many backticks
+                let mut end = None;
+                let mut offset = ticks;
+                while let Some(i) = rest[offset..].find(fence) {
+                    let i = offset + i;
+                    let run = rest[i..].len() - rest[i..].trim_start_matches('`').len();
+                    if run == ticks {
+                        end = Some(i + ticks);
+                        break;
+                    }
+                    offset = i + run;
+                }
+                end.unwrap_or(ticks)
+            }
+            '$' => match find_formula(rest) {
+                Some((len, formula, display)) => {
+                    let (start, end) = if display {
+                        (DISPLAY_START, DISPLAY_END)
+                    } else {
+                        (INLINE_START, INLINE_END)
+                    };
+                    protected.push(start);
+                    for c in formula.chars() {
+                        if c.is_ascii_punctuation() {
+                            protected.push('\\');
+                        }
+                        protected.push(c);
+                    }
+                    protected.push(end);
+                    rest = &rest[len..];
+                    continue;
+                }
+                // `$$` never opens an inline formula
+                None => rest.len() - rest.trim_start_matches('$').len(),
+            },
+            c => c.len_utf8(),
+        };
+        protected.push_str(&rest[..skip]);
+        rest = &rest[skip..];
+    }
+}
+
+/// the formula at the start of the text, as its length with the dollar signs, its source
+/// and whether it's a display formula
+fn find_formula(text: &str) -> Option<(usize, &str, bool)> {
+    if let Some(rest) = text.strip_prefix("$$") {
+        let end = rest.find("$$")?;
+        return Some((end + 4, &rest[..end], true));
+    }
+
+    let rest = &text[1..];
+    if rest.starts_with(|c: char| c.is_whitespace() || c == '$') || rest.is_empty() {
+        return None;
+    }
+    // a `$$` closes no inline formula, as it opens a display one
+    let (end, _) = rest.char_indices().find(|&(i, c)| {
+        c == '$'
+            && !rest[..i].ends_with(|c: char| c.is_whitespace() || c == '\\' || c == '$')
+            && !rest[i + 1..].starts_with(|c: char| c.is_ascii_digit() || c == '$')
+    })?;
+    Some((end + 2, &rest[..end], false))
+}
+
+/// Finds the formulas that `protect` took out of the markdown, returning the byte range
+/// of each, including its marks, and its source
+pub fn find_formulas(text: &str) -> Vec<(Range<usize>, &str)> {
+    let mut formulas = vec![];
+    let mut offset = 0;
+    while let Some(start) = text[offset..].find([INLINE_START, DISPLAY_START]) {
+        let start = offset + start;
+        let source = start + INLINE_START.len_utf8();
+        let Some(len) = text[source..].find([INLINE_END, DISPLAY_END]) else {
+            break;
+        };
+        let end = source + len + INLINE_END.len_utf8();
+        formulas.push((start..end, &text[source..source + len]));
+        offset = end;
+    }
+    formulas
+}
+
+/// the source of the display formula, if that's all the text is
+pub fn display_formula(text: &str) -> Option<&str> {
+    let formula = text
+        .trim()
+        .strip_prefix(DISPLAY_START)?
+        .strip_suffix(DISPLAY_END)?;
+    (!formula.contains([INLINE_START, INLINE_END, DISPLAY_START, DISPLAY_END])).then_some(formula)
+}
+
+/// whether the text ends partway through a formula, which carries on in the next node
+pub fn in_formula(text: &str) -> bool {
+    text.rfind([INLINE_START, DISPLAY_START]) > text.rfind([INLINE_END, DISPLAY_END])
+}
+
+/// the text with its formulas written with dollar signs again, for text that isn't typeset
+/// as math, such as headings and captions
+pub fn unprotect(text: &str) -> String {
+    text.replace([INLINE_START, INLINE_END], "$")
+        .replace([DISPLAY_START, DISPLAY_END], "$$")
+}
+
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    /// a `\right` ended the last list
+    right: bool,
+}
+
+impl Parser<'_> {
+    fn skip_whitespace(&mut self) {
+        while self.chars.next_if(|c| c.is_whitespace()).is_some() {}
+    }
+
+    /// atoms until the end of the group or a `\right`
+    fn list(&mut self) -> Result<Vec<Node>, String> {
+        let mut nodes = vec![];
+        loop {
+            self.skip_whitespace();
+            match self.chars.peek() {
+                None | Some('}') => return Ok(nodes),
+                Some('^' | '_') => {
+                    let base = nodes.pop().unwrap_or(Node::Group(vec![]));
+                    nodes.push(self.scripts(base)?);
+                }
+                _ => match self.atom()? {
+                    Some(node) => nodes.push(node),
+                    None => return Ok(nodes),
+                },
+            }
+        }
+    }
+
+    fn scripts(&mut self, base: Node) -> Result<Node, String> {
+        let (mut sup, mut sub) = (None, None);
+        loop {
+            self.skip_whitespace();
+            let script = match self.chars.peek() {
+                Some('^') => &mut sup,
+                Some('_') => &mut sub,
+                _ => break,
+            };
+            self.chars.next();
+            if script.replace(Box::new(self.argument()?)).is_some() {
+                return Err("double superscript or subscript".to_owned());
+            }
+        }
+        Ok(Node::Scripts {
+            base: Box::new(base),
+            sup,
+            sub,
+        })
+    }
+
+    /// a single atom or group, for scripts and commands such as `\frac`
+    fn argument(&mut self) -> Result<Node, String> {
+        self.skip_whitespace();
+        self.atom()?
+            .ok_or_else(|| "missing argument before \\right".to_owned())
+    }
+
+    /// the next atom, or `None` if it's a `\right`
+    fn atom(&mut self) -> Result<Option<Node>, String> {
+        let c = self.chars.next().ok_or("unexpected end of formula")?;
+        Ok(Some(match c {
+            '{' => {
+                let nodes = self.list()?;
+                if self.chars.next() != Some('}') {
+                    return Err("unclosed group".to_owned());
+                }
+                Node::Group(nodes)
+            }
+            '\\' => return self.command(),
+            '-' => Node::Char('−', Class::Bin),
+            '*' => Node::Char('∗', Class::Bin),
+            // smart punctuation turns primes into apostrophes
+            '\'' | '’' => Node::Char('′', Class::Ord),
+            c => Node::Char(c, class(c)),
+        }))
+    }
+
+    fn command(&mut self) -> Result<Option<Node>, String> {
+        let mut name = String::new();
+        while let Some(c) = self.chars.next_if(char::is_ascii_alphabetic) {
+            name.push(c);
+        }
+
+        if name.is_empty() {
+            // control symbols, such as `\,` or `\{`
+            let c = self.chars.next().ok_or("formula ends with a backslash")?;
+            return Ok(Some(match c {
+                ',' => Node::Space(3.0 / 18.0),
+                ':' | '>' => Node::Space(4.0 / 18.0),
+                ';' => Node::Space(5.0 / 18.0),
+                '!' => Node::Space(-3.0 / 18.0),
+                ' ' => Node::Space(0.25),
+                '{' => Node::Char('{', Class::Open),
+                '}' => Node::Char('}', Class::Close),
+                '|' => Node::Char('‖', Class::Ord),
+                c => Node::Char(c, Class::Ord),
+            }));
+        }
+
+        Ok(Some(match name.as_str() {
+            "frac" | "dfrac" | "tfrac" => {
+                let numerator = self.argument()?;
+                let denominator = self.argument()?;
+                Node::Frac(Box::new(numerator), Box::new(denominator))
+            }
+            "sqrt" => Node::Sqrt(Box::new(self.argument()?)),
+            "text" | "mathrm" => Node::Text(self.text_argument()?, Class::Ord),
+            "operatorname" => Node::Text(self.text_argument()?, Class::Op),
+            "left" => {
+                let left = self.delimiter()?;
+                let body = self.list()?;
+                if !std::mem::take(&mut self.right) {
+                    return Err("\\left without a matching \\right".to_owned());
+                }
+                let right = self.delimiter()?;
+                Node::Delimited(left, body, right)
+            }
+            "right" => {
+                self.right = true;
+                return Ok(None);
+            }
+            "quad" => Node::Space(1.0),
+            "qquad" => Node::Space(2.0),
+            "log" | "ln" | "exp" | "sin" | "cos" | "tan" | "lim" | "max" | "min" | "sup"
+            | "inf" | "det" | "gcd" | "deg" | "dim" | "ker" | "arg" => {
+                Node::Text(name.clone(), Class::Op)
+            }
+            name => {
+                let (c, class) =
+                    command_symbol(name).ok_or_else(|| format!("unknown command \\{name}"))?;
+                Node::Char(c, class)
+            }
+        }))
+    }
+
+    /// the raw text within braces, for `\text{…}`
+    fn text_argument(&mut self) -> Result<String, String> {
+        self.skip_whitespace();
+        if self.chars.next() != Some('{') {
+            return Err("expected { after \\text".to_owned());
+        }
+        let mut text = String::new();
+        for c in self.chars.by_ref() {
+            if c == '}' {
+                return Ok(text);
+            }
+            text.push(c);
+        }
+        Err("unclosed \\text".to_owned())
+    }
+
+    fn delimiter(&mut self) -> Result<char, String> {
+        self.skip_whitespace();
+        match self.atom()? {
+            Some(Node::Char(c, _)) => Ok(c),
+            _ => Err("expected a delimiter after \\left or \\right".to_owned()),
+        }
+    }
+}
+
+fn class(c: char) -> Class {
+    match c {
+        '+' => Class::Bin,
+        '=' | '<' | '>' | ':' => Class::Rel,
+        '(' | '[' => Class::Open,
+        ')' | ']' | '!' => Class::Close,
+        ',' | ';' => Class::Punct,
+        _ => Class::Ord,
+    }
+}
+
+fn command_symbol(name: &str) -> Option<(char, Class)> {
+    use Class::*;
+
+    Some(match name {
+        "alpha" => ('α', Ord),
+        "beta" => ('β', Ord),
+        "gamma" => ('γ', Ord),
+        "delta" => ('δ', Ord),
+        "epsilon" | "varepsilon" => ('ε', Ord),
+        "zeta" => ('ζ', Ord),
+        "eta" => ('η', Ord),
+        "theta" => ('θ', Ord),
+        "iota" => ('ι', Ord),
+        "kappa" => ('κ', Ord),
+        "lambda" => ('λ', Ord),
+        "mu" => ('μ', Ord),
+        "nu" => ('ν', Ord),
+        "xi" => ('ξ', Ord),
+        "pi" => ('π', Ord),
+        "rho" => ('ρ', Ord),
+        "sigma" => ('σ', Ord),
+        "tau" => ('τ', Ord),
+        "upsilon" => ('υ', Ord),
+        "phi" | "varphi" => ('φ', Ord),
+        "chi" => ('χ', Ord),
+        "psi" => ('ψ', Ord),
+        "omega" => ('ω', Ord),
+        "Gamma" => ('Γ', Ord),
+        "Delta" => ('Δ', Ord),
+        "Theta" => ('Θ', Ord),
+        "Lambda" => ('Λ', Ord),
+        "Xi" => ('Ξ', Ord),
+        "Pi" => ('Π', Ord),
+        "Sigma" => ('Σ', Ord),
+        "Phi" => ('Φ', Ord),
+        "Psi" => ('Ψ', Ord),
+        "Omega" => ('Ω', Ord),
+        "infty" => ('∞', Ord),
+        "partial" => ('∂', Ord),
+        "nabla" => ('∇', Ord),
+        "forall" => ('∀', Ord),
+        "exists" => ('∃', Ord),
+        "emptyset" => ('∅', Ord),
+        "ldots" | "dots" => ('…', Ord),
+        "cdots" => ('⋯', Ord),
+        "prime" => ('′', Ord),
+        "sum" => ('∑', Op),
+        "prod" => ('∏', Op),
+        "int" => ('∫', Op),
+        "iint" => ('∬', Op),
+        "oint" => ('∮', Op),
+        "cdot" => ('⋅', Bin),
+        "times" => ('×', Bin),
+        "div" => ('÷', Bin),
+        "pm" => ('±', Bin),
+        "mp" => ('∓', Bin),
+        "cup" => ('∪', Bin),
+        "cap" => ('∩', Bin),
+        "oplus" => ('⊕', Bin),
+        "circ" => ('∘', Bin),
+        "le" | "leq" => ('≤', Rel),
+        "ge" | "geq" => ('≥', Rel),
+        "ne" | "neq" => ('≠', Rel),
+        "ll" => ('≪', Rel),
+        "gg" => ('≫', Rel),
+        "approx" => ('≈', Rel),
+        "equiv" => ('≡', Rel),
+        "sim" => ('∼', Rel),
+        "propto" => ('∝', Rel),
+        "in" => ('∈', Rel),
+        "notin" => ('∉', Rel),
+        "subset" => ('⊂', Rel),
+        "subseteq" => ('⊆', Rel),
+        "to" | "rightarrow" => ('→', Rel),
+        "leftarrow" | "gets" => ('←', Rel),
+        "Rightarrow" | "implies" => ('⇒', Rel),
+        "Leftrightarrow" | "iff" => ('⇔', Rel),
+        "mapsto" => ('↦', Rel),
+        "langle" => ('⟨', Open),
+        "rangle" => ('⟩', Close),
+        "lfloor" => ('⌊', Open),
+        "rfloor" => ('⌋', Close),
+        "lceil" => ('⌈', Open),
+        "rceil" => ('⌉', Close),
+        _ => return None,
+    })
+}
+
+/// how a formula is set: display style for formulas on their own line, text style
+/// inline, and smaller script styles for sub and superscripts
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MathStyle {
+    Display,
+    Text,
+    Script,
+    ScriptScript,
+}
+
+impl MathStyle {
+    fn script(self) -> Self {
+        match self {
+            MathStyle::Display | MathStyle::Text => MathStyle::Script,
+            _ => MathStyle::ScriptScript,
+        }
+    }
+
+    /// style of a fraction's numerator and denominator
+    fn fraction(self) -> Self {
+        match self {
+            MathStyle::Display => MathStyle::Text,
+            MathStyle::Text => MathStyle::Script,
+            _ => MathStyle::ScriptScript,
+        }
+    }
+}
+
+/// a laid out formula. Positions are relative to the left end of the baseline, with y going up
+#[derive(Default)]
+pub struct MathBox {
+    pub width: Pt,
+    /// extent above the baseline
+    pub height: Pt,
+    /// extent below the baseline
+    pub depth: Pt,
+    pub items: Vec<MathItem>,
+}
+
+pub enum MathItem {
+    /// shaped text, with its baseline starting at (x, y)
+    Glyphs {
+        line: LayoutLine,
+        /// the text that was shaped, for the glyphs' `ToUnicode` entries
+        text: String,
+        attrs: AttrsList,
+        font_size: Pt,
+        x: Pt,
+        y: Pt,
+    },
+    /// a filled rectangle, such as a fraction bar, from its lower left corner
+    Rule { x: Pt, y: Pt, width: Pt, height: Pt },
+    /// a stroked line through the points, such as a radical sign
+    Path {
+        points: Vec<(Pt, Pt)>,
+        thickness: Pt,
+    },
+}
+
+impl MathBox {
+    fn new(width: Pt) -> Self {
+        Self {
+            width,
+            ..Default::default()
+        }
+    }
+
+    /// adds the other box's contents, with its origin at (x, y)
+    fn place(&mut self, other: MathBox, x: Pt, y: Pt) {
+        self.height = max(self.height, other.height + y);
+        self.depth = max(self.depth, other.depth - y);
+        for mut item in other.items {
+            match &mut item {
+                MathItem::Glyphs { x: ix, y: iy, .. } | MathItem::Rule { x: ix, y: iy, .. } => {
+                    *ix += x;
+                    *iy += y;
+                }
+                MathItem::Path { points, .. } => {
+                    for (px, py) in points {
+                        *px += x;
+                        *py += y;
+                    }
+                }
+            }
+            self.items.push(item);
+        }
+    }
+
+    /// adds the other box to the right of this one
+    fn append(&mut self, other: MathBox) {
+        let width = other.width;
+        self.place(other, self.width, Pt(0.0));
+        self.width += width;
+    }
+}
+
+fn max(a: Pt, b: Pt) -> Pt {
+    if a > b {
+        a
+    } else {
+        b
+    }
+}
+
+/// Sizes and gaps of the parts of a formula, in ems, from an OpenType math font's MATH table.
+/// The names are those of the table's `MathConstants`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MathConstants {
+    pub script_scale: f32,
+    pub script_script_scale: f32,
+    /// height of the fraction bar and the center of operators and delimiters
+    pub axis_height: f32,
+    pub subscript_shift_down: f32,
+    pub subscript_baseline_drop_min: f32,
+    pub superscript_shift_up: f32,
+    pub superscript_baseline_drop_max: f32,
+    pub sub_superscript_gap_min: f32,
+    pub space_after_script: f32,
+    pub upper_limit_gap_min: f32,
+    pub lower_limit_gap_min: f32,
+    pub fraction_numerator_shift_up: f32,
+    pub fraction_numerator_display_style_shift_up: f32,
+    pub fraction_denominator_shift_down: f32,
+    pub fraction_denominator_display_style_shift_down: f32,
+    pub fraction_numerator_gap_min: f32,
+    pub fraction_num_display_style_gap_min: f32,
+    pub fraction_rule_thickness: f32,
+    pub fraction_denominator_gap_min: f32,
+    pub fraction_denom_display_style_gap_min: f32,
+    pub radical_vertical_gap: f32,
+    pub radical_display_style_vertical_gap: f32,
+    pub radical_rule_thickness: f32,
+    pub radical_extra_ascender: f32,
+}
+
+/// TeX's rules of thumb, for fonts without a MATH table
+impl Default for MathConstants {
+    fn default() -> Self {
+        Self {
+            script_scale: 0.7,
+            script_script_scale: 0.5,
+            axis_height: 0.25,
+            subscript_shift_down: 0.15,
+            subscript_baseline_drop_min: 0.0,
+            superscript_shift_up: 0.4,
+            superscript_baseline_drop_max: 0.35,
+            sub_superscript_gap_min: 0.15,
+            space_after_script: 0.05,
+            upper_limit_gap_min: 0.15,
+            lower_limit_gap_min: 0.15,
+            fraction_numerator_shift_up: 0.0,
+            fraction_numerator_display_style_shift_up: 0.0,
+            fraction_denominator_shift_down: 0.0,
+            fraction_denominator_display_style_shift_down: 0.0,
+            fraction_numerator_gap_min: 0.1,
+            fraction_num_display_style_gap_min: 0.15,
+            fraction_rule_thickness: 0.05,
+            fraction_denominator_gap_min: 0.1,
+            fraction_denom_display_style_gap_min: 0.15,
+            radical_vertical_gap: 0.1,
+            radical_display_style_vertical_gap: 0.1,
+            radical_rule_thickness: 0.05,
+            radical_extra_ascender: 0.0,
+        }
+    }
+}
+
+impl MathConstants {
+    /// the constants of the family's regular font, or the defaults if it isn't installed or
+    /// has no MATH table
+    pub fn of_family(font_system: &mut FontSystem, family: &str) -> Self {
+        let query = fontdb::Query {
+            families: &[Family::Name(family)],
+            weight: Weight::NORMAL,
+            stretch: Stretch::Normal,
+            style: Style::Normal,
+        };
+        let Some(id) = font_system.db().query(&query) else {
+            return Self::default();
+        };
+        font_system
+            .get_font(id)
+            .and_then(|font| Self::read(font.rustybuzz()))
+            .unwrap_or_default()
+    }
+
+    fn read(face: &ttf_parser::Face) -> Option<Self> {
+        let math = face
+            .raw_face()
+            .table(ttf_parser::Tag::from_bytes(b"MATH"))?;
+        let i16_at = |offset: usize| -> Option<i16> {
+            Some(i16::from_be_bytes(
+                math.get(offset..offset + 2)?.try_into().ok()?,
+            ))
+        };
+        let constants = i16_at(4)? as u16 as usize;
+        let em = face.units_per_em() as f32;
+        let percent = |offset: usize| Some(i16_at(constants + offset)? as f32 / 100.0);
+        // the four-byte value records start after two percentages and two heights, and are
+        // numbered here in the order the table has them
+        let value = |record: usize| Some(i16_at(constants + 8 + record * 4)? as f32 / em);
+        Some(Self {
+            script_scale: percent(0)?,
+            script_script_scale: percent(2)?,
+            axis_height: value(1)?,
+            subscript_shift_down: value(4)?,
+            subscript_baseline_drop_min: value(6)?,
+            superscript_shift_up: value(7)?,
+            superscript_baseline_drop_max: value(10)?,
+            sub_superscript_gap_min: value(11)?,
+            space_after_script: value(13)?,
+            upper_limit_gap_min: value(14)?,
+            lower_limit_gap_min: value(16)?,
+            fraction_numerator_shift_up: value(28)?,
+            fraction_numerator_display_style_shift_up: value(29)?,
+            fraction_denominator_shift_down: value(30)?,
+            fraction_denominator_display_style_shift_down: value(31)?,
+            fraction_numerator_gap_min: value(32)?,
+            fraction_num_display_style_gap_min: value(33)?,
+            fraction_rule_thickness: value(34)?,
+            fraction_denominator_gap_min: value(35)?,
+            fraction_denom_display_style_gap_min: value(36)?,
+            radical_vertical_gap: value(45)?,
+            radical_display_style_vertical_gap: value(46)?,
+            radical_rule_thickness: value(47)?,
+            radical_extra_ascender: value(48)?,
+        })
+    }
+
+    fn scale(&self, style: MathStyle) -> f32 {
+        match style {
+            MathStyle::Display | MathStyle::Text => 1.0,
+            MathStyle::Script => self.script_scale,
+            MathStyle::ScriptScript => self.script_script_scale,
+        }
+    }
+}
+
+pub struct MathLayout<'a> {
+    pub font_system: &'a mut FontSystem,
+    /// family for symbols and operators, ideally an OpenType math font such as
+    /// DejaVu Math TeX Gyre
+    pub math_font: &'a str,
+    pub constants: MathConstants,
+    /// size of text in text and display style
+    pub font_size: Pt,
+}
+
+impl<'a> MathLayout<'a> {
+    pub fn layout(&mut self, nodes: &[Node], style: MathStyle) -> MathBox {
+        let mut out = MathBox::default();
+        let mut prev = None;
+        for node in nodes {
+            let mut class = node.class();
+            // a binary operator without a left operand is unary, as in `-x`
+            if class == Class::Bin
+                && matches!(
+                    prev,
+                    None | Some(Class::Bin | Class::Op | Class::Rel | Class::Open | Class::Punct)
+                )
+            {
+                class = Class::Ord;
+            }
+            if let Some(prev) = prev {
+                out.width += self.em(style) * spacing(prev, class, style);
+            }
+            let node = self.node(node, style);
+            out.append(node);
+            prev = Some(class);
+        }
+        out
+    }
+
+    fn em(&self, style: MathStyle) -> Pt {
+        self.font_size * self.constants.scale(style)
+    }
+
+    /// height of the fraction bar and the center of operators and delimiters
+    fn axis(&self, style: MathStyle) -> Pt {
+        self.em(style) * self.constants.axis_height
+    }
+
+    fn node(&mut self, node: &Node, style: MathStyle) -> MathBox {
+        let em = self.em(style);
+        match node {
+            Node::Char(c, class) => {
+                let text = c.to_string();
+                if *class == Class::Op {
+                    // large operators are bigger in display style, and centered on the axis
+                    let size = if style == MathStyle::Display {
+                        em * 1.4
+                    } else {
+                        em
+                    };
+                    let glyph = self.text(&text, self.symbol_attrs(*c), size);
+                    let shift = self.axis(style) - (glyph.height - glyph.depth) * 0.5;
+                    let mut out = MathBox::new(glyph.width);
+                    out.place(glyph, Pt(0.0), shift);
+                    return out;
+                }
+                self.text(&text, self.symbol_attrs(*c), em)
+            }
+            Node::Text(text, _) => self.text(text, Attrs::new().family(Family::Serif), em),
+            Node::Group(nodes) => self.layout(nodes, style),
+            Node::Space(ems) => MathBox::new(em * *ems),
+            Node::Frac(numerator, denominator) => {
+                let inner = style.fraction();
+                let numerator = self.node(numerator, inner);
+                let denominator = self.node(denominator, inner);
+
+                let c = self.constants;
+                let axis = self.axis(style);
+                let rule = em * c.fraction_rule_thickness;
+                let display = style == MathStyle::Display;
+                let (shift_up, shift_down, numerator_gap, denominator_gap) = if display {
+                    (
+                        c.fraction_numerator_display_style_shift_up,
+                        c.fraction_denominator_display_style_shift_down,
+                        c.fraction_num_display_style_gap_min,
+                        c.fraction_denom_display_style_gap_min,
+                    )
+                } else {
+                    (
+                        c.fraction_numerator_shift_up,
+                        c.fraction_denominator_shift_down,
+                        c.fraction_numerator_gap_min,
+                        c.fraction_denominator_gap_min,
+                    )
+                };
+                let padding = em * 0.1;
+                let width = max(numerator.width, denominator.width) + padding * 2.0;
+
+                let mut out = MathBox::new(width);
+                out.items.push(MathItem::Rule {
+                    x: padding * 0.5,
+                    y: axis - rule * 0.5,
+                    width: width - padding,
+                    height: rule,
+                });
+                // at least the font's shift, and further if needed to clear the bar
+                let shift = max(
+                    em * shift_up,
+                    axis + rule * 0.5 + em * numerator_gap + numerator.depth,
+                );
+                let x = (width - numerator.width) * 0.5;
+                out.place(numerator, x, shift);
+                let shift = max(
+                    em * shift_down,
+                    denominator.height + em * denominator_gap + rule * 0.5 - axis,
+                );
+                let shift = Pt(0.0) - shift;
+                let x = (width - denominator.width) * 0.5;
+                out.place(denominator, x, shift);
+                out
+            }
+            Node::Sqrt(body) => {
+                let body = self.node(body, style);
+                let rule = em * self.constants.radical_rule_thickness;
+                let gap = if style == MathStyle::Display {
+                    self.constants.radical_display_style_vertical_gap
+                } else {
+                    self.constants.radical_vertical_gap
+                };
+                let sign = em * 0.6;
+                let top = body.height + em * gap + rule * 0.5;
+                let bottom = Pt(0.0) - body.depth;
+                let height = top - bottom;
+
+                let mut out = MathBox::new(sign + body.width + em * 0.1);
+                out.items.push(MathItem::Path {
+                    points: vec![
+                        (em * 0.05, bottom + height * 0.4),
+                        (sign * 0.3, bottom + height * 0.5),
+                        (sign * 0.55, bottom),
+                        (sign, top),
+                        (out.width, top),
+                    ],
+                    thickness: rule,
+                });
+                out.place(body, sign, Pt(0.0));
+                let extra = em * self.constants.radical_extra_ascender;
+                out.height = max(out.height, top + rule * 0.5 + extra);
+                out
+            }
+            Node::Scripts { base, sup, sub } => {
+                let limits = style == MathStyle::Display && base.takes_limits();
+                let base_box = self.node(base, style);
+                let sup = sup.as_ref().map(|sup| self.node(sup, style.script()));
+                let sub = sub.as_ref().map(|sub| self.node(sub, style.script()));
+                if limits {
+                    self.limits(base_box, sup, sub, em)
+                } else {
+                    self.side_scripts(base_box, sup, sub, em)
+                }
+            }
+            Node::Delimited(left, body, right) => {
+                let body = self.layout(body, style);
+                let axis = self.axis(style);
+                // delimiters grow evenly around the axis to cover the body
+                let extent = max(body.height - axis, body.depth + axis);
+                let size = max(em, extent * 2.2);
+
+                let mut out = MathBox::default();
+                let mut delimiter = |out: &mut MathBox, c: char| {
+                    if c == '.' {
+                        return;
+                    }
+                    let glyph = self.text(&c.to_string(), self.symbol_attrs(c), size);
+                    let width = glyph.width;
+                    let shift = axis - (glyph.height - glyph.depth) * 0.5;
+                    out.place(glyph, out.width, shift);
+                    out.width += width;
+                };
+                delimiter(&mut out, *left);
+                out.append(body);
+                delimiter(&mut out, *right);
+                out
+            }
+        }
+    }
+
+    /// scripts above and below the base, for large operators in display style
+    fn limits(
+        &mut self,
+        base: MathBox,
+        sup: Option<MathBox>,
+        sub: Option<MathBox>,
+        em: Pt,
+    ) -> MathBox {
+        let width = [Some(&base), sup.as_ref(), sub.as_ref()]
+            .into_iter()
+            .flatten()
+            .fold(Pt(0.0), |width, b| max(width, b.width));
+
+        let mut out = MathBox::new(width);
+        let (height, depth) = (base.height, base.depth);
+        let x = (width - base.width) * 0.5;
+        out.place(base, x, Pt(0.0));
+        if let Some(sup) = sup {
+            let shift = height + em * self.constants.upper_limit_gap_min + sup.depth;
+            let x = (width - sup.width) * 0.5;
+            out.place(sup, x, shift);
+        }
+        if let Some(sub) = sub {
+            let shift = Pt(0.0) - depth - em * self.constants.lower_limit_gap_min - sub.height;
+            let x = (width - sub.width) * 0.5;
+            out.place(sub, x, shift);
+        }
+        out
+    }
+
+    /// superscripts raised and subscripts lowered to the right of the base
+    fn side_scripts(
+        &mut self,
+        base: MathBox,
+        sup: Option<MathBox>,
+        sub: Option<MathBox>,
+        em: Pt,
+    ) -> MathBox {
+        let c = self.constants;
+        let mut sup_shift = max(
+            em * c.superscript_shift_up,
+            base.height - em * c.superscript_baseline_drop_max,
+        );
+        let mut sub_shift = max(
+            em * c.subscript_shift_down,
+            base.depth + em * c.subscript_baseline_drop_min,
+        );
+
+        // keep the superscript and subscript apart
+        if let (Some(sup), Some(sub)) = (&sup, &sub) {
+            let gap = (sup_shift - sup.depth) - (sub.height - sub_shift);
+            let min_gap = em * c.sub_superscript_gap_min;
+            if gap < min_gap {
+                let extra = min_gap - gap;
+                sup_shift += extra * 0.5;
+                sub_shift += extra * 0.5;
+            }
+        }
+
+        let x = base.width;
+        let mut out = MathBox::default();
+        out.append(base);
+        let mut scripts_width = Pt(0.0);
+        if let Some(sup) = sup {
+            scripts_width = max(scripts_width, sup.width);
+            out.place(sup, x, sup_shift);
+        }
+        if let Some(sub) = sub {
+            scripts_width = max(scripts_width, sub.width);
+            out.place(sub, x, Pt(0.0) - sub_shift);
+        }
+        out.width += scripts_width + em * c.space_after_script;
+        out
+    }
+
+    /// italic serif for latin letters, upright serif for digits and ASCII punctuation,
+    /// and the math font for everything else
+    fn symbol_attrs(&self, c: char) -> Attrs<'a> {
+        let attrs = Attrs::new().family(Family::Serif);
+        if c.is_ascii_alphabetic() {
+            attrs.style(Style::Italic)
+        } else if c.is_ascii() {
+            attrs
+        } else {
+            attrs.family(Family::Name(self.math_font))
+        }
+    }
+
+    fn text(&mut self, text: &str, attrs: Attrs, font_size: Pt) -> MathBox {
+        let attrs = AttrsList::new(attrs);
+        let shape = ShapeLine::new(&mut *self.font_system, text, &attrs);
+        let line = shape
+            .layout(
+                font_size.0,
+                f32::INFINITY,
+                cosmic_text::Wrap::None,
+                Some(cosmic_text::Align::Left),
+            )
+            .remove(0);
+
+        MathBox {
+            width: Pt(line.w),
+            height: font_size * 0.72,
+            depth: font_size * 0.22,
+            items: vec![MathItem::Glyphs {
+                line,
+                text: text.to_owned(),
+                attrs,
+                font_size,
+                x: Pt(0.0),
+                y: Pt(0.0),
+            }],
+        }
+    }
+}
+
+/// space between neighbouring atoms, in ems. Only thin spaces are kept in script styles
+fn spacing(left: Class, right: Class, style: MathStyle) -> f32 {
+    use Class::*;
+
+    let script = style >= MathStyle::Script;
+    match (left, right) {
+        (Rel, Rel | Close | Punct) | (Open, _) | (_, Close | Punct) => 0.0,
+        (Op, Ord | Op) | (Ord | Close, Op) => 3.0 / 18.0,
+        _ if script => 0.0,
+        (Bin, _) | (_, Bin) => 4.0 / 18.0,
+        (Rel, _) | (_, Rel) => 5.0 / 18.0,
+        (Punct, _) => 3.0 / 18.0,
+        _ => 0.0,
+    }
+}
+
+#[test]
+fn test_parse() {
+    assert_eq!(
+        parse(r"\frac{a}{2} \le x_i^2"),
+        Ok(vec![
+            Node::Frac(
+                Box::new(Node::Group(vec![Node::Char('a', Class::Ord)])),
+                Box::new(Node::Group(vec![Node::Char('2', Class::Ord)])),
+            ),
+            Node::Char('≤', Class::Rel),
+            Node::Scripts {
+                base: Box::new(Node::Char('x', Class::Ord)),
+                sup: Some(Box::new(Node::Char('2', Class::Ord))),
+                sub: Some(Box::new(Node::Char('i', Class::Ord))),
+            },
+        ])
+    );
+    assert_eq!(
+        parse(r"\left( -1 \right]"),
+        Ok(vec![Node::Delimited(
+            '(',
+            vec![Node::Char('−', Class::Bin), Node::Char('1', Class::Ord)],
+            ']'
+        )])
+    );
+    assert_eq!(parse(r"\hat{x}"), Err("unknown command \\hat".to_owned()));
+    assert_eq!(parse(r"\frac{a}{2"), Err("unclosed group".to_owned()));
+    assert_eq!(
+        parse(r"x^2^3"),
+        Err("double superscript or subscript".to_owned())
+    );
+}
+
+#[test]
+fn test_math_constants() {
+    let font = "example/assets/fonts/DejaVu_Math_TeX_Gyre/DejaVuMathTeXGyre.ttf";
+    let data = std::fs::read(font).unwrap();
+    let face = ttf_parser::Face::parse(&data, 0).unwrap();
+    let constants = MathConstants::read(&face).unwrap();
+    assert_eq!(constants.script_scale, 0.8);
+    assert_eq!(constants.axis_height, 0.275);
+    assert_eq!(constants.fraction_rule_thickness, 0.064);
+    assert_eq!(constants.radical_display_style_vertical_gap, 0.096);
+}
+
+#[test]
+fn test_protect() {
+    use comrak::{nodes::NodeValue, Arena, ComrakOptions};
+
+    let text = "costs $20,000 or $30,000";
+    assert_eq!(protect(text), text);
+    let text = "`$x$` and\n\n    $y$\n";
+    assert_eq!(protect(text), text);
+
+    // the formulas come out of markdown as they were written
+    let markdown = "where $\\{x\\}$ and $a*b*c$\nbut not $ z$, and $$\\frac{1}{2}\n\\, x$$";
+    let arena = Arena::new();
+    let root = comrak::parse_document(&arena, &protect(markdown), &ComrakOptions::default());
+    let paragraph = root.first_child().unwrap();
+    crate::typography::merge_text_nodes(paragraph);
+    // the first line isn't in a formula, so it stays a text node of its own
+    let text: String = paragraph
+        .children()
+        .filter_map(|node| match &node.data.borrow().value {
+            NodeValue::Text(text) => Some(text.clone()),
+            _ => None,
+        })
+        .collect();
+    let found: Vec<&str> = find_formulas(&text).into_iter().map(|(_, f)| f).collect();
+    assert_eq!(found, vec!["\\{x\\}", "a*b*c", "\\frac{1}{2}\n\\, x"]);
+    assert!(unprotect(&text).contains("but not $ z$"));
+}
@@ -1,13 +1,26 @@
-use std::{collections::HashSet, ops::Div};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet, VecDeque},
+    ops::{Div, Range},
+};
 
-use crate::printpdf::{
-    ImageTransform, IndirectFontRef, Line, Mm, PdfDocument, PdfLayerIndex, PdfPageIndex, Point, Pt,
-    Rgb,
+use crate::{
+    color_glyph,
+    config::{BookConfig, FontConfig, FooterLayout, TextDirection, WritingMode},
+    diagram,
+    figure::{ImageAlign, ImageOptions, ImageWidth, Placement},
+    math::{self, MathBox, MathConstants, MathItem, MathLayout, MathStyle},
+    printpdf::{
+        CurTransMat, Destination, FontInstance, Image, ImageTransform, IndirectFontRef, Line,
+        LinkAnnotation, Mm, PageLabel, PageLabelStyle, PdfDocument, PdfLayer, PdfLayerIndex,
+        PdfPageIndex, Point, Pt, Rgb, Svg, SvgText, SvgTextAnchor, SvgTransform, TextMatrix,
+        TextRenderingMode,
+    },
+    typography::{self, is_cjk},
 };
 use cosmic_text::{
-    fontdb, Attrs, AttrsList, Color, Family, FontSystem, LayoutLine, ShapeLine, Weight,
+    fontdb, rustybuzz::ttf_parser, Attrs, AttrsList, Color, Family, FontSystem, LayoutGlyph,
+    LayoutLine, ShapeLine, Style, Weight,
 };
-use image::DynamicImage;
 use syntect::{
     highlighting::{HighlightState, Highlighter, RangedHighlightIterator, ThemeSet},
     parsing::{ParseState, ScopeStack, SyntaxSet},
@@ -54,46 +67,477 @@ const PAGE_HEIGHT: Mm = Mm(297.0);
 const X_MARGIN: Mm = Mm(10.0);
 const Y_MARGIN: Mm = Mm(25.0);
 const BOTTOM_RULE: Mm = Mm(PAGE_HEIGHT.0 - Y_MARGIN.0);
+/// smallest text set on the spine of the print cover. Longer titles leave it blank
+const MIN_SPINE_SIZE: Pt = Pt(5.0);
+/// in vertical writing, the text layer is turned a quarter turn clockwise about this
+/// height, so that lines start `Y_MARGIN` from the top of the page and run down it
+const VERTICAL_TOP: Mm = Mm(PAGE_HEIGHT.0 - Y_MARGIN.0 + X_MARGIN.0);
 
 pub struct Fonts {
     pub font_system: FontSystem,
-    pub fonts: HashSet<fontdb::ID>,
+    pub fonts: HashSet<FontInstance>,
+    /// the text each glyph was first written for, which becomes the font's `ToUnicode` map
+    pub glyph_text: HashMap<fontdb::ID, HashMap<u16, String>>,
+    pub fallback: FontConfig,
+    /// the markdown file and line of the text being shaped, for warnings
+    pub source: Option<(String, usize)>,
+    /// where each further markdown line of the paragraph being shaped starts in its text
+    pub line_starts: Vec<usize>,
+    /// the missing characters already warned about, by where they are, as a paragraph
+    /// can be shaped more than once
+    pub warned: HashSet<(String, char)>,
 }
 
 impl Fonts {
-    fn get_font_by_id(&mut self, id: fontdb::ID) -> IndirectFontRef {
-        self.fonts.insert(id);
+    fn get_font_by_id(&mut self, id: fontdb::ID, attrs: &Attrs, font_size: Pt) -> IndirectFontRef {
+        let instance = self.instance(id, attrs, font_size);
+        let font = self.font_ref(&instance);
+        self.fonts.insert(instance);
+        font
+    }
+
+    /// the font for glyphs that stand upright in vertical writing, which advance down the page
+    fn get_upright_font_by_id(
+        &mut self,
+        id: fontdb::ID,
+        attrs: &Attrs,
+        font_size: Pt,
+    ) -> IndirectFontRef {
+        let instance = FontInstance {
+            vertical: true,
+            ..self.instance(id, attrs, font_size)
+        };
+        let font = self.font_ref(&instance);
+        self.fonts.insert(instance);
+        font
+    }
+
+    /// the instance of the font for text with the attributes. Variable fonts are set at
+    /// the axis values for the family's role, and static fonts only have the one instance.
+    ///
+    /// cosmic-text shapes with the default instance, so text at other widths or weights
+    /// may end up a little longer or shorter than it was laid out
+    fn instance(&mut self, id: fontdb::ID, attrs: &Attrs, font_size: Pt) -> FontInstance {
+        let axes = self.fallback.axes(attrs.family);
+        let font = self.font_system.get_font(id).unwrap();
+        let mut variations = vec![];
+        for axis in font.rustybuzz().variation_axes() {
+            let value = match &axis.tag.to_bytes() {
+                b"wght" => attrs.weight.0 as f32 + axes.weight - 400.0,
+                b"wdth" => axes.width,
+                b"opsz" => axes.optical_size(font_size.0),
+                _ => continue,
+            };
+            let value = (value.clamp(axis.min_value, axis.max_value) * 10.0).round() as i32;
+            if value != (axis.def_value * 10.0).round() as i32 {
+                variations.push((axis.tag.to_bytes(), value));
+            }
+        }
+        FontInstance {
+            id,
+            variations,
+            vertical: false,
+        }
+    }
+
+    /// shape the text, setting each character in the first font of its family's fallback
+    /// chain that has it. Warns about characters that none of them have, as those are set
+    /// with `.notdef` or whichever system font has them. `offset` is where the text starts
+    /// in the paragraph, to find the line each character is on
+    fn shape(&mut self, text: &str, offset: usize, attrs: &AttrsList) -> ShapeLine {
+        let mut fallback = copy_attrs(attrs);
+        let mut missing = BTreeSet::new();
+        // the best match for each family and style, or None if it isn't installed
+        let mut faces = HashMap::new();
+        // characters in a row set in the same fallback font are one span, so they
+        // still shape together
+        let mut run: Option<(Range<usize>, Attrs)> = None;
+        let mut previous = None;
+        for (i, c) in text.char_indices() {
+            let span = attrs.get_span(i);
+            let chain = self.fallback.chain(span.family);
+            let font = match previous {
+                Some(font) if joins_previous(c) => Some(font),
+                _ => chain.iter().copied().find(|&family| {
+                    let key = (family, span.weight, span.stretch, span.style);
+                    let face = faces.entry(key).or_insert_with(|| {
+                        let query = fontdb::Query {
+                            families: &[Family::Name(family)],
+                            weight: span.weight,
+                            stretch: span.stretch,
+                            style: span.style,
+                        };
+                        let id = self.font_system.db().query(&query)?;
+                        self.font_system.get_font(id)
+                    });
+                    face.as_ref()
+                        .map_or(false, |font| font.rustybuzz().glyph_index(c).is_some())
+                }),
+            };
+            // fonts often leave out direction marks and word joiners, which are never drawn
+            if font.is_none() && !c.is_control() && !is_format_control(c) {
+                let line = self
+                    .line_starts
+                    .partition_point(|&start| start <= offset + i);
+                missing.insert((line, c));
+            }
+            previous = font;
+
+            let Some(family) = font.filter(|&family| chain.first() != Some(&family)) else {
+                continue;
+            };
+            let range = i..i + c.len_utf8();
+            let font_attrs = span.family(Family::Name(family));
+            run = match run.take() {
+                Some((current, current_attrs))
+                    if current.end == i && current_attrs == font_attrs =>
+                {
+                    Some((current.start..range.end, font_attrs))
+                }
+                other => {
+                    if let Some((range, attrs)) = other {
+                        fallback.add_span(range, attrs);
+                    }
+                    Some((range, font_attrs))
+                }
+            };
+        }
+        if let Some((range, attrs)) = run {
+            fallback.add_span(range, attrs);
+        }
+
+        for (line, c) in missing {
+            let location = self.location(line);
+            if self.warned.insert((location.clone(), c)) {
+                log::warn!(
+                    "{location}no font in the fallback chain has {c:?} (U+{:04X})",
+                    c as u32
+                );
+            }
+        }
+
+        ShapeLine::new(&mut self.font_system, text, &fallback)
+    }
+
+    /// write the glyphs, along with the text each one stands for. A glyph can only map to one
+    /// text in the `ToUnicode` map, so if any was written for different text before, such as a
+    /// contextual alternate, the run is wrapped in an `ActualText` span
+    fn write_glyphs<'t>(
+        &mut self,
+        layer: &mut PdfLayer,
+        font_id: fontdb::ID,
+        glyphs: impl IntoIterator<Item = (u16, &'t str)>,
+    ) {
+        self.write_spaced_glyphs(layer, font_id, glyphs, &[]);
+    }
+
+    /// write the glyphs, moving the one after each back by its adjustment, in thousandths
+    /// of an em
+    fn write_spaced_glyphs<'t>(
+        &mut self,
+        layer: &mut PdfLayer,
+        font_id: fontdb::ID,
+        glyphs: impl IntoIterator<Item = (u16, &'t str)>,
+        adjustments: &[f32],
+    ) {
+        // format controls have no width and nothing to draw, and would otherwise be
+        // mapped to whichever glyph the shaper hid them with
+        let glyphs: Vec<(u16, &str, f32)> = glyphs
+            .into_iter()
+            .enumerate()
+            .map(|(i, (glyph, text))| (glyph, text, adjustments.get(i).copied().unwrap_or(0.0)))
+            .filter(|(_, text, _)| text.is_empty() || !text.chars().all(is_format_control))
+            .collect();
+        let texts = self.glyph_text.entry(font_id).or_default();
+        let mut ambiguous = false;
+        for &(glyph, text, _) in &glyphs {
+            match texts.get(&glyph) {
+                Some(mapped) => ambiguous |= mapped != text,
+                None => {
+                    texts.insert(glyph, text.to_owned());
+                }
+            }
+        }
+
+        if ambiguous {
+            let text: String = glyphs.iter().map(|&(_, text, _)| text).collect();
+            layer.begin_actual_text(&text);
+        }
+        if glyphs
+            .iter()
+            .all(|&(_, _, adjustment)| adjustment.abs() < 0.01)
+        {
+            layer.write_codepoints(glyphs.iter().map(|&(glyph, _, _)| glyph));
+        } else {
+            let glyphs = glyphs
+                .iter()
+                .map(|&(glyph, _, adjustment)| (glyph, adjustment));
+            layer.write_positioned_codepoints(glyphs);
+        }
+        if ambiguous {
+            layer.end_marked_content();
+        }
+    }
+
+    /// how much further each glyph advances in the embedded font than it was laid out, in
+    /// thousandths of an em. Variable fonts are shaped with their default instance, but
+    /// embedded instanced at the text's axis values, which changes the widths. Empty if the
+    /// font isn't instanced
+    fn instance_adjustments(
+        &mut self,
+        id: fontdb::ID,
+        attrs: &Attrs,
+        size: Pt,
+        glyphs: &[LayoutGlyph],
+    ) -> Vec<f32> {
+        let instance = self.instance(id, attrs, size);
+        if instance.variations.is_empty() {
+            return vec![];
+        }
+        let font = self.font_system.get_font(id).unwrap();
+        let mut face = (**font.rustybuzz()).clone();
+        for (tag, value) in &instance.variations {
+            face.set_variation(ttf_parser::Tag::from_bytes(tag), *value as f32 / 10.0);
+        }
+        let units_per_em = face.units_per_em() as f32;
+        let em = Dots::from(size).0;
+        glyphs
+            .iter()
+            .map(|glyph| {
+                let advance = face
+                    .glyph_hor_advance(ttf_parser::GlyphId(glyph.cache_key.glyph_id))
+                    .unwrap_or(0);
+                (advance as f32 / units_per_em - glyph.w / em) * 1000.0
+            })
+            .collect()
+    }
+
+    /// where the text being shaped is, `lines` markdown lines into its block, as a prefix
+    /// for warnings
+    pub fn location(&self, lines: usize) -> String {
+        match &self.source {
+            Some((file, line)) => format!("{file}:{}: ", line + lines),
+            None => String::new(),
+        }
+    }
+
+    /// reference to the font, without marking it as used in the book
+    fn font_ref(&self, instance: &FontInstance) -> IndirectFontRef {
+        let face = self.font_system.db().face(instance.id).unwrap();
         IndirectFontRef {
-            name: self
-                .font_system
-                .db()
-                .face(id)
-                .unwrap()
-                .post_script_name
-                .to_owned(),
+            name: instance.name(&face.post_script_name),
         }
     }
 }
 
 pub struct Document {
+    pub config: BookConfig,
     pub fonts: Fonts,
     pub pdf: PdfDocument,
     pub pages: Vec<Page>,
     pub paragraph: Paragraph,
     pub syntax: SyntaxSet,
     pub theme: ThemeSet,
+    /// fonts and defaults for parsing SVG figures
+    pub svg_options: usvg::Options,
+    /// number of figures in the current chapter
     pub images: usize,
+    /// number of the current chapter, counting from 1
+    pub chapter: usize,
+    /// page number ranges, as (index into `pages` of the first page, label)
+    pub numbering: Vec<(usize, PageLabel)>,
+    /// whether figures are currently numbered. only figures within chapters are
+    pub numbered_figures: bool,
+    /// figure numbers by label, worked out before any chapter is written so that
+    /// references can point forwards
+    pub figure_numbers: HashMap<String, String>,
+    /// numbered figures that have been placed
+    pub figures: Vec<FigureEntry>,
+    /// links and formulas within the text. `Attrs::metadata` is an index into this, plus one
+    pub span_data: Vec<SpanData>,
+    /// links written so far, resolved into annotations by `write_links`
+    pub links: Vec<PendingLink>,
+    /// pages set aside for the list of figures, as a range of indices into `pages`
+    pub list_of_figures: Option<Range<usize>>,
+    /// figures deferred to the top of the next page
+    pub floats: VecDeque<FloatingFigure>,
+    /// base direction of the heading being written
+    pub direction: TextDirection,
+    /// writing mode of the chapter or section being written
+    pub writing_mode: WritingMode,
+    /// whether the next paragraph opens a chapter, and so gets its drop cap and small caps
+    pub chapter_opening: bool,
+    /// how far paragraphs in the list being written are indented from both margins
+    pub list_indent: Mm,
+    /// marker of the list item being written, for its first paragraph
+    pub list_marker: Option<String>,
+}
+
+/// the title, epigraph and image set on the first page of a chapter
+pub struct ChapterOpener {
+    pub title: Option<String>,
+    /// writing mode of the chapter, if not the book's
+    pub writing_mode: Option<WritingMode>,
+    pub epigraph: Option<Paragraph>,
+    pub image: Option<(Art, ImageOptions)>,
+}
+
+/// a figure and its caption
+pub struct Figure {
+    pub art: Art,
+    pub caption: Paragraph,
+    /// plain text of the caption, for the list of figures
+    pub title: String,
+    pub options: ImageOptions,
+    /// eg "3.2", if the figure is numbered
+    pub number: Option<String>,
+}
+
+/// a figure that has been sized, waiting to be placed
+pub struct FloatingFigure {
+    art: Art,
+    caption: Option<ShapedLines>,
+    title: String,
+    number: Option<String>,
+    align: ImageAlign,
+    placement: Placement,
+    /// width of the art in pixels
+    pixel_width: f32,
+    width: Mm,
+    height: Mm,
+}
+
+impl FloatingFigure {
+    /// height of the figure with its caption and the space around them
+    fn total_height(&self) -> Mm {
+        self.height + caption_height(self.caption.as_ref())
+    }
+}
+
+/// height of a figure's caption, along with the line above it and the line after the figure
+fn caption_height(caption: Option<&ShapedLines>) -> Mm {
+    let line_height = Mm::from(Pt(14.0));
+    let caption_lines = caption.map_or(0, |c| c.lines.len());
+    line_height * (caption_lines + 2) as f32
+}
+
+/// a numbered figure that has been placed
+pub struct FigureEntry {
+    pub number: String,
+    pub title: String,
+    /// index into `pages`
+    pub page: usize,
+    /// distance from the top of the page to the top of the figure
+    pub top: Mm,
+}
+
+/// extra data for a span of text, referenced from its `Attrs::metadata`
+pub enum SpanData {
+    /// a link to the figure with this number
+    FigureLink(String),
+    /// an inline formula, drawn in place of the span's single placeholder glyph
+    Math(MathBox),
+}
+
+/// lines drawn under or through text, and raised or lowered text. These are kept
+/// in the top bits of `Attrs::metadata`, so they can be combined with `span_data`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Decoration(usize);
+
+impl Decoration {
+    pub const UNDERLINE: Self = Self(1 << (usize::BITS - 1));
+    pub const STRIKETHROUGH: Self = Self(1 << (usize::BITS - 2));
+    pub const SUPERSCRIPT: Self = Self(1 << (usize::BITS - 3));
+    pub const SUBSCRIPT: Self = Self(1 << (usize::BITS - 4));
+    /// capitals standing in for lowercase letters, eg in the first line of a chapter
+    const SMALL_CAPS: Self = Self(1 << (usize::BITS - 5));
+    const MASK: usize = 0b11111 << (usize::BITS - 5);
+
+    /// size of raised and lowered text, relative to the text around it
+    const SCRIPT_SCALING: f32 = 0.65;
+
+    pub fn with(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    pub fn without(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    fn from_metadata(metadata: usize) -> Self {
+        Self(metadata & Self::MASK)
+    }
+
+    /// mark the text as decorated. Raised and lowered text is laid out smaller
+    pub fn apply(self, attrs: Attrs) -> Attrs {
+        let attrs = attrs.metadata(attrs.metadata | self.0);
+        if self.contains(Self::SUPERSCRIPT) || self.contains(Self::SUBSCRIPT) {
+            attrs.scaling(attrs.scaling * Self::SCRIPT_SCALING)
+        } else {
+            attrs
+        }
+    }
+}
+
+/// index into `span_data` of the metadata, if it has any
+fn span_index(metadata: usize) -> Option<usize> {
+    (metadata & !Decoration::MASK).checked_sub(1)
+}
+
+/// a clickable area linking to a figure, which might not have been placed yet
+pub struct PendingLink {
+    pub page: PdfPageIndex,
+    pub lower_left: Point,
+    pub upper_right: Point,
+    /// index into `span_data`
+    pub target: usize,
 }
 
 pub struct Page {
     pub page: PdfPageIndex,
     pub text: PdfLayerIndex,
+    /// layer for the running header and page number, which aren't turned with vertical text
+    pub furniture: PdfLayerIndex,
     pub y_offset: Mm,
+    pub kind: PageKind,
+    /// whether the text layer is turned for vertical writing
+    pub vertical: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum PageKind {
+    /// regular page, with a running header and a page number
+    Body,
+    /// first page of a chapter. no running header
+    Opener,
+    /// intentionally left blank. no header or page number
+    Blank,
+    /// title, copyright and dedication pages. no header or page number
+    Display,
+    /// full-bleed cover art before the printed interior. no header or page number
+    Cover,
+}
+
+/// a raster image or an SVG, for figures and cover artwork
+#[derive(Clone)]
+pub enum Art {
+    Image(Image),
+    Svg(Svg),
 }
 
 pub struct Paragraph {
     pub text: String,
     pub attrs: AttrsList,
+    /// where each markdown line after the first starts in the text, for warnings
+    pub line_starts: Vec<usize>,
+    /// how far the paragraph is indented from both margins, eg in a list
+    pub indent: Mm,
+    /// length of the list marker at the start of the text, which hangs out into the indent
+    pub marker: usize,
 }
 
 impl Default for Paragraph {
@@ -101,23 +545,44 @@ impl Default for Paragraph {
         Self {
             text: String::new(),
             attrs: AttrsList::new(Attrs::new().family(Family::Serif)),
+            line_starts: vec![],
+            indent: Mm(0.0),
+            marker: 0,
         }
     }
 }
 
 impl Page {
-    fn new(pdf: &mut PdfDocument) -> Self {
-        let (page, text) = pdf.add_page(PAGE_WIDTH, PAGE_HEIGHT, "text");
+    fn new(pdf: &mut PdfDocument, writing_mode: WritingMode) -> Self {
+        let (page, furniture) = pdf.add_page(PAGE_WIDTH, PAGE_HEIGHT, "text");
+        let vertical = writing_mode == WritingMode::VerticalRightToLeft;
+        let text = if vertical {
+            let pdf_page = pdf.get_page(page);
+            let text = pdf_page.add_layer("vertical text");
+            let top = Pt::from(VERTICAL_TOP).0;
+            pdf_page
+                .get_layer(text)
+                .set_ctm(CurTransMat::Raw([0.0, -1.0, 1.0, 0.0, 0.0, top]));
+            text
+        } else {
+            furniture
+        };
         Page {
             page,
             text,
+            furniture,
             y_offset: Y_MARGIN,
+            kind: PageKind::Body,
+            vertical,
         }
     }
 }
 
 impl Paragraph {
     pub fn write_body(&mut self, text: &str, attrs: Attrs) {
+        if text.is_empty() {
+            return;
+        }
         let start = self.text.len();
         self.text.push_str(text);
         let end = self.text.len();
@@ -126,11 +591,44 @@ impl Paragraph {
     }
     pub fn write_line_break(&mut self) {
         self.text.push('\n');
+        self.line_starts.push(self.text.len());
+    }
+
+    /// starts a list item with its marker, after any direction mark
+    pub fn write_marker(&mut self, marker: &str, attrs: Attrs) {
+        self.write_body(&format!("{marker}\u{2002}"), attrs);
+        self.marker = self.text.len();
+    }
+
+    /// text written from here on is `lines` markdown lines further down
+    pub fn skip_source_lines(&mut self, lines: usize) {
+        let start = self.text.len();
+        self.line_starts
+            .extend(std::iter::repeat(start).take(lines));
+    }
+
+    /// sets the base direction of the paragraph, rather than it following its first letter,
+    /// by starting it with a direction mark. Must be called before anything is written
+    pub fn set_direction(&mut self, direction: TextDirection) {
+        assert!(self.text.is_empty(), "paragraph already started");
+        if let Some(mark) = direction.mark() {
+            self.text.push(mark);
+        }
+    }
+
+    /// whether nothing but a direction mark has been written
+    fn is_empty(&self) -> bool {
+        self.text
+            .trim_start_matches(['\u{200E}', '\u{200F}'])
+            .is_empty()
     }
 }
 
 struct ShapedLines {
     lines: Vec<LayoutLine>,
+    /// how far each line is indented from `x_margin`
+    indents: Vec<Mm>,
+    text: String,
     attrs: AttrsList,
     font_size: Pt,
     x_margin: Mm,
@@ -149,21 +647,142 @@ impl Document {
         let font_size = Pt(12.0);
         let line_height = Pt(14.0);
 
-        let paragraph = std::mem::take(&mut self.paragraph);
-        if !paragraph.text.is_empty() {
-            let lines = self.shape_lines(&paragraph.text, paragraph.attrs, font_size, X_MARGIN);
-            self.write_shaped_lines(lines, line_height, Mm(0.0), false);
+        let mut paragraph = std::mem::take(&mut self.paragraph);
+        let opening = std::mem::take(&mut self.chapter_opening);
+        self.fonts.line_starts = std::mem::take(&mut paragraph.line_starts);
+        if !paragraph.is_empty() {
+            if opening {
+                self.write_opening_paragraph(paragraph, font_size, line_height);
+            } else {
+                let x_margin = X_MARGIN + paragraph.indent;
+                let first_lines = self.hanging_marker(&paragraph, font_size, x_margin);
+                let lines = self.shape_lines_around(
+                    &paragraph.text,
+                    paragraph.attrs,
+                    font_size,
+                    x_margin,
+                    &first_lines,
+                );
+                self.write_shaped_lines(lines, line_height, Mm(0.0), false);
+            }
 
             let page_layout = self.pages.last_mut().unwrap();
             page_layout.y_offset += Mm::from(line_height) * 0.5;
         }
+        self.fonts.line_starts.clear();
+    }
+
+    /// the first line of a list item, widened so that its marker hangs out into the indent
+    /// on the side the paragraph starts from, which is the right for right-to-left text
+    fn hanging_marker(
+        &mut self,
+        paragraph: &Paragraph,
+        font_size: Pt,
+        x_margin: Mm,
+    ) -> Vec<(Mm, Mm)> {
+        if paragraph.marker == 0 {
+            return vec![];
+        }
+        let marker = &paragraph.text[..paragraph.marker];
+        let marker = self.shape_lines(marker, copy_attrs(&paragraph.attrs), font_size, x_margin);
+        let marker_width = Mm::from(Dots(marker.lines[0].w));
+        let width = self.page_size().0 - x_margin * 2.0 + marker_width;
+        if self.fonts.shape(&paragraph.text, 0, &paragraph.attrs).rtl {
+            vec![(Mm(0.0), width)]
+        } else {
+            vec![(Mm(-marker_width.0), width)]
+        }
+    }
+
+    /// the first paragraph of a chapter, with a drop cap and its first line in small caps
+    /// if the book has them. The drop cap is set on the side the paragraph starts from
+    fn write_opening_paragraph(&mut self, paragraph: Paragraph, font_size: Pt, line_height: Pt) {
+        let Paragraph {
+            mut text,
+            mut attrs,
+            ..
+        } = paragraph;
+        let text_width = self.page_size().0 - X_MARGIN * 2.0;
+
+        let drop_lines = self.config.chapters.drop_cap_lines;
+        let mut first_lines = vec![];
+        if let Some(end) = drop_cap_end(&text).filter(|_| drop_lines > 0) {
+            // the capital reaches from the cap height of the first line to the baseline
+            // of the last line it drops through
+            let initial_attrs = AttrsList::new(attrs.get_span(0));
+            let initial = self.shape_lines(
+                &text[..end],
+                copy_attrs(&initial_attrs),
+                font_size,
+                X_MARGIN,
+            );
+            let cap_height = initial.lines[0].glyphs.last().map_or(0.7, |glyph| {
+                let font = self
+                    .fonts
+                    .font_system
+                    .get_font(glyph.cache_key.font_id)
+                    .unwrap();
+                let face = font.rustybuzz();
+                face.capital_height()
+                    .map_or(0.7, |height| height as f32 / face.units_per_em() as f32)
+            });
+            let drop = line_height * (drop_lines - 1) as f32;
+            let size = Pt((drop.0 + font_size.0 * cap_height) / cap_height);
+
+            // a right-to-left initial is laid out flush right, so the lines beside it
+            // end short of the right margin rather than starting in from the left
+            let initial = self.shape_lines(&text[..end], initial_attrs, size, X_MARGIN);
+            let indent = Mm::from(Dots(initial.lines[0].w)) + Mm::from(font_size) * 0.25;
+            let rtl = self.fonts.shape(&text, 0, &attrs).rtl;
+            let first_line = if rtl {
+                (Mm(0.0), text_width - indent)
+            } else {
+                (indent, text_width - indent)
+            };
+            first_lines = vec![first_line; drop_lines];
+
+            self.overflow(Mm::from(line_height) * drop_lines as f32);
+            self.write_line(
+                &initial.lines[0],
+                &initial.text,
+                &initial.attrs,
+                X_MARGIN,
+                size,
+                Pt(0.0),
+                Mm::from(drop),
+            );
+
+            text = text[end..].to_owned();
+            attrs = attrs_from(&attrs, end);
+            for start in &mut self.fonts.line_starts {
+                *start = start.saturating_sub(end);
+            }
+        }
+
+        let mut lines =
+            self.shape_lines_around(&text, copy_attrs(&attrs), font_size, X_MARGIN, &first_lines);
+        if self.config.chapters.first_line_small_caps {
+            // small caps are wider, so the first line may then end sooner. The letters
+            // pushed onto the second line go back to lowercase
+            let mut end = line_start(&lines, 1).unwrap_or(text.len());
+            for _ in 0..2 {
+                let (caps, caps_attrs) = small_caps(&text, &attrs, end);
+                lines =
+                    self.shape_lines_around(&caps, caps_attrs, font_size, X_MARGIN, &first_lines);
+                match line_start(&lines, 1) {
+                    Some(start) if start < end => end = start,
+                    _ => break,
+                }
+            }
+        }
+        self.write_shaped_lines(lines, line_height, Mm(0.0), false);
     }
 
     pub fn add_y_offset(&mut self, offset: Mm) {
         match self.pages.last_mut() {
             Some(p) => p,
             None => {
-                self.pages.push(Page::new(&mut self.pdf));
+                self.pages.push(Page::new(&mut self.pdf, self.writing_mode));
                 self.pages.last_mut().unwrap()
             }
         }
@@ -171,6 +790,7 @@ impl Document {
     }
 
     pub fn write_header(&mut self, paragraph: &str, heading: u8) {
+        self.chapter_opening = false;
         let font_size = SIZES[heading as usize - 1];
         let line_height = font_size * 1.4;
         let attrs = Attrs::new().family(Family::SansSerif).weight(Weight::BOLD);
@@ -180,12 +800,29 @@ impl Document {
         // }
 
         let attrs = AttrsList::new(attrs);
-        let lines = self.shape_lines(paragraph, attrs, font_size, X_MARGIN);
+        let text = match self.direction.mark() {
+            Some(mark) => format!("{mark}{paragraph}"),
+            None => paragraph.to_owned(),
+        };
+        let lines = self.shape_lines(&text, attrs, font_size, X_MARGIN);
         self.overflow(Mm::from(line_height) * lines.lines.len() as f32);
 
         self.write_shaped_lines(lines, line_height, Mm::from(line_height) * 0.5, false);
     }
 
+    /// width and height of the page as the text sees it. Vertical text is laid out in
+    /// lines across a page on its side, between the top and bottom margins
+    fn page_size(&self) -> (Mm, Mm) {
+        match self.writing_mode {
+            WritingMode::Horizontal => (PAGE_WIDTH, PAGE_HEIGHT),
+            WritingMode::VerticalRightToLeft => (VERTICAL_TOP - Y_MARGIN + X_MARGIN, PAGE_WIDTH),
+        }
+    }
+
+    fn bottom_rule(&self) -> Mm {
+        self.page_size().1 - Y_MARGIN
+    }
+
     fn shape_lines(
         &mut self,
         text: &str,
@@ -193,21 +830,103 @@ impl Document {
         font_size: Pt,
         x_margin: Mm,
     ) -> ShapedLines {
-        let shape = ShapeLine::new(&mut self.fonts.font_system, text, &attrs);
-        let lines = shape.layout(
-            Dots::from(font_size).0,
-            Dots::from(PAGE_WIDTH - x_margin * 2.0).0,
-            cosmic_text::Wrap::Word,
-            Some(cosmic_text::Align::Left),
-        );
+        self.shape_lines_around(text, attrs, font_size, x_margin, &[])
+    }
+
+    /// shape the text into lines `x_margin` in from each side of the page, except for the
+    /// first few, which each have their own indent from there and width, eg to wrap around
+    /// a drop cap
+    fn shape_lines_around(
+        &mut self,
+        text: &str,
+        attrs: AttrsList,
+        font_size: Pt,
+        x_margin: Mm,
+        first_lines: &[(Mm, Mm)],
+    ) -> ShapedLines {
+        let mut lines = vec![];
+        let mut indents = vec![];
+        // each of the first lines is laid out on its own, and the text after it again
+        let mut start = 0;
+        for &(indent, width) in first_lines {
+            let rest = attrs_from(&attrs, start);
+            let mut layout = self.layout_lines(&text[start..], start, &rest, font_size, width);
+            let next = layout
+                .get(1)
+                .and_then(|line| line.glyphs.iter().map(|glyph| glyph.start).min());
+            let Some(mut line) = layout.drain(..).next() else {
+                break;
+            };
+            offset_glyphs(&mut line, start);
+            lines.push(line);
+            indents.push(indent);
+            match next {
+                Some(next) => start += next,
+                None => {
+                    start = text.len();
+                    break;
+                }
+            }
+        }
+
+        if start < text.len() || lines.is_empty() {
+            let width = self.page_size().0 - x_margin * 2.0;
+            let rest = attrs_from(&attrs, start);
+            for mut line in self.layout_lines(&text[start..], start, &rest, font_size, width) {
+                offset_glyphs(&mut line, start);
+                lines.push(line);
+                indents.push(Mm(0.0));
+            }
+        }
+
         ShapedLines {
             lines,
+            indents,
+            text: text.to_owned(),
             attrs,
             font_size,
             x_margin,
         }
     }
 
+    fn layout_lines(
+        &mut self,
+        text: &str,
+        offset: usize,
+        attrs: &AttrsList,
+        font_size: Pt,
+        width: Mm,
+    ) -> Vec<LayoutLine> {
+        let shape = self.fonts.shape(text, offset, attrs);
+        // right-to-left paragraphs are set flush right
+        let align = if shape.rtl {
+            cosmic_text::Align::Right
+        } else {
+            cosmic_text::Align::Left
+        };
+        let mut lines = shape.layout(
+            Dots::from(font_size).0,
+            Dots::from(width).0,
+            cosmic_text::Wrap::Word,
+            Some(align),
+        );
+        if self.config.typography.compress_punctuation {
+            for line in &mut lines {
+                let shortened = compress_punctuation(text, line);
+                // lines set flush right or centred move over by what they lost, to stay aligned
+                let realign = match align {
+                    cosmic_text::Align::Right => shortened,
+                    cosmic_text::Align::Center => shortened / 2.0,
+                    _ => 0.0,
+                };
+                for glyph in &mut line.glyphs {
+                    glyph.x += realign;
+                }
+            }
+        }
+        lines
+    }
+
     fn write_shaped_lines(
         &mut self,
         layout: ShapedLines,
@@ -215,18 +934,19 @@ impl Document {
         y_offset: Mm,
         center: bool,
     ) {
-        for line in layout.lines {
+        for (line, indent) in layout.lines.into_iter().zip(layout.indents) {
             self.overflow(Mm(0.0));
 
             // where does the line start
             let x_offset = if center {
-                (PAGE_WIDTH - Mm::from(Dots(line.w))) * 0.5
+                (self.page_size().0 - Mm::from(Dots(line.w))) * 0.5
             } else {
-                layout.x_margin
+                layout.x_margin + indent
             };
 
             self.write_line(
                 &line,
+                &layout.text,
                 &layout.attrs,
                 x_offset,
                 layout.font_size,
@@ -237,136 +957,975 @@ impl Document {
     }
 
     fn overflow(&mut self, size: Mm) {
-        assert!(size + Y_MARGIN < BOTTOM_RULE, "block is toooooo big");
+        let bottom_rule = self.bottom_rule();
+        assert!(size + Y_MARGIN <= bottom_rule, "block is toooooo big");
 
-        match self.pages.last_mut() {
-            Some(p) => {
-                // if this will overflow our line limit, then make a new page
-                if p.y_offset + size > BOTTOM_RULE {
-                    self.pages.push(Page::new(&mut self.pdf));
-                }
-            }
-            None => {
-                self.pages.push(Page::new(&mut self.pdf));
-            }
-        };
+        if self.pages.is_empty() {
+            self.pages.push(Page::new(&mut self.pdf, self.writing_mode));
+        }
+
+        // if this will overflow our line limit, then make a new page. deferred figures
+        // go at the top of the new page, which might not leave enough room either
+        while self.pages.last().unwrap().y_offset + size > bottom_rule {
+            self.pages.push(Page::new(&mut self.pdf, self.writing_mode));
+            self.place_floats();
+        }
     }
 
     fn write_line(
         &mut self,
         line: &LayoutLine,
+        text: &str,
         attrs: &AttrsList,
         x_offset: Mm,
         font_size: Pt,
         line_height: Pt,
         y_offset: Mm,
     ) {
+        let (_, page_height) = self.page_size();
         let page_layout = self.pages.last_mut().unwrap();
+        let vertical = page_layout.vertical;
         let layer = self
             .pdf
             .get_page(page_layout.page)
             .get_layer(page_layout.text);
 
-        // start the line
+        // start the line at its first glyph, which isn't at the margin for lines set
+        // flush right
+        let indent = line.glyphs.first().map_or(0.0, |glyph| glyph.x);
         layer.begin_text_section();
-        layer.set_text_cursor(x_offset, PAGE_HEIGHT - page_layout.y_offset - y_offset);
+        layer.set_text_cursor(
+            x_offset + Mm::from(Dots(indent)),
+            page_height - page_layout.y_offset - y_offset,
+        );
 
+        // Chinese and Japanese stand upright in vertical writing, rather than being turned
+        // with the line, so a run of mixed text is split where they start and end
         let runs = GroupSliceBy {
             slice: line.glyphs.as_slice(),
-            group: |glyph| (attrs.get_span(glyph.start), glyph.cache_key.font_id),
+            group: |glyph| {
+                let upright = vertical && text[glyph.start..].starts_with(is_cjk);
+                (
+                    attrs.get_span(glyph.start),
+                    glyph.cache_key.font_id,
+                    upright,
+                )
+            },
         };
-        for ((attr, font_id), run) in runs {
-            let pdf_font = self.fonts.get_font_by_id(font_id);
-            layer.set_font(&pdf_font, font_size.0 * attr.scaling);
+        let baseline = page_height - page_layout.y_offset - y_offset;
+        // where the text cursor is, in layout units, and whether it's turned for upright glyphs
+        let mut pen = indent;
+        let mut turned = false;
+        // underlines and strikethroughs, drawn once the text is done
+        let mut rules = vec![];
+        // glyphs of color fonts, which are drawn over their invisible text
+        let mut color_glyphs = vec![];
+        for ((attr, font_id, upright), run) in runs {
+            let (first, last) = (run.first().unwrap(), run.last().unwrap());
+            let data = span_index(attr.metadata).map(|i| &self.span_data[i]);
+
+            if let Some(SpanData::Math(math)) = data {
+                // formulas can't be drawn within a text section, so pause it
+                layer.end_text_section();
+                draw_math(
+                    layer,
+                    &mut self.fonts,
+                    math,
+                    x_offset + Mm::from(Dots(first.x)),
+                    baseline,
+                );
+                layer.begin_text_section();
+                layer.set_text_cursor(x_offset + Mm::from(Dots(last.x + last.w)), baseline);
+                pen = last.x + last.w;
+                turned = false;
+                continue;
+            }
+
+            let (start, end) = (
+                x_offset + Mm::from(Dots(first.x)),
+                x_offset + Mm::from(Dots(last.x + last.w)),
+            );
+            let decoration = Decoration::from_metadata(attr.metadata);
+            let font = self.fonts.font_system.get_font(font_id).unwrap();
+            let face = font.rustybuzz();
+            let em =
+                |size: Pt, units: i16| Mm::from(size) * (units as f32 / face.units_per_em() as f32);
+
+            let mut size = font_size * attr.scaling;
+            let mut glyphs: Vec<(u16, &str)> = glyph_texts(text, run).collect();
+            let mut rise = Mm(0.0);
+            let mut substituted = false;
+            let superscript = decoration.contains(Decoration::SUPERSCRIPT);
+            if superscript || decoration.contains(Decoration::SUBSCRIPT) {
+                let feature = if superscript { b"sups" } else { b"subs" };
+                let ids: Vec<u16> = glyphs.iter().map(|&(glyph, _)| glyph).collect();
+                if let Some(script) = script_glyphs(face, feature, &ids) {
+                    // these are designed to be set at full size, already raised or lowered
+                    for ((glyph, _), substitute) in glyphs.iter_mut().zip(script) {
+                        *glyph = substitute;
+                    }
+                    size = font_size;
+                    substituted = true;
+                } else if superscript {
+                    let offset = face.superscript_metrics().map_or(330, |m| m.y_offset);
+                    rise = em(font_size, offset);
+                } else {
+                    let offset = face.subscript_metrics().map_or(150, |m| m.y_offset);
+                    rise = em(font_size, -offset);
+                }
+            }
+            let small_caps = decoration.contains(Decoration::SMALL_CAPS);
+            if small_caps {
+                // the letters were laid out as capitals, which fonts with small caps can
+                // turn into them at full size
+                let ids: Vec<u16> = glyphs.iter().map(|&(glyph, _)| glyph).collect();
+                if let Some(caps) = script_glyphs(face, b"c2sc", &ids) {
+                    for ((glyph, _), substitute) in glyphs.iter_mut().zip(caps) {
+                        *glyph = substitute;
+                    }
+                    size = font_size * (attr.scaling / SMALL_CAPS_SCALING);
+                    substituted = true;
+                }
+            }
+
+            // upright glyphs use the vertical forms of punctuation
+            let pdf_font = if upright {
+                let ids: Vec<u16> = glyphs.iter().map(|&(glyph, _)| glyph).collect();
+                for ((glyph, _), substitute) in glyphs
+                    .iter_mut()
+                    .zip(substitute_glyphs(face, b"vert", &ids))
+                {
+                    *glyph = substitute.unwrap_or(*glyph);
+                }
+                self.fonts.get_upright_font_by_id(font_id, &attr, size)
+            } else {
+                self.fonts.get_font_by_id(font_id, &attr, size)
+            };
+            // the middle of the em box, which upright glyphs are centered on
+            let middle = em(size, (face.ascender() + face.descender()) / 2);
+            layer.set_font(&pdf_font, size.0);
             layer.set_fill_color(map_cosmic_color(attr.color_opt));
-            layer.write_codepoints(run.iter().map(|x| x.cache_key.glyph_id))
+            if rise != Mm(0.0) {
+                layer.set_line_offset(Pt::from(rise).0);
+            }
+            // color glyphs are drawn once the text is done. The text is still written,
+            // invisibly, so that it can be copied and searched
+            let color = color_glyph::has_color_glyphs(face);
+            if color {
+                layer.set_text_rendering_mode(TextRenderingMode::Invisible);
+                for glyph in run {
+                    let origin = (x_offset + Mm::from(Dots(glyph.x)), baseline + rise);
+                    let glyph_id = glyph.cache_key.glyph_id;
+                    color_glyphs.push((font_id, glyph_id, origin, size, attr.color_opt));
+                }
+            }
+            // variable fonts are embedded with other widths than they were laid out with, so
+            // each glyph is moved back to where it was laid out. Upright glyphs advance by
+            // their height, and substitutes are caught up with after the run
+            let adjustments = if upright || substituted {
+                vec![]
+            } else {
+                self.fonts.instance_adjustments(font_id, &attr, size, run)
+            };
+            // glyphs moved after layout, such as compressed punctuation, aren't where the
+            // glyphs before them leave the cursor, so the text starts again at them
+            let mut piece = 0;
+            while piece < run.len() {
+                let end = (piece + 1..run.len())
+                    .find(|&i| (run[i].x - (run[i - 1].x + run[i - 1].w)).abs() > 0.01)
+                    .unwrap_or(run.len());
+                if (run[piece].x - pen).abs() > 0.01 || upright != turned {
+                    let x = x_offset + Mm::from(Dots(run[piece].x));
+                    if upright {
+                        // a quarter turn back, so that the glyphs advance along the line
+                        let (x, y) = (Pt::from(x).0, Pt::from(baseline + middle).0);
+                        layer.set_text_matrix(TextMatrix::Raw([0.0, 1.0, -1.0, 0.0, x, y]));
+                    } else {
+                        layer.end_text_section();
+                        layer.begin_text_section();
+                        layer.set_text_cursor(x, baseline);
+                    }
+                    turned = upright;
+                }
+                // small caps are read as the lowercase letters they stand for, and color
+                // glyphs as the text they were shaped from, eg a whole emoji sequence
+                let actual_text = (small_caps || color).then(|| {
+                    let start = run[piece..end].iter().map(|glyph| glyph.start).min();
+                    let stop = run[piece..end].iter().map(|glyph| glyph.end).max();
+                    actual_text(text, start.unwrap()..stop.unwrap(), small_caps)
+                });
+                if let Some(actual_text) = &actual_text {
+                    layer.begin_actual_text(actual_text);
+                }
+                let adjustments = adjustments.get(piece..end).unwrap_or_default();
+                self.fonts.write_spaced_glyphs(
+                    layer,
+                    font_id,
+                    glyphs[piece..end].iter().copied(),
+                    adjustments,
+                );
+                if actual_text.is_some() {
+                    layer.end_marked_content();
+                }
+                pen = run[end - 1].x + run[end - 1].w;
+                piece = end;
+            }
+            if color {
+                layer.set_text_rendering_mode(TextRenderingMode::Fill);
+            }
+            if rise != Mm(0.0) {
+                layer.set_line_offset(0.0);
+            }
+            if substituted {
+                // the substitutes aren't quite the width that was laid out, so
+                // carry on from where the layout expects
+                layer.end_text_section();
+                layer.begin_text_section();
+                layer.set_text_cursor(end, baseline);
+                turned = false;
+            }
+
+            // positions are of the top of the line, relative to the baseline
+            if decoration.contains(Decoration::UNDERLINE) {
+                let (position, thickness) = face
+                    .underline_metrics()
+                    .map_or((-100, 50), |m| (m.position, m.thickness));
+                let top = baseline + em(size, position);
+                rules.push((start, end, top, em(size, thickness), attr.color_opt));
+            }
+            if decoration.contains(Decoration::STRIKETHROUGH) {
+                let (position, thickness) = face
+                    .strikeout_metrics()
+                    .map_or((300, 50), |m| (m.position, m.thickness));
+                let top = baseline + rise + em(size, position);
+                rules.push((start, end, top, em(size, thickness), attr.color_opt));
+            }
+
+            if let Some(SpanData::FigureLink(_)) = data {
+                let bottom = baseline - Mm::from(font_size) * 0.25;
+                let top = baseline + Mm::from(font_size) * 0.85;
+                // annotations are placed on the page, which vertical text is turned on
+                let (lower_left, upper_right) = if vertical {
+                    (
+                        Point::new(bottom, VERTICAL_TOP - end),
+                        Point::new(top, VERTICAL_TOP - start),
+                    )
+                } else {
+                    (Point::new(start, bottom), Point::new(end, top))
+                };
+                self.links.push(PendingLink {
+                    page: page_layout.page,
+                    lower_left,
+                    upper_right,
+                    target: span_index(attr.metadata).unwrap(),
+                });
+            }
         }
         layer.end_text_section();
+
+        for (left, right, top, thickness, color) in rules {
+            let bottom = top - thickness;
+            layer.set_fill_color(map_cosmic_color(color));
+            layer.add_shape(Line {
+                points: vec![
+                    (Point::new(left, bottom), false),
+                    (Point::new(left, top), false),
+                    (Point::new(right, top), false),
+                    (Point::new(right, bottom), false),
+                ],
+                is_closed: true,
+                has_fill: true,
+                has_stroke: false,
+                is_clipping_path: false,
+            });
+        }
+
+        for (font_id, glyph_id, origin, size, color) in color_glyphs {
+            let font = self.fonts.font_system.get_font(font_id).unwrap();
+            draw_color_glyph(
+                &mut self.pdf,
+                (page_layout.page, page_layout.text),
+                font.rustybuzz(),
+                glyph_id,
+                origin,
+                size,
+                map_cosmic_color(color),
+            );
+        }
         page_layout.y_offset += line_height.into();
     }
 
-    /// write page titles and page numbers
-    pub fn write_extras(&mut self) {
-        let font_size = Pt(12.0);
-        let line_height = Pt(14.0);
-
-        let attr = Attrs::new().family(Family::Serif).weight(Weight::BOLD);
+    /// number of the next figure, eg "3.2". Only captioned figures within chapters are numbered
+    pub fn next_figure_number(&mut self, options: &ImageOptions) -> Option<String> {
+        if !options.caption || !self.numbered_figures {
+            return None;
+        }
+        self.images += 1;
+        Some(format!("{}.{}", self.chapter, self.images))
+    }
 
-        let title_shape = ShapeLine::new(
-            &mut self.fonts.font_system,
-            "Async Rust: Deep Dive",
-            &AttrsList::new(attr),
-        );
-        let title_layout = title_shape.layout(
-            Dots::from(font_size).0,
-            Dots::from(PAGE_WIDTH).0,
-            cosmic_text::Wrap::Word,
-            Some(cosmic_text::Align::Center),
-        );
-        let [title_layout] = title_layout.as_slice() else { panic!("header overflowed line") };
+    /// metadata for `Attrs`, so that text written with it links to the figure
+    pub fn figure_link(&mut self, number: String) -> usize {
+        self.span_data.push(SpanData::FigureLink(number));
+        self.span_data.len()
+    }
 
-        let font_id = {
-            let mut font_ids = title_layout.glyphs.iter().map(|a| a.cache_key.font_id);
-            match font_ids.next() {
-                Some(first) => font_ids.try_fold(first, |a, b| (a == b).then_some(a)),
-                None => None,
+    /// lay out a formula at the body text size, or warn and return None if it can't be parsed
+    fn layout_math(&mut self, formula: &str, style: MathStyle) -> Option<MathBox> {
+        let nodes = match math::parse(formula) {
+            Ok(nodes) => nodes,
+            Err(err) => {
+                log::warn!(
+                    "{}can't typeset {formula:?}, setting it as text: {err}",
+                    self.fonts.location(self.paragraph.line_starts.len())
+                );
+                return None;
             }
+        };
+        let constants =
+            MathConstants::of_family(&mut self.fonts.font_system, &self.config.math_font);
+        let math = MathLayout {
+            font_system: &mut self.fonts.font_system,
+            math_font: &self.config.math_font,
+            constants,
+            font_size: Pt(12.0),
         }
-        .expect("extras should need just a single font");
+        .layout(&nodes, style);
+        Some(math)
+    }
 
-        let pdf_font = self.fonts.get_font_by_id(font_id);
+    /// add an inline formula, eg `x^2`, to the paragraph. The paragraph holds a
+    /// non-breaking space, stretched to the width of the formula, which is swapped
+    /// for the formula when the line is written
+    pub fn write_inline_math(&mut self, formula: &str, attrs: Attrs) {
+        let lines = formula.matches('\n').count();
+        let Some(math) = self.layout_math(formula, MathStyle::Text) else {
+            // the formula's own line breaks are kept in the text
+            let text = format!("${formula}$");
+            for (i, _) in text.match_indices('\n') {
+                let start = self.paragraph.text.len() + i + 1;
+                self.paragraph.line_starts.push(start);
+            }
+            self.write_body(&text, attrs);
+            return;
+        };
+
+        let space = self.shape_lines("\u{A0}", AttrsList::new(attrs), Pt(12.0), X_MARGIN);
+        let space_width = Mm::from(Dots(space.lines[0].w));
+        let scaling = Mm::from(math.width) / space_width;
+
+        self.span_data.push(SpanData::Math(math));
+        let metadata = self.span_data.len();
+        self.write_body("\u{A0}", attrs.scaling(scaling).metadata(metadata));
+        self.paragraph.skip_source_lines(lines);
+    }
+
+    /// a formula on its own line, centered, with its `\tag{…}` on the right
+    pub fn write_display_math(&mut self, formula: &str) {
+        self.end_last_paragraph();
+        self.chapter_opening = false;
+
+        let (body, tag) = math::split_tag(formula);
+        let Some(math) = self.layout_math(&body, MathStyle::Display) else {
+            let serif = Attrs::new().family(Family::Serif);
+            self.write_body(&format!("$${formula}$$"), serif);
+            self.end_last_paragraph();
+            return;
+        };
+        let tag =
+            tag.and_then(|tag| self.layout_math(&format!("\\text{{({tag})}}"), MathStyle::Text));
+
+        let line_height = Mm::from(Pt(14.0));
+        self.overflow(Mm::from(math.height + math.depth) + line_height);
+        let (page_width, page_height) = self.page_size();
+
+        let page_layout = self.pages.last_mut().unwrap();
+        let layer = self
+            .pdf
+            .get_page(page_layout.page)
+            .get_layer(page_layout.text);
+
+        let baseline = page_layout.y_offset + line_height * 0.5 + Mm::from(math.height);
+        let x = (page_width - Mm::from(math.width)) * 0.5;
+        draw_math(layer, &mut self.fonts, &math, x, page_height - baseline);
+        if let Some(tag) = tag {
+            let x = page_width - X_MARGIN - Mm::from(tag.width);
+            draw_math(layer, &mut self.fonts, &tag, x, page_height - baseline);
+        }
+
+        page_layout.y_offset = baseline + Mm::from(math.depth) + line_height;
+    }
+
+    /// turn the links to figures into annotations, once every figure has been placed
+    pub fn write_links(&mut self) {
+        for link in std::mem::take(&mut self.links) {
+            let SpanData::FigureLink(number) = &self.span_data[link.target] else { unreachable!("links are only made for figure references") };
+            // numbering and placing figures walk the chapters separately, so a figure
+            // can be numbered but never placed if the two disagree
+            let Some(figure) = self.figures.iter().find(|f| &f.number == number) else {
+                log::warn!("figure {number} was never placed, leaving out the link to it");
+                continue;
+            };
+            let page = &self.pages[figure.page];
+            // figures on vertical pages are across the page rather than down it
+            let top = if page.vertical {
+                PAGE_HEIGHT
+            } else {
+                PAGE_HEIGHT - figure.top
+            };
+            let destination = Destination {
+                page: page.page,
+                top: Some(top.into()),
+            };
+            self.pdf.get_page(link.page).add_link(LinkAnnotation {
+                lower_left: link.lower_left,
+                upper_right: link.upper_right,
+                destination,
+            });
+        }
+    }
+
+    /// the printed page number of the page, eg "iv" or "12"
+    fn page_number(&self, index: usize) -> String {
+        let (start, label) = self
+            .numbering
+            .iter()
+            .rev()
+            .find(|(start, _)| *start <= index)
+            .map_or(
+                (0, PageLabel::new(PageLabelStyle::Decimal)),
+                |(start, label)| (*start, label.clone()),
+            );
+        label.format((index - start) as u32)
+    }
+
+    /// number of pages the list of figures takes up
+    fn list_of_figures_pages(figures: usize) -> usize {
+        let line_height = Mm::from(Pt(14.0));
+        let mut pages = 1;
+        let mut y_offset = Y_MARGIN + Mm::from(SIZES[0] * 1.4);
+        for _ in 0..figures {
+            if y_offset + line_height > BOTTOM_RULE {
+                pages += 1;
+                y_offset = Y_MARGIN;
+            }
+            y_offset += line_height;
+        }
+        pages
+    }
+
+    /// set aside pages for the list of figures, which is filled in by
+    /// `write_list_of_figures` once the page of every figure is known
+    pub fn reserve_list_of_figures(&mut self, figures: usize) {
+        self.new_section();
+        let start = self.pages.len() - 1;
+        for _ in 1..Self::list_of_figures_pages(figures) {
+            self.new_page();
+        }
+        self.list_of_figures = Some(start..self.pages.len());
+    }
+
+    /// fill in the pages set aside by `reserve_list_of_figures`, with a line
+    /// per figure linking to it
+    pub fn write_list_of_figures(&mut self) {
+        let Some(reserved) = self.list_of_figures.take() else {
+            return;
+        };
+
+        // write into the reserved pages as though they were the last pages of the book
+        let mut rest = self.pages.split_off(reserved.start + 1);
+        let after = rest.split_off(reserved.len() - 1);
+        let mut spare: Vec<Page> = rest.into_iter().rev().collect();
+
+        self.write_header("List of Figures", 1);
+
+        let font_size = Pt(12.0);
+        let line_height = Pt(14.0);
+        let attrs = Attrs::new().family(Family::Serif);
+
+        let figures = std::mem::take(&mut self.figures);
+        for figure in &figures {
+            if self.pages.last().unwrap().y_offset + Mm::from(line_height) > BOTTOM_RULE {
+                // the pages were counted before any were written, so there are
+                // only more to come if the entries took more room than counted
+                let Some(page) = spare.pop() else {
+                    log::warn!("the list of figures overflowed its pages, leaving out the rest");
+                    break;
+                };
+                self.pages.push(page);
+            }
+
+            let link = self.figure_link(figure.number.clone());
+            let attrs = attrs.metadata(link);
+
+            let page_number = self.page_number(figure.page);
+            let number = self.shape_lines(&page_number, AttrsList::new(attrs), font_size, X_MARGIN);
+            let [line] = number.lines.as_slice() else { panic!("page number overflowed line") };
+            let x_offset = PAGE_WIDTH - X_MARGIN - Mm::from(Dots(line.w));
+            self.write_line(
+                line,
+                &number.text,
+                &number.attrs,
+                x_offset,
+                font_size,
+                Pt(0.0),
+                Mm(0.0),
+            );
+
+            // titles that are too long are cut off at the end of the first line
+            let entry = format!("Figure {}  {}", figure.number, figure.title);
+            let entry = self.shape_lines(
+                &entry,
+                AttrsList::new(attrs),
+                font_size,
+                X_MARGIN + Mm(10.0),
+            );
+            self.write_line(
+                &entry.lines[0],
+                &entry.text,
+                &entry.attrs,
+                X_MARGIN,
+                font_size,
+                line_height,
+                Mm(0.0),
+            );
+        }
+        self.figures = figures;
+
+        self.pages.extend(spare.into_iter().rev());
+        self.pages.extend(after);
+    }
+
+    /// write page titles and page numbers
+    pub fn write_extras(&mut self) {
+        let font_size = Pt(12.0);
+        let line_height = Pt(14.0);
+
+        let attr = Attrs::new().family(Family::Serif).weight(Weight::BOLD);
+
+        let title_shape = self
+            .fonts
+            .shape(&self.config.title, 0, &AttrsList::new(attr));
+        let title_layout = title_shape.layout(
+            Dots::from(font_size).0,
+            Dots::from(PAGE_WIDTH).0,
+            cosmic_text::Wrap::Word,
+            Some(cosmic_text::Align::Center),
+        );
+        let [title_layout] = title_layout.as_slice() else { panic!("header overflowed line") };
+
+        let font_id = {
+            let mut font_ids = title_layout.glyphs.iter().map(|a| a.cache_key.font_id);
+            match font_ids.next() {
+                Some(first) => font_ids.try_fold(first, |a, b| (a == b).then_some(a)),
+                None => None,
+            }
+        }
+        .expect("extras should need just a single font");
+
+        let pdf_font = self.fonts.get_font_by_id(font_id, &attr, font_size);
+
+        let cover = self.cover_pages();
+        let default_label = PageLabel::new(PageLabelStyle::Decimal);
+        let mut numbering = self.numbering.iter().peekable();
+        let (mut range_start, mut label) = (0, &default_label);
 
         for (i, page_layout) in self.pages.iter_mut().enumerate() {
-            let number = format!("{}", i + 1);
-            let number_shape =
-                ShapeLine::new(&mut self.fonts.font_system, &number, &AttrsList::new(attr));
+            if let Some((start, next_label)) = numbering.next_if(|(start, _)| *start <= i) {
+                range_start = *start;
+                label = next_label;
+            }
+            if i == range_start {
+                self.pdf.add_page_label(label.clone(), page_layout.page);
+            }
+            if matches!(
+                page_layout.kind,
+                PageKind::Blank | PageKind::Display | PageKind::Cover
+            ) {
+                continue;
+            }
+
+            let number = label.format((i - range_start) as u32);
+            let number_shape = self.fonts.shape(&number, 0, &AttrsList::new(attr));
             let number_layout = number_shape
                 .layout(
                     Dots::from(font_size).0,
                     Dots::from(PAGE_WIDTH).0,
                     cosmic_text::Wrap::Word,
-                    Some(cosmic_text::Align::Center),
+                    Some(cosmic_text::Align::Left),
                 )
                 .remove(0);
 
+            let number_width = Mm::from(Dots(number_layout.w));
+            // the first page of the interior is a recto (right hand) page
+            let number_x = match self.config.footer {
+                FooterLayout::Centered => (PAGE_WIDTH - number_width) * 0.5,
+                FooterLayout::Outer if (i - cover) % 2 == 0 => PAGE_WIDTH - X_MARGIN - number_width,
+                FooterLayout::Outer => X_MARGIN,
+            };
+
             let current_layer = self
                 .pdf
                 .get_page(page_layout.page)
-                .get_layer(page_layout.text);
+                .get_layer(page_layout.furniture);
 
             current_layer.set_font(&pdf_font, font_size.0);
             current_layer.set_line_height(line_height.0);
 
-            current_layer.begin_text_section();
-            current_layer.set_fill_color(map_cosmic_color(attr.color_opt));
-            let x = Mm::from(Dots(title_layout.glyphs.first().unwrap().x));
-            current_layer.set_text_cursor(x, PAGE_HEIGHT - Mm(5.0) - Mm::from(line_height)); // 5mm from the top
-            current_layer
-                .write_codepoints(title_layout.glyphs.iter().map(|x| x.cache_key.glyph_id));
-            current_layer.end_text_section();
+            if page_layout.kind == PageKind::Body {
+                current_layer.begin_text_section();
+                current_layer.set_fill_color(map_cosmic_color(attr.color_opt));
+                let x = Mm::from(Dots(title_layout.glyphs.first().unwrap().x));
+                current_layer.set_text_cursor(x, PAGE_HEIGHT - Mm(5.0) - Mm::from(line_height)); // 5mm from the top
+                self.fonts.write_glyphs(
+                    current_layer,
+                    font_id,
+                    glyph_texts(&self.config.title, &title_layout.glyphs),
+                );
+                current_layer.end_text_section();
+            }
 
             current_layer.begin_text_section();
-            let x = Mm::from(Dots(number_layout.glyphs.first().unwrap().x));
             current_layer.set_fill_color(map_cosmic_color(attr.color_opt));
-            current_layer.set_text_cursor(x, Mm(12.0) - Mm::from(line_height));
-            current_layer
-                .write_codepoints(number_layout.glyphs.iter().map(|x| x.cache_key.glyph_id));
+            current_layer.set_text_cursor(number_x, Mm(12.0) - Mm::from(line_height));
+            self.fonts.write_glyphs(
+                current_layer,
+                font_id,
+                glyph_texts(&number, &number_layout.glyphs),
+            );
             current_layer.end_text_section();
         }
     }
 
+    /// start a new page number range on the next page
+    pub fn start_numbering(&mut self, label: PageLabel) {
+        let start = self.pages.len();
+        self.numbering.retain(|&(s, _)| s != start);
+        self.numbering.push((start, label));
+    }
+
     /// prepare new page, if necessary
     pub fn new_page(&mut self) {
         // todo: check for pre-created pages. for now it's not possible
-        let (page, text) = self.pdf.add_page(PAGE_WIDTH, PAGE_HEIGHT, "text");
-        self.pages.push(Page {
+        self.pages.push(Page::new(&mut self.pdf, self.writing_mode));
+    }
+
+    /// number of pages taken by the ebook cover, which come before the printed interior
+    fn cover_pages(&self) -> usize {
+        self.pages
+            .iter()
+            .take_while(|page| page.kind == PageKind::Cover)
+            .count()
+    }
+
+    /// prepare a new page, inserting a blank verso page first if
+    /// sections should start on a recto page
+    fn new_recto_page(&mut self) {
+        // even indices into the interior are recto pages
+        let index = self.pages.len() - self.cover_pages();
+        if self.config.chapters.recto_start && index % 2 == 1 {
+            self.new_page();
+            self.pages.last_mut().unwrap().kind = PageKind::Blank;
+        }
+        self.new_page();
+    }
+
+    /// write a line of text centered on the page
+    fn write_centered(&mut self, text: &str, attrs: Attrs, font_size: Pt) {
+        let lines = self.shape_lines(text, AttrsList::new(attrs), font_size, X_MARGIN);
+        self.write_shaped_lines(lines, font_size * 1.4, Mm(0.0), true);
+    }
+
+    /// full-bleed cover as the first page of the ebook. It isn't part of the printed
+    /// interior, so it doesn't count towards which pages are rectos
+    pub fn write_cover(&mut self, art: Art) {
+        self.end_last_paragraph();
+        self.new_page();
+        let page_layout = self.pages.last_mut().unwrap();
+        page_layout.kind = PageKind::Cover;
+
+        add_art(
+            &mut self.pdf,
+            page_layout.page,
+            page_layout.text,
+            art,
+            (Mm(0.0), Mm(0.0)),
+            (PAGE_WIDTH, PAGE_HEIGHT),
+        );
+    }
+
+    /// print-on-demand cover, with the back cover, spine and front cover side by side
+    /// on one sheet and a bleed around the outside. The spine width is computed from
+    /// the number of interior pages, so this must be called once the book is laid out.
+    ///
+    /// Returns the cover document and the fonts it uses
+    pub fn print_cover(
+        &mut self,
+        front: Art,
+        back: Option<Art>,
+    ) -> (PdfDocument, HashSet<FontInstance>) {
+        let cover = &self.config.print_cover;
+        let bleed = cover.bleed;
+
+        // each sheet of paper holds two pages
+        let interior_pages = self.pages.len() - self.cover_pages();
+        let sheets = (interior_pages + 1) / 2;
+        let spine = cover.paper_thickness * sheets as f32;
+
+        let width = bleed * 2.0 + PAGE_WIDTH * 2.0 + spine;
+        let height = bleed * 2.0 + PAGE_HEIGHT;
+
+        let mut pdf = PdfDocument::empty(self.config.title.clone());
+        let (page, layer) = pdf.add_page(width, height, "cover");
+        let mut fonts = HashSet::new();
+
+        if let Some(back) = back {
+            add_art(
+                &mut pdf,
+                page,
+                layer,
+                back,
+                (Mm(0.0), Mm(0.0)),
+                (bleed + PAGE_WIDTH, height),
+            );
+        }
+        add_art(
+            &mut pdf,
             page,
-            text,
-            y_offset: Y_MARGIN,
+            layer,
+            front,
+            (bleed + PAGE_WIDTH + spine, Mm(0.0)),
+            (PAGE_WIDTH + bleed, height),
+        );
+
+        // spine text, reading top to bottom. skipped if the spine is too thin to hold it
+        let mut font_size = Pt((Pt::from(spine).0 * 0.6).min(10.0));
+        if spine >= Mm(6.0) {
+            let text = if self.config.authors.is_empty() {
+                self.config.title.clone()
+            } else {
+                format!("{} — {}", self.config.title, self.config.authors.join(", "))
+            };
+            let attrs = Attrs::new().family(Family::SansSerif).weight(Weight::BOLD);
+            let attrs = AttrsList::new(attrs);
+            let shape = self.fonts.shape(&text, 0, &attrs);
+            let length = Dots::from(PAGE_HEIGHT - Y_MARGIN * 2.0).0;
+            let layout = |font_size: Pt| {
+                let lines = shape.layout(
+                    Dots::from(font_size).0,
+                    length,
+                    cosmic_text::Wrap::None,
+                    Some(cosmic_text::Align::Left),
+                );
+                lines.into_iter().next()
+            };
+            // text too long for the spine is set smaller to fit, with a little room to spare
+            let mut line = layout(font_size);
+            if let Some(overflow) = line
+                .as_ref()
+                .map(|line| line.w / length)
+                .filter(|&r| r > 1.0)
+            {
+                font_size = font_size * (0.98 / overflow);
+                line = layout(font_size);
+            }
+            let line = line.filter(|_| font_size >= MIN_SPINE_SIZE);
+            let Some(line) = line else {
+                log::warn!("the spine is too short for {text:?}, leaving it blank");
+                return (pdf, fonts);
+            };
+
+            let x = bleed + PAGE_WIDTH + spine * 0.5 - Mm::from(font_size) * 0.35;
+            let y = (height + Mm::from(Dots(line.w))) * 0.5;
+
+            let current_layer = pdf.get_page(page).get_layer(layer);
+            current_layer.begin_text_section();
+            current_layer.set_text_matrix(TextMatrix::TranslateRotate(x.into(), y.into(), 270.0));
+            let runs = GroupSliceBy {
+                slice: line.glyphs.as_slice(),
+                group: |glyph| (attrs.get_span(glyph.start), glyph.cache_key.font_id),
+            };
+            for ((attr, font_id), run) in runs {
+                let instance = self
+                    .fonts
+                    .instance(font_id, &attr, font_size * attr.scaling);
+                let pdf_font = self.fonts.font_ref(&instance);
+                fonts.insert(instance);
+                current_layer.set_font(&pdf_font, font_size.0 * attr.scaling);
+                current_layer.set_fill_color(map_cosmic_color(attr.color_opt));
+                self.fonts
+                    .write_glyphs(current_layer, font_id, glyph_texts(&text, run));
+            }
+            current_layer.end_text_section();
+        }
+
+        (pdf, fonts)
+    }
+
+    /// title page with the title, subtitle and authors, and the publisher at the bottom
+    pub fn write_title_page(&mut self) {
+        self.end_last_paragraph();
+        self.new_recto_page();
+        let page_layout = self.pages.last_mut().unwrap();
+        page_layout.kind = PageKind::Display;
+        page_layout.y_offset = PAGE_HEIGHT * 0.3;
+
+        let title = self.config.title.clone();
+        let bold = Attrs::new().family(Family::SansSerif).weight(Weight::BOLD);
+        self.write_centered(&title, bold, SIZES[0]);
+
+        if let Some(subtitle) = self.config.subtitle.clone() {
+            self.write_centered(&subtitle, Attrs::new().family(Family::SansSerif), SIZES[2]);
+        }
+
+        if !self.config.authors.is_empty() {
+            self.add_y_offset(Mm::from(SIZES[2]));
+            let authors = self.config.authors.join(", ");
+            self.write_centered(&authors, Attrs::new().family(Family::Serif), SIZES[3]);
+        }
+
+        if let Some(publisher) = self.config.publisher.clone() {
+            let line_height = SIZES[4] * 1.4;
+            self.pages.last_mut().unwrap().y_offset = BOTTOM_RULE - Mm::from(line_height);
+            self.write_centered(&publisher, Attrs::new().family(Family::Serif), SIZES[4]);
+        }
+    }
+
+    /// copyright page, on the verso of the title page, with the notice at the bottom
+    pub fn write_copyright_page(&mut self) {
+        let Some(copyright) = self.config.copyright.clone() else {
+            return;
+        };
+
+        self.end_last_paragraph();
+        self.new_page();
+        self.pages.last_mut().unwrap().kind = PageKind::Display;
+
+        let font_size = Pt(10.0);
+        let line_height = Pt(12.0);
+        let attrs = Attrs::new().family(Family::Serif);
+
+        let mut lines = vec![];
+        let holder = self.config.authors.join(", ");
+        lines.push(match copyright.year {
+            Some(year) => format!("Copyright © {year} {holder}"),
+            None => format!("Copyright © {holder}"),
         });
+        if let Some(publisher) = &self.config.publisher {
+            lines.push(format!("Published by {publisher}"));
+        }
+        if let Some(edition) = &copyright.edition {
+            lines.push(edition.clone());
+        }
+        if let Some(isbn) = &copyright.isbn {
+            lines.push(format!("ISBN {isbn}"));
+        }
+        if let Some(license) = &copyright.license {
+            lines.push(String::new());
+            lines.push(license.clone());
+        }
+
+        let shaped = self.shape_lines(
+            &lines.join("\n"),
+            AttrsList::new(attrs),
+            font_size,
+            X_MARGIN * 2.0,
+        );
+        // a notice taller than the page starts at the top and runs on to the next one
+        let height = Mm::from(line_height) * shaped.lines.len() as f32;
+        self.pages.last_mut().unwrap().y_offset = Mm((BOTTOM_RULE - height).0.max(Y_MARGIN.0));
+        self.write_shaped_lines(shaped, line_height, Mm(0.0), false);
+    }
+
+    /// dedication, centered in italics a third of the way down a recto page
+    pub fn write_dedication(&mut self) {
+        let Some(dedication) = self.config.dedication.clone() else {
+            return;
+        };
+
+        self.end_last_paragraph();
+        self.new_recto_page();
+        let page_layout = self.pages.last_mut().unwrap();
+        page_layout.kind = PageKind::Display;
+        page_layout.y_offset = PAGE_HEIGHT * 0.3;
+
+        let attrs = Attrs::new().family(Family::Serif).style(Style::Italic);
+        self.write_centered(&dedication, attrs, Pt(12.0));
+    }
+
+    /// start an unnumbered front or back matter section, such as a preface or
+    /// an "About the author" page, on a fresh page
+    pub fn new_section(&mut self) {
+        self.end_last_paragraph();
+        self.flush_floats();
+        self.numbered_figures = false;
+        self.writing_mode = self.config.writing_mode;
+        self.new_recto_page();
+        self.pages.last_mut().unwrap().kind = PageKind::Opener;
+    }
+
+    /// start a new chapter on a fresh page, with the label, title, epigraph and image
+    /// set lower down the page. The chapter content continues below
+    pub fn write_chapter_opener(&mut self, opener: ChapterOpener) {
+        let ChapterOpener {
+            title,
+            writing_mode,
+            epigraph,
+            image,
+        } = opener;
+
+        self.end_last_paragraph();
+        self.flush_floats();
+        self.chapter += 1;
+        self.images = 0;
+        self.numbered_figures = true;
+        self.writing_mode = writing_mode.unwrap_or(self.config.writing_mode);
+
+        self.new_recto_page();
+        let page_layout = self.pages.last_mut().unwrap();
+        page_layout.kind = PageKind::Opener;
+        page_layout.y_offset = self.config.chapters.title_drop;
+
+        if let Some(label) = &self.config.chapters.label {
+            let label = format!("{label} {}", self.chapter);
+            let font_size = SIZES[3];
+            let attrs = AttrsList::new(Attrs::new().family(Family::SansSerif));
+            let lines = self.shape_lines(&label, attrs, font_size, X_MARGIN);
+            self.write_shaped_lines(lines, font_size * 1.4, Mm(0.0), false);
+        }
+
+        if let Some(title) = title {
+            self.write_header(&title, 1);
+        }
+        self.add_y_offset(Mm::from(SIZES[0]));
+
+        if let Some(epigraph) = epigraph {
+            let font_size = Pt(12.0);
+            let line_height = Pt(14.0);
+            let lines =
+                self.shape_lines(&epigraph.text, epigraph.attrs, font_size, PAGE_WIDTH * 0.2);
+            self.write_shaped_lines(lines, line_height, Mm(0.0), false);
+            self.add_y_offset(Mm::from(line_height) * 2.0);
+        }
+
+        if let Some((art, options)) = image {
+            self.add_image(Figure {
+                art,
+                caption: Paragraph::default(),
+                title: String::new(),
+                options: ImageOptions {
+                    caption: false,
+                    placement: Placement::Here,
+                    ..options
+                },
+                number: None,
+            });
+        }
+        self.chapter_opening = true;
     }
 
     pub fn write_code(&mut self, lang: &str, text: &str, font_size: Pt, line_height: Pt) {
+        self.chapter_opening = false;
         let theme = self.theme.themes["base16-ocean.dark"].clone();
         let highlighter = Highlighter::new(&theme);
         let mut highlight_state = HighlightState::new(&highlighter, ScopeStack::new());
@@ -386,6 +1945,7 @@ impl Document {
 
         let default_attrs = Attrs::new().family(Family::Monospace).color(fg);
 
+        let (page_width, page_height) = self.page_size();
         {
             self.overflow(Mm::from(line_height) * text.lines().count() as f32);
             let page_layout = self.pages.last_mut().unwrap();
@@ -405,14 +1965,14 @@ impl Document {
             current_layer.set_fill_color(bg);
             current_layer.add_shape(Line {
                 points: vec![
-                    (Point::new(X_MARGIN * 1.5, PAGE_HEIGHT - bottom), false),
-                    (Point::new(X_MARGIN * 1.5, PAGE_HEIGHT - top), false),
+                    (Point::new(X_MARGIN * 1.5, page_height - bottom), false),
+                    (Point::new(X_MARGIN * 1.5, page_height - top), false),
                     (
-                        Point::new(PAGE_WIDTH - X_MARGIN * 1.5, PAGE_HEIGHT - top),
+                        Point::new(page_width - X_MARGIN * 1.5, page_height - top),
                         false,
                     ),
                     (
-                        Point::new(PAGE_WIDTH - X_MARGIN * 1.5, PAGE_HEIGHT - bottom),
+                        Point::new(page_width - X_MARGIN * 1.5, page_height - bottom),
                         false,
                     ),
                 ],
@@ -425,7 +1985,10 @@ impl Document {
             page_layout.y_offset += Mm::from(line_height);
         }
 
-        for line in text.lines() {
+        // each line of code is shaped on its own, so warnings find their line from where
+        // it starts in the block
+        self.fonts.line_starts = text.match_indices('\n').map(|(i, _)| i + 1).collect();
+        for (n, line) in text.lines().enumerate() {
             let mut attrs = AttrsList::new(default_attrs);
 
             if let Some(state) = parse_state.as_mut() {
@@ -438,16 +2001,18 @@ impl Document {
                 }
             }
 
-            let shape = ShapeLine::new(&mut self.fonts.font_system, line, &attrs);
-            let line = shape.layout(
+            let offset = n.checked_sub(1).map_or(0, |n| self.fonts.line_starts[n]);
+            let shape = self.fonts.shape(line, offset, &attrs);
+            let layout = shape.layout(
                 Dots::from(font_size).0,
-                Dots::from(PAGE_WIDTH).0,
+                Dots::from(page_width).0,
                 cosmic_text::Wrap::Word,
-                Some(cosmic_text::Align::Center),
+                Some(cosmic_text::Align::Left),
             );
-            let [line] = line.as_slice() else { panic!("codeblock line overflowed") };
+            let [layout] = layout.as_slice() else { panic!("codeblock line overflowed") };
 
             self.write_line(
+                layout,
                 line,
                 &attrs,
                 X_MARGIN * 2.0,
@@ -456,58 +2021,507 @@ impl Document {
                 Mm(0.0),
             );
         }
+        self.fonts.line_starts.clear();
 
         self.add_y_offset(Mm::from(line_height) * 1.5);
     }
 
-    pub fn add_image(&mut self, title: Paragraph, image: &DynamicImage) {
-        self.images += 1;
+    /// lay out a ```` ```dot ```` or ```` ```sequence ```` code block as an uncaptioned figure,
+    /// or as a code block with a warning if it can't be read
+    pub fn write_diagram(&mut self, kind: &str, source: &str) {
+        // labels are measured with the same fallback fonts as the text around them
+        let mut measure = |text: &str| {
+            let attrs = AttrsList::new(Attrs::new().family(Family::SansSerif));
+            let shape = self.fonts.shape(text, 0, &attrs);
+            let lines = shape.layout(
+                diagram::FONT_SIZE,
+                f32::INFINITY,
+                cosmic_text::Wrap::None,
+                Some(cosmic_text::Align::Left),
+            );
+            lines.first().map_or(0.0, |line| line.w)
+        };
+        let svg = match kind {
+            "dot" => diagram::dot_to_svg(source, &mut measure),
+            "sequence" => diagram::sequence_to_svg(source, &mut measure),
+            _ => panic!("unknown diagram kind {kind:?}"),
+        };
+        self.end_last_paragraph();
+        let svg = match svg {
+            Ok(svg) => svg,
+            Err(err) => {
+                log::warn!(
+                    "{}can't lay out the {kind} diagram, setting it as code: {err}",
+                    self.fonts.location(0)
+                );
+                self.write_code(kind, source, Pt(10.0), Pt(12.0));
+                return;
+            }
+        };
+        let svg = Svg::parse_with_options(&svg, &self.svg_options).unwrap();
+
+        self.add_image(Figure {
+            art: Art::Svg(svg),
+            caption: Paragraph::default(),
+            title: String::new(),
+            options: ImageOptions {
+                caption: false,
+                ..ImageOptions::default()
+            },
+            number: None,
+        });
+    }
+
+    /// lay out a figure. Unless it is placed `here`, a figure that doesn't fit on the
+    /// current page is deferred to the top of the next page, and the text that follows
+    /// fills the rest of this one
+    pub fn add_image(&mut self, figure: Figure) {
+        self.chapter_opening = false;
+        let figure = self.layout_figure(figure);
+        let bottom_rule = self.bottom_rule();
+        let fits = self
+            .pages
+            .last()
+            .map_or(true, |p| p.y_offset + figure.total_height() <= bottom_rule);
+        // figures are kept in order, so nothing overtakes a deferred figure
+        let deferred = !self.floats.is_empty();
+
+        match figure.placement {
+            Placement::Here => self.place_figure(figure),
+            Placement::Float if fits && !deferred => self.place_figure(figure),
+            Placement::Top if self.at_page_top() && !deferred => self.place_figure(figure),
+            _ => self.floats.push_back(figure),
+        }
+    }
+
+    /// whether nothing has been written to the current page yet
+    fn at_page_top(&self) -> bool {
+        self.pages.last().map_or(true, |p| p.y_offset <= Y_MARGIN)
+    }
+
+    /// place deferred figures at the top of a fresh page, in order, until one doesn't fit.
+    /// The first always goes on the page, as it can't fit any better on the next
+    fn place_floats(&mut self) {
+        while let Some(figure) = self.floats.pop_front() {
+            let page_layout = self.pages.last().unwrap();
+            if figure.placement == Placement::Page {
+                // page figures get a page to themselves
+                if page_layout.y_offset > Y_MARGIN {
+                    self.pages.push(Page::new(&mut self.pdf, self.writing_mode));
+                }
+                self.place_figure(figure);
+                // the page is full, so whatever comes next starts a new one, and nothing
+                // at the end of a section or the book leaves an empty page
+                let bottom = self.bottom_rule();
+                self.pages.last_mut().unwrap().y_offset = bottom;
+                continue;
+            }
+            let fits = page_layout.y_offset + figure.total_height() <= self.bottom_rule();
+            if !fits && !self.at_page_top() {
+                self.floats.push_front(figure);
+                break;
+            }
+            self.place_figure(figure);
+        }
+    }
+
+    /// place any deferred figures before a new section starts
+    pub fn flush_floats(&mut self) {
+        while !self.floats.is_empty() {
+            self.pages.push(Page::new(&mut self.pdf, self.writing_mode));
+            self.place_floats();
+        }
+    }
 
-        let width = image.width();
-        let height = image.height();
+    /// size the figure and shape its caption
+    fn layout_figure(&mut self, figure: Figure) -> FloatingFigure {
+        let Figure {
+            art,
+            caption,
+            title,
+            options,
+            number,
+        } = figure;
+
+        let (width, height) = match &art {
+            Art::Image(image) => (image.image.width.0 as u32, image.image.height.0 as u32),
+            Art::Svg(svg) => (svg.width.0 as u32, svg.height.0 as u32),
+        };
 
-        let max_width: Mm = PAGE_WIDTH * 0.75;
-        let max_height: Mm = PAGE_HEIGHT * 0.75;
+        let (page_width, page_height) = self.page_size();
+        let text_width = page_width - X_MARGIN * 2.0;
 
-        let render_width;
-        let render_height;
-        if max_width * height as f32 / width as f32 > max_height {
-            render_height = max_height;
-            render_width = max_height * width as f32 / height as f32
+        let caption = options
+            .caption
+            .then(|| self.shape_lines(&caption.text, caption.attrs, Pt(12.0), page_width * 0.125));
+        // no taller than 75% of the page, and short enough to fit on a page with its caption
+        let page_room = self.bottom_rule() - Y_MARGIN - caption_height(caption.as_ref());
+        let max_height = if page_room < page_height * 0.75 {
+            Mm(page_room.0.max(page_height.0 * 0.25))
         } else {
-            render_height = max_width * height as f32 / width as f32;
-            render_width = max_width;
+            page_height * 0.75
+        };
+
+        // by default, images are shown at their natural size, but no wider than 75% of the page
+        let natural_width = Mm(width as f32 / options.dpi.unwrap_or(DOTS_PER_INCH) * MM_PER_INCH);
+        let mut render_width = match options.width {
+            Some(ImageWidth::Relative(fraction)) => text_width * fraction,
+            Some(ImageWidth::Absolute(width)) => width,
+            None if natural_width > page_width * 0.75 => page_width * 0.75,
+            None => natural_width,
+        };
+        if render_width > text_width {
+            render_width = text_width;
+        }
+        if render_width * height as f32 / width as f32 > max_height {
+            render_width = max_height * width as f32 / height as f32;
         }
+        let render_height = render_width * height as f32 / width as f32;
+
+        FloatingFigure {
+            art,
+            caption,
+            title,
+            number,
+            align: options.align,
+            placement: options.placement,
+            pixel_width: width as f32,
+            width: render_width,
+            height: render_height,
+        }
+    }
 
-        let font_size = Pt(12.0);
+    /// draw the figure and its caption at the current position
+    fn place_figure(&mut self, figure: FloatingFigure) {
         let line_height = Pt(14.0);
+        // only a figure with a very long caption is taller than a page, and then the
+        // caption runs on to the next
+        let page_room = self.bottom_rule() - Y_MARGIN;
+        self.overflow(Mm(figure.total_height().0.min(page_room.0)));
+
+        let FloatingFigure {
+            art,
+            caption,
+            title,
+            number,
+            align,
+            pixel_width,
+            width: render_width,
+            height: render_height,
+            ..
+        } = figure;
+
+        if let Some(number) = number {
+            self.figures.push(FigureEntry {
+                number,
+                title,
+                page: self.pages.len() - 1,
+                top: self.pages.last().unwrap().y_offset,
+            });
+        }
 
-        let caption_lines =
-            self.shape_lines(&title.text, title.attrs, font_size, PAGE_WIDTH * 0.125);
+        let (page_width, page_height) = self.page_size();
+        let page_layout = self.pages.last_mut().unwrap();
 
-        self.overflow(render_height + Mm::from(line_height) * caption_lines.lines.len() as f32);
+        let translate_x = match align {
+            ImageAlign::Left => X_MARGIN,
+            ImageAlign::Center => (page_width - render_width) * 0.5,
+            ImageAlign::Right => page_width - X_MARGIN - render_width,
+        };
+        let translate_y = page_height - page_layout.y_offset - render_height;
+        let dpi = (Dots(pixel_width) / render_width).0;
+        page_layout.y_offset += render_height + Mm::from(line_height);
+
+        match art {
+            Art::Image(image) => image.add_to_document(
+                &mut self.pdf,
+                page_layout.page,
+                page_layout.text,
+                ImageTransform {
+                    translate_x: Some(translate_x),
+                    translate_y: Some(translate_y),
+                    rotate: None,
+                    scale_x: None,
+                    scale_y: None,
+                    dpi: Some(dpi),
+                },
+            ),
+            Art::Svg(mut svg) => {
+                let texts = std::mem::take(&mut svg.texts);
+                svg.add_to_document(
+                    &mut self.pdf,
+                    page_layout.page,
+                    page_layout.text,
+                    SvgTransform {
+                        translate_x: Some(translate_x.into()),
+                        translate_y: Some(translate_y.into()),
+                        dpi: Some(dpi),
+                        ..Default::default()
+                    },
+                );
+
+                // svg text is drawn as paths, so overlay the same text invisibly to keep it selectable
+                let mm_per_px = render_width.0 / pixel_width;
+                for text in texts {
+                    self.write_invisible_text(
+                        &text,
+                        translate_x + Mm(text.x * mm_per_px),
+                        translate_y + render_height - Mm(text.y * mm_per_px),
+                        Mm(text.font_size * mm_per_px).into(),
+                    );
+                }
+            }
+        }
+
+        if let Some(caption) = caption {
+            self.write_shaped_lines(caption, line_height, Mm(0.0), true);
+        }
+        self.add_y_offset(Mm::from(line_height));
+    }
+}
+
+impl Document {
+    /// write a single line of SVG text with the invisible rendering mode, with its baseline
+    /// at (x, y). It is set in the SVG's font if that is installed, so that it runs as far
+    /// as the outlines drawn for it, or otherwise in the sans serif font
+    fn write_invisible_text(&mut self, svg_text: &SvgText, x: Mm, y: Mm, font_size: Pt) {
+        let text = svg_text.text.as_str();
+        let weight = if svg_text.bold {
+            Weight::BOLD
+        } else {
+            Weight::NORMAL
+        };
+        let family = svg_text
+            .font_family
+            .as_deref()
+            .map(Family::Name)
+            .filter(|&family| {
+                let query = fontdb::Query {
+                    families: &[family],
+                    weight,
+                    stretch: fontdb::Stretch::Normal,
+                    style: Style::Normal,
+                };
+                self.fonts.font_system.db().query(&query).is_some()
+            })
+            .unwrap_or(Family::SansSerif);
+        let attrs = Attrs::new().family(family).weight(weight);
+        let shape = self.fonts.shape(text, 0, &AttrsList::new(attrs));
+        let lines = shape.layout(
+            Dots::from(font_size).0,
+            f32::INFINITY,
+            cosmic_text::Wrap::None,
+            Some(cosmic_text::Align::Left),
+        );
+        let Some(line) = lines.first() else { return };
+
+        let width = Mm::from(Dots(line.w));
+        let x = match svg_text.anchor {
+            SvgTextAnchor::Start => x,
+            SvgTextAnchor::Middle => x - width * 0.5,
+            SvgTextAnchor::End => x - width,
+        };
 
         let page_layout = self.pages.last_mut().unwrap();
-        let current_page = self.pdf.get_page(page_layout.page);
+        let layer = self
+            .pdf
+            .get_page(page_layout.page)
+            .get_layer(page_layout.text);
 
-        let image = crate::printpdf::Image::from_dynamic_image(image);
-        image.add_to_layer(
-            current_page,
-            page_layout.text,
+        layer.begin_text_section();
+        layer.set_text_rendering_mode(TextRenderingMode::Invisible);
+        layer.set_text_cursor(x, y);
+
+        let runs = GroupSliceBy {
+            slice: line.glyphs.as_slice(),
+            group: |glyph| glyph.cache_key.font_id,
+        };
+        for (font_id, run) in runs {
+            let pdf_font = self.fonts.get_font_by_id(font_id, &attrs, font_size);
+            layer.set_font(&pdf_font, font_size.0);
+            self.fonts
+                .write_glyphs(layer, font_id, glyph_texts(text, run));
+        }
+        layer.set_text_rendering_mode(TextRenderingMode::Fill);
+        layer.end_text_section();
+    }
+}
+
+/// draw a laid out formula, with its baseline starting at (x, y)
+fn draw_math(layer: &mut PdfLayer, fonts: &mut Fonts, math: &MathBox, x: Mm, y: Mm) {
+    let black = map_cosmic_color(None);
+    for item in &math.items {
+        match item {
+            MathItem::Glyphs {
+                line,
+                text,
+                attrs,
+                font_size,
+                x: item_x,
+                y: item_y,
+            } => {
+                let Some(first) = line.glyphs.first() else {
+                    continue;
+                };
+                layer.begin_text_section();
+                layer.set_text_cursor(x + Mm::from(*item_x + Pt(first.x)), y + Mm::from(*item_y));
+                let runs = GroupSliceBy {
+                    slice: line.glyphs.as_slice(),
+                    group: |glyph| (attrs.get_span(glyph.start), glyph.cache_key.font_id),
+                };
+                for ((attr, font_id), run) in runs {
+                    let pdf_font = fonts.get_font_by_id(font_id, &attr, *font_size * attr.scaling);
+                    layer.set_font(&pdf_font, font_size.0 * attr.scaling);
+                    layer.set_fill_color(black.clone());
+                    fonts.write_glyphs(layer, font_id, glyph_texts(text, run));
+                }
+                layer.end_text_section();
+            }
+            MathItem::Rule {
+                x: rule_x,
+                y: rule_y,
+                width,
+                height,
+            } => {
+                let (left, bottom) = (x + Mm::from(*rule_x), y + Mm::from(*rule_y));
+                let (right, top) = (left + Mm::from(*width), bottom + Mm::from(*height));
+                layer.set_fill_color(black.clone());
+                layer.add_shape(Line {
+                    points: vec![
+                        (Point::new(left, bottom), false),
+                        (Point::new(left, top), false),
+                        (Point::new(right, top), false),
+                        (Point::new(right, bottom), false),
+                    ],
+                    is_closed: true,
+                    has_fill: true,
+                    has_stroke: false,
+                    is_clipping_path: false,
+                });
+            }
+            MathItem::Path { points, thickness } => {
+                layer.set_outline_color(black.clone());
+                layer.set_outline_thickness(thickness.0);
+                layer.add_shape(Line {
+                    points: points
+                        .iter()
+                        .map(|&(px, py)| (Point::new(x + Mm::from(px), y + Mm::from(py)), false))
+                        .collect(),
+                    is_closed: false,
+                    has_fill: false,
+                    has_stroke: true,
+                    is_clipping_path: false,
+                });
+            }
+        }
+    }
+}
+
+/// scales the art to cover the whole box, cropping whatever overflows it
+fn add_art(
+    pdf: &mut PdfDocument,
+    page: PdfPageIndex,
+    layer: PdfLayerIndex,
+    art: Art,
+    (x, y): (Mm, Mm),
+    (width, height): (Mm, Mm),
+) {
+    let (art_width, art_height) = match &art {
+        Art::Image(image) => (image.image.width.0 as f32, image.image.height.0 as f32),
+        Art::Svg(svg) => (svg.width.0 as f32, svg.height.0 as f32),
+    };
+
+    // dots per mm, such that the art covers the box in both directions
+    let scale = (art_width / width.0).min(art_height / height.0);
+    let render_width = Mm(art_width / scale);
+    let render_height = Mm(art_height / scale);
+    let translate_x = x + (width - render_width) * 0.5;
+    let translate_y = y + (height - render_height) * 0.5;
+    let dpi = scale * MM_PER_INCH;
+
+    let current_layer = pdf.get_page(page).get_layer(layer);
+    current_layer.save_graphics_state();
+    current_layer.add_shape(Line {
+        points: vec![
+            (Point::new(x, y), false),
+            (Point::new(x, y + height), false),
+            (Point::new(x + width, y + height), false),
+            (Point::new(x + width, y), false),
+        ],
+        is_closed: true,
+        has_fill: false,
+        has_stroke: false,
+        is_clipping_path: true,
+    });
+
+    match art {
+        Art::Image(image) => image.add_to_document(
+            pdf,
+            page,
+            layer,
             ImageTransform {
-                translate_x: Some((PAGE_WIDTH - render_width) * 0.5),
-                translate_y: Some(PAGE_HEIGHT - page_layout.y_offset - render_height),
-                rotate: None,
-                scale_x: None,
-                scale_y: None,
-                dpi: Some((Dots(width as f32) / render_width).0),
+                translate_x: Some(translate_x),
+                translate_y: Some(translate_y),
+                dpi: Some(dpi),
+                ..Default::default()
             },
-        );
-        page_layout.y_offset += render_height + Mm::from(line_height);
+        ),
+        Art::Svg(svg) => svg.add_to_document(
+            pdf,
+            page,
+            layer,
+            SvgTransform {
+                translate_x: Some(translate_x.into()),
+                translate_y: Some(translate_y.into()),
+                dpi: Some(dpi),
+                ..Default::default()
+            },
+        ),
+    }
 
-        self.write_shaped_lines(caption_lines, line_height, Mm(0.0), true);
-        self.add_y_offset(Mm::from(line_height));
+    pdf.get_page(page).get_layer(layer).restore_graphics_state();
+}
+
+/// draw a glyph of a color font with its origin at (x, y): the layers of a `COLR` glyph,
+/// the largest bitmap of an `sbix` or `CBDT` glyph, or otherwise its outline in the
+/// text color
+fn draw_color_glyph(
+    pdf: &mut PdfDocument,
+    (page, layer): (PdfPageIndex, PdfLayerIndex),
+    face: &ttf_parser::Face,
+    glyph_id: u16,
+    (x, y): (Mm, Mm),
+    size: Pt,
+    foreground: crate::printpdf::Color,
+) {
+    let glyph_id = ttf_parser::GlyphId(glyph_id);
+    let origin = (Pt::from(x), Pt::from(y));
+    let scale = size.0 / face.units_per_em() as f32;
+
+    if let Some(raster) = face
+        .glyph_raster_image(glyph_id, u16::MAX)
+        .filter(|raster| raster.format == ttf_parser::RasterImageFormat::PNG)
+    {
+        let Ok(image) = Image::from_bytes(raster.data.to_vec()) else {
+            return;
+        };
+        let pixel = Mm::from(size) * (1.0 / raster.pixels_per_em as f32);
+        image.add_to_document(
+            pdf,
+            page,
+            layer,
+            ImageTransform {
+                translate_x: Some(x + pixel * raster.x as f32),
+                translate_y: Some(y + pixel * raster.y as f32),
+                dpi: Some(MM_PER_INCH / pixel.0),
+                ..Default::default()
+            },
+        );
+        return;
     }
+
+    let layer = pdf.get_page(page).get_layer(layer);
+    color_glyph::draw(layer, face, glyph_id, origin, scale, foreground);
 }
 
 fn map_color(c: syntect::highlighting::Color) -> crate::printpdf::Color {
@@ -519,6 +2533,228 @@ fn map_color(c: syntect::highlighting::Color) -> crate::printpdf::Color {
     ))
 }
 
+/// the glyphs from the font's `sups` or `subs` feature, if it has a substitute for all of them
+fn script_glyphs(face: &ttf_parser::Face, feature: &[u8; 4], glyphs: &[u16]) -> Option<Vec<u16>> {
+    substitute_glyphs(face, feature, glyphs)
+        .into_iter()
+        .collect()
+}
+
+/// the substitute for each glyph from the font's single substitutions for the feature,
+/// or None for glyphs it doesn't have one for
+fn substitute_glyphs(
+    face: &ttf_parser::Face,
+    feature: &[u8; 4],
+    glyphs: &[u16],
+) -> Vec<Option<u16>> {
+    use ttf_parser::gsub::{SingleSubstitution, SubstitutionSubtable};
+
+    let Some(gsub) = face.tables().gsub else {
+        return vec![None; glyphs.len()];
+    };
+    let tag = ttf_parser::Tag::from_bytes(feature);
+    let lookups: Vec<_> = gsub
+        .features
+        .into_iter()
+        .filter(|f| f.tag == tag)
+        .flat_map(|f| f.lookup_indices)
+        .filter_map(|i| gsub.lookups.get(i))
+        .collect();
+
+    glyphs
+        .iter()
+        .map(|&glyph| {
+            let glyph = ttf_parser::GlyphId(glyph);
+            lookups
+                .iter()
+                .flat_map(|lookup| lookup.subtables.into_iter::<SubstitutionSubtable>())
+                .find_map(|subtable| match subtable {
+                    SubstitutionSubtable::Single(SingleSubstitution::Format1 {
+                        coverage,
+                        delta,
+                    }) => {
+                        coverage.get(glyph)?;
+                        Some((glyph.0 as i16).wrapping_add(delta) as u16)
+                    }
+                    SubstitutionSubtable::Single(SingleSubstitution::Format2 {
+                        coverage,
+                        substitutes,
+                    }) => substitutes.get(coverage.get(glyph)?).map(|g| g.0),
+                    _ => None,
+                })
+        })
+        .collect()
+}
+
+/// zero width joiners, variation selectors, combining marks and emoji modifiers, which
+/// are set in the same font as the character before them
+fn joins_previous(c: char) -> bool {
+    matches!(
+        c,
+        '\u{200D}'
+            | '\u{FE00}'..='\u{FE0F}'
+            | '\u{0300}'..='\u{036F}'
+            | '\u{20D0}'..='\u{20FF}'
+            | '\u{1F3FB}'..='\u{1F3FF}'
+            | '\u{E0020}'..='\u{E007F}'
+    )
+}
+
+/// invisible characters that only change how the text around them is laid out: word
+/// joiners, and the marks, embeddings and isolates that set the direction of the text
+fn is_format_control(c: char) -> bool {
+    matches!(
+        c,
+        '\u{200E}' | '\u{200F}' | '\u{202A}'..='\u{202E}' | '\u{2060}' | '\u{2066}'..='\u{2069}'
+    )
+}
+
+/// moves the glyphs of the line back where full-width punctuation is compressed, returning
+/// how much shorter the line is than it was laid out
+fn compress_punctuation(text: &str, line: &mut LayoutLine) -> f32 {
+    let glyphs: Vec<(char, f32)> = line
+        .glyphs
+        .iter()
+        .map(|glyph| (text[glyph.start..].chars().next().unwrap_or(' '), glyph.w))
+        .collect();
+    let (shifts, shortened) = typography::compress_punctuation(&glyphs);
+    for (glyph, shift) in line.glyphs.iter_mut().zip(shifts) {
+        glyph.x -= shift;
+    }
+    line.w -= shortened;
+    shortened
+}
+
+/// a copy of the attributes, as cosmic-text's `AttrsList` can't be cloned
+fn copy_attrs(attrs: &AttrsList) -> AttrsList {
+    let mut copy = AttrsList::new(attrs.defaults());
+    for (range, span) in attrs.spans() {
+        copy.add_span(range.clone(), span.as_attrs());
+    }
+    copy
+}
+
+/// what the glyphs set for the text are read as, in small caps or not
+fn actual_text(text: &str, range: Range<usize>, small_caps: bool) -> String {
+    if small_caps {
+        text[range].chars().flat_map(char::to_lowercase).collect()
+    } else {
+        text[range].to_owned()
+    }
+}
+
+/// the attributes of the text from `start` on
+fn attrs_from(attrs: &AttrsList, start: usize) -> AttrsList {
+    let mut attrs = copy_attrs(attrs);
+    if start == 0 {
+        attrs
+    } else {
+        attrs.split_off(start)
+    }
+}
+
+/// moves the glyphs of a line laid out from `start` of the text to index the whole text
+fn offset_glyphs(line: &mut LayoutLine, start: usize) {
+    for glyph in &mut line.glyphs {
+        glyph.start += start;
+        glyph.end += start;
+    }
+}
+
+/// where in the text the line starts, if there is such a line
+fn line_start(lines: &ShapedLines, line: usize) -> Option<usize> {
+    let line = lines.lines.get(line)?;
+    line.glyphs.iter().map(|glyph| glyph.start).min()
+}
+
+/// the end of the letter to set as a drop cap, along with any quotes or brackets before it,
+/// eg `“T`. None if the text doesn't start with a letter or digit
+fn drop_cap_end(text: &str) -> Option<usize> {
+    for (i, c) in text.char_indices().take(3) {
+        if c.is_alphanumeric() {
+            return Some(i + c.len_utf8());
+        }
+        let opening = matches!(
+            c,
+            '"' | '\'' | '“' | '‘' | '„' | '‚' | '«' | '‹' | '(' | '['
+        );
+        if !opening && !is_format_control(c) {
+            return None;
+        }
+    }
+    None
+}
+
+/// size of small caps, relative to the capitals of the text around them
+const SMALL_CAPS_SCALING: f32 = 0.8;
+
+/// the text with its lowercase letters up to `end` set as smaller capitals, which the font's
+/// own small caps replace when it has them. Letters whose capital is a different length,
+/// such as `ß`, are left alone so the attributes still line up, as are those that don't
+/// come back from their capital, such as `ς`, so the text can still be copied
+fn small_caps(text: &str, attrs: &AttrsList, end: usize) -> (String, AttrsList) {
+    let mut caps = String::with_capacity(text.len());
+    let mut caps_attrs = copy_attrs(attrs);
+    // runs of letters with the same attributes are one span, so they still shape together
+    let mut run: Option<(Range<usize>, Attrs)> = None;
+    for (i, c) in text.char_indices() {
+        let mut upper = c.to_uppercase();
+        let capital = match (upper.next(), upper.next()) {
+            (Some(u), None)
+                if i < end
+                    && c.is_lowercase()
+                    && u.len_utf8() == c.len_utf8()
+                    && u.to_lowercase().eq([c]) =>
+            {
+                u
+            }
+            _ => {
+                caps.push(c);
+                continue;
+            }
+        };
+        caps.push(capital);
+        let span = attrs.get_span(i);
+        let span = span
+            .scaling(span.scaling * SMALL_CAPS_SCALING)
+            .metadata(span.metadata | Decoration::SMALL_CAPS.0);
+        let range = i..i + c.len_utf8();
+        run = match run.take() {
+            Some((current, current_attrs)) if current.end == i && current_attrs == span => {
+                Some((current.start..range.end, span))
+            }
+            other => {
+                if let Some((range, attrs)) = other {
+                    caps_attrs.add_span(range, attrs);
+                }
+                Some((range, span))
+            }
+        };
+    }
+    if let Some((range, attrs)) = run {
+        caps_attrs.add_span(range, attrs);
+    }
+    (caps, caps_attrs)
+}
+
+/// the text each glyph stands for. The first glyph of a cluster, such as a ligature, stands
+/// for all of the cluster's text and the rest stand for none of it
+fn glyph_texts<'a>(
+    text: &'a str,
+    glyphs: &'a [LayoutGlyph],
+) -> impl Iterator<Item = (u16, &'a str)> + 'a {
+    glyphs.iter().enumerate().map(move |(i, glyph)| {
+        let cluster = (glyph.start, glyph.end);
+        let first = i == 0 || (glyphs[i - 1].start, glyphs[i - 1].end) != cluster;
+        let text = if first {
+            &text[glyph.start..glyph.end]
+        } else {
+            ""
+        };
+        (glyph.cache_key.glyph_id, text)
+    })
+}
+
 fn map_cosmic_color(c: Option<cosmic_text::Color>) -> crate::printpdf::Color {
     match c {
         Some(c) => crate::printpdf::Color::Rgb(Rgb::new(
@@ -577,3 +2813,26 @@ where
         Some((group, std::mem::take(&mut self.slice)))
     }
 }
+
+#[test]
+fn test_actual_text() {
+    use crate::printpdf::lopdf::{content::Content, Object, Stream};
+
+    // an emoji drawn from a color font is read as the emoji, not the invisible glyph under it
+    let text = "Ship it 👍";
+    let start = text.find('👍').unwrap();
+    let mut layer = PdfLayer::new("text");
+    layer.begin_actual_text(&actual_text(text, start..text.len(), false));
+    let stream = Stream::from(layer);
+    let content = Content::decode(&stream.content).unwrap();
+    let [Object::Name(tag), Object::Dictionary(properties)] = &content.operations[0].operands[..]
+    else {
+        panic!("expected a marked content span");
+    };
+    assert_eq!(tag, b"Span");
+    let actual = properties.get(b"ActualText").unwrap().as_str().unwrap();
+    // UTF-16BE with a byte order mark, where U+1F44D is a surrogate pair
+    assert_eq!(actual, [0xFE, 0xFF, 0xD8, 0x3D, 0xDC, 0x4D]);
+
+    assert_eq!(actual_text("Rust", 0..4, true), "rust");
+}
@@ -0,0 +1,66 @@
+//! Link annotations, for clickable areas that jump to another place in the document
+
+use lopdf;
+
+use crate::printpdf::{PdfPageIndex, Point, Pt};
+
+/// Clickable area on a page, linking to a destination within the document
+///
+/// See PDF Reference 1.7 Page 615
+#[derive(Debug, Copy, Clone)]
+pub struct LinkAnnotation {
+    /// Lower left corner of the clickable area
+    pub lower_left: Point,
+    /// Upper right corner of the clickable area
+    pub upper_right: Point,
+    /// Where the link jumps to
+    pub destination: Destination,
+}
+
+/// A position within the document
+#[derive(Debug, Copy, Clone)]
+pub struct Destination {
+    pub page: PdfPageIndex,
+    /// Distance from the bottom of the page that should be shown at the top of
+    /// the window. If `None`, the current position is kept
+    pub top: Option<Pt>,
+}
+
+impl LinkAnnotation {
+    /// Converts the annotation into a dictionary. `page_ids` are the object IDs
+    /// of every page in the document, so that the destination page can be referenced
+    pub fn into_dictionary(self, page_ids: &[lopdf::ObjectId]) -> lopdf::Dictionary {
+        use lopdf::Object::*;
+
+        let top = match self.destination.top {
+            Some(top) => Real(top.0),
+            None => Null,
+        };
+
+        lopdf::Dictionary::from_iter(vec![
+            ("Type", Name("Annot".into())),
+            ("Subtype", Name("Link".into())),
+            (
+                "Rect",
+                Array(vec![
+                    Real(self.lower_left.x.0),
+                    Real(self.lower_left.y.0),
+                    Real(self.upper_right.x.0),
+                    Real(self.upper_right.y.0),
+                ]),
+            ),
+            // no visible border
+            ("Border", Array(vec![Integer(0), Integer(0), Integer(0)])),
+            (
+                "Dest",
+                Array(vec![
+                    Reference(page_ids[self.destination.page.0]),
+                    Name("XYZ".into()),
+                    Null,
+                    top,
+                    Null,
+                ]),
+            ),
+        ])
+    }
+}
@@ -74,6 +74,19 @@ impl From<image::ColorType> for ColorSpace {
     }
 }
 
+impl ColorSpace {
+    /// Number of color components per pixel, including alpha
+    pub fn components(self) -> usize {
+        use self::ColorSpace::*;
+        match self {
+            Greyscale | Palette => 1,
+            GreyscaleAlpha => 2,
+            Rgb => 3,
+            Rgba | Cmyk => 4,
+        }
+    }
+}
+
 impl From<ColorSpace> for &'static str {
     fn from(val: ColorSpace) -> Self {
         use self::ColorSpace::*;
@@ -1,16 +1,33 @@
 //! Embedding fonts in 2D for Pdf
+use crate::printpdf::subsetting::{instance, subset, subset_tag};
 use cosmic_text::fontdb::FaceInfo;
-use cosmic_text::rustybuzz::ttf_parser::GlyphId;
+use cosmic_text::rustybuzz::ttf_parser::{self, GlyphId};
 use cosmic_text::Font;
 use lopdf;
 use lopdf::StringFormat;
 use lopdf::{Dictionary as LoDictionary, Stream as LoStream};
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::iter::FromIterator;
 
 pub struct ExternalFont<'a> {
     pub font: &'a Font,
     pub face_info: FaceInfo,
+    /// Glyph IDs written with this font. Only these glyphs are embedded
+    pub used_glyphs: HashSet<u16>,
+    /// The text each glyph stands for, from how it was shaped. Glyphs that aren't
+    /// here are mapped to the character they have in the font's cmap
+    pub glyph_text: HashMap<u16, String>,
+    /// Axis values to instance a variable font at, as in `FontInstance`
+    pub variations: Vec<([u8; 4], i32)>,
+    /// Whether the font is written top to bottom, as in `FontInstance`
+    pub vertical: bool,
+}
+
+/// A font added to the document
+pub(crate) struct EmbeddedFont {
+    pub(crate) dictionary: LoDictionary,
+    /// Mapping from the glyph IDs in the original font to the glyph IDs in the embedded subset
+    pub(crate) gid_mapping: HashMap<u16, u16>,
 }
 
 /// The text rendering mode determines how a text is drawn
@@ -49,18 +66,65 @@ impl From<TextRenderingMode> for i64 {
 
 impl ExternalFont<'_> {
     /// Takes the font and adds it to the document and consumes the font.
+    /// The font is subset to the glyphs that were used, falling back to the whole
+    /// font if it can't be subset.
     ///
     /// Returns None if the font doesn't need to be embedded
-    pub(crate) fn into_with_document(self, doc: &mut lopdf::Document) -> Option<LoDictionary> {
+    pub(crate) fn into_with_document(self, doc: &mut lopdf::Document) -> Option<EmbeddedFont> {
         use lopdf::Object;
         use lopdf::Object::*;
 
         let font = self.font;
-        let face_info = self.face_info;
+        let mut face_info = self.face_info;
+        let mut used_glyphs = self.used_glyphs;
+
+        // variable fonts are instanced at the axis values, which also changes the glyphs' widths
+        let mut face = (**font.rustybuzz()).clone();
+        let mut font_data = font.data().to_owned();
+        if !self.variations.is_empty() {
+            let values: Vec<f32> = face
+                .variation_axes()
+                .into_iter()
+                .map(|axis| {
+                    self.variations
+                        .iter()
+                        .find(|(tag, _)| *tag == axis.tag.to_bytes())
+                        .map_or(axis.def_value, |&(_, value)| value as f32 / 10.0)
+                })
+                .collect();
+            match instance(&font_data, &values) {
+                Ok(instanced) => {
+                    font_data = instanced;
+                    for (tag, value) in &self.variations {
+                        face.set_variation(ttf_parser::Tag::from_bytes(tag), *value as f32 / 10.0);
+                    }
+                }
+                Err(err) => log::warn!(
+                    "could not instance {}, embedding the default instance: {err}",
+                    face_info.post_script_name
+                ),
+            }
+        }
+
+        let (font_data, gid_mapping) = match subset(&font_data, &mut used_glyphs) {
+            Ok(subset) => {
+                let tag = subset_tag(&face_info.post_script_name, &used_glyphs);
+                face_info.post_script_name = format!("{tag}+{}", face_info.post_script_name);
+                (subset.new_font_bytes, subset.gid_mapping)
+            }
+            Err(err) => {
+                log::warn!(
+                    "could not subset {}, embedding the whole font: {err}",
+                    face_info.post_script_name
+                );
+                let identity = used_glyphs.iter().map(|&gid| (gid, gid)).collect();
+                (font_data, identity)
+            }
+        };
 
         let font_stream = LoStream::new(
-            LoDictionary::from_iter(vec![("Length1", Integer(font.data().len() as i64))]),
-            font.data().to_owned(),
+            LoDictionary::from_iter(vec![("Length1", Integer(font_data.len() as i64))]),
+            font_data,
         )
         .with_compression(false); /* important! font stream must not be compressed! */
 
@@ -73,57 +137,66 @@ impl ExternalFont<'_> {
                 Name(face_info.post_script_name.clone().into_bytes()),
             ),
             // Identity-H for horizontal writing, Identity-V for vertical writing
-            ("Encoding".into(), Name("Identity-H".into())),
+            (
+                "Encoding".into(),
+                Name(
+                    if self.vertical {
+                        "Identity-V"
+                    } else {
+                        "Identity-H"
+                    }
+                    .into(),
+                ),
+            ),
             // Missing DescendantFonts and ToUnicode
         ];
 
+        let metrics = DescriptorMetrics::new(&face);
         let mut font_descriptor_vec: Vec<(::std::string::String, Object)> = vec![
             ("Type".into(), Name("FontDescriptor".into())),
             (
                 "FontName".into(),
                 Name(face_info.post_script_name.clone().into_bytes()),
             ),
+            ("Ascent".into(), Integer(metrics.ascent)),
+            ("Descent".into(), Integer(metrics.descent)),
+            ("CapHeight".into(), Integer(metrics.cap_height)),
+            ("ItalicAngle".into(), Real(metrics.italic_angle)),
+            ("Flags".into(), Integer(metrics.flags)),
+            ("StemV".into(), Integer(metrics.stem_v)),
+            // although the following entry is technically not needed, Adobe Reader needs it
             (
-                "Ascent".into(),
-                Integer(i64::from(font.rustybuzz().ascender())),
-            ),
-            (
-                "Descent".into(),
-                Integer(i64::from(font.rustybuzz().descender())),
-            ),
-            (
-                "CapHeight".into(),
-                Integer(i64::from(font.rustybuzz().ascender())),
+                "FontBBox".into(),
+                Array(metrics.bbox.into_iter().map(Integer).collect()),
             ),
-            ("ItalicAngle".into(), Integer(0)),
-            ("Flags".into(), Integer(32)),
-            ("StemV".into(), Integer(80)),
         ];
+        if let Some(x_height) = metrics.x_height {
+            font_descriptor_vec.push(("XHeight".into(), Integer(x_height)));
+        }
 
         // End setting required font arguments
 
-        // Maximum height of a single character in the font
-        let mut max_height = 0;
-        // Total width of all characters
-        let mut total_width = 0;
         // Widths (or heights, depends on self.vertical_writing)
         // of the individual characters, indexed by glyph id
         let mut widths = Vec::<(u32, u32)>::new();
 
-        // Glyph IDs - (Unicode IDs - character width, character height)
-        let mut cmap = BTreeMap::<u32, (u32, u32, u32)>::new();
-        cmap.insert(0, (0, 1000, 1000));
+        // Glyph IDs - (text, character width, character height)
+        let mut cmap = BTreeMap::<u32, (::std::string::String, u32, u32)>::new();
+        cmap.insert(0, (::std::string::String::new(), 1000, 1000));
 
-        for (glyph_id, c) in glyph_ids(font) {
-            if let Some(glyph_metrics) = glyph_metrics(font, glyph_id) {
-                if glyph_metrics.height > max_height {
-                    max_height = glyph_metrics.height;
-                }
+        // ligatures and alternates aren't in the cmap, so the text from shaping comes first
+        let mut texts: HashMap<u16, ::std::string::String> = glyph_ids(&face)
+            .into_iter()
+            .map(|(glyph_id, c)| (glyph_id, c.to_string()))
+            .collect();
+        texts.extend(self.glyph_text);
 
-                total_width += glyph_metrics.width;
+        for (&glyph_id, &new_glyph_id) in &gid_mapping {
+            if let Some(glyph_metrics) = glyph_metrics(&face, glyph_id) {
+                let text = texts.remove(&glyph_id).unwrap_or_default();
                 cmap.insert(
-                    glyph_id as u32,
-                    (c as u32, glyph_metrics.width, glyph_metrics.height),
+                    new_glyph_id as u32,
+                    (text, glyph_metrics.width, glyph_metrics.height),
                 );
             }
         }
@@ -153,9 +226,12 @@ impl ExternalFont<'_> {
                     cur_first_bit = (*glyph_id >> 8) as u16;
                 }
 
-                let (unicode, width, _) = *unicode_width_tuple;
-                current_cmap_block.push((*glyph_id, unicode));
-                widths.push((*glyph_id, width));
+                let (text, width, _) = unicode_width_tuple;
+                // glyphs that don't stand for any text, such as the rest of a cluster
+                if !text.is_empty() {
+                    current_cmap_block.push((*glyph_id, text.clone()));
+                }
+                widths.push((*glyph_id, *width));
             }
 
             all_cmap_blocks.push(current_cmap_block);
@@ -180,10 +256,17 @@ impl ExternalFont<'_> {
         let mut current_width_vec = Vec::<Object>::new();
 
         // scale the font width so that it sort-of fits into an 1000 unit square
-        let percentage_font_scaling = 1000.0 / (font.rustybuzz().units_per_em() as f64);
+        let percentage_font_scaling = 1000.0 / (face.units_per_em() as f64);
+
+        // widths of the glyphs in the subset, in the order of their new glyph IDs
+        let mut glyphs: Vec<(u16, u16)> = gid_mapping
+            .iter()
+            .map(|(&old_gid, &new_gid)| (new_gid, old_gid))
+            .collect();
+        glyphs.sort_unstable();
 
-        for gid in 0..font.rustybuzz().number_of_glyphs() {
-            if let Some(GlyphMetrics { width, .. }) = glyph_metrics(font, gid) {
+        for (gid, old_gid) in glyphs {
+            if let Some(GlyphMetrics { width, .. }) = glyph_metrics(&face, old_gid) {
                 if gid == current_high_gid {
                     current_width_vec
                         .push(Integer((width as f64 * percentage_font_scaling) as i64));
@@ -210,6 +293,30 @@ impl ExternalFont<'_> {
         // default width for characters
         let dw = { ("DW", Integer(1000)) };
 
+        // vertical metrics, see page 450 in the PDF 1.7 reference. Each glyph gets
+        // `gid [w1y vx vy]`: its advance down the page, as a negative number, and the
+        // position of its vertical origin (the top center) from its horizontal origin
+        let mut vertical_metrics = Vec::<Object>::new();
+        if self.vertical {
+            let mut glyphs: Vec<(u16, u16)> = gid_mapping
+                .iter()
+                .map(|(&old_gid, &new_gid)| (new_gid, old_gid))
+                .collect();
+            glyphs.sort_unstable();
+            for (gid, old_gid) in glyphs {
+                let Some(metrics) = vertical_glyph_metrics(&face, old_gid) else {
+                    continue;
+                };
+                let scaled = |units: i32| Integer((units as f64 * percentage_font_scaling) as i64);
+                vertical_metrics.push(Integer(gid as i64));
+                vertical_metrics.push(Array(vec![
+                    scaled(-metrics.advance),
+                    scaled(metrics.origin_x),
+                    scaled(metrics.origin_y),
+                ]));
+            }
+        }
+
         let mut desc_fonts = LoDictionary::from_iter(vec![
             ("Type", Name("Font".into())),
             ("Subtype", Name("CIDFontType2".into())),
@@ -225,18 +332,13 @@ impl ExternalFont<'_> {
             w,
             dw,
         ]);
+        if self.vertical {
+            desc_fonts.set("W2", Array(vertical_metrics));
+            desc_fonts.set("DW2", Array(vec![Integer(880), Integer(-1000)]));
+        }
 
-        let font_bbox = vec![
-            Integer(0),
-            Integer(max_height as i64),
-            Integer(total_width as i64),
-            Integer(max_height as i64),
-        ];
         font_descriptor_vec.push(("FontFile2".into(), Reference(doc.add_object(font_stream))));
 
-        // although the following entry is technically not needed, Adobe Reader needs it
-        font_descriptor_vec.push(("FontBBox".into(), Array(font_bbox)));
-
         let font_descriptor_vec_id = doc.add_object(LoDictionary::from_iter(font_descriptor_vec));
 
         desc_fonts.set("FontDescriptor", Reference(font_descriptor_vec_id));
@@ -247,13 +349,103 @@ impl ExternalFont<'_> {
         ));
         font_vec.push(("ToUnicode".into(), Reference(cid_to_unicode_map_stream_id)));
 
-        Some(LoDictionary::from_iter(font_vec))
+        Some(EmbeddedFont {
+            dictionary: LoDictionary::from_iter(font_vec),
+            gid_mapping,
+        })
+    }
+}
+
+/// Font descriptor flags, see PDF Reference 1.7 Page 458
+const FLAG_FIXED_PITCH: i64 = 1 << 0;
+const FLAG_SERIF: i64 = 1 << 1;
+const FLAG_SYMBOLIC: i64 = 1 << 2;
+const FLAG_NONSYMBOLIC: i64 = 1 << 5;
+const FLAG_ITALIC: i64 = 1 << 6;
+
+/// The metrics of a font descriptor, read from the font's `head`, `hhea`, `OS/2`
+/// and `post` tables and scaled to the 1000 unit glyph space
+struct DescriptorMetrics {
+    ascent: i64,
+    descent: i64,
+    cap_height: i64,
+    x_height: Option<i64>,
+    italic_angle: f32,
+    flags: i64,
+    stem_v: i64,
+    bbox: [i64; 4],
+}
+
+impl DescriptorMetrics {
+    fn new(face: &ttf_parser::Face) -> Self {
+        let scale = |units: i16| (units as f64 * 1000.0 / face.units_per_em() as f64) as i64;
+        // the height of a glyph's outline, if the font has one for the character
+        let glyph_height = |c: char| {
+            let bbox = face.glyph_bounding_box(face.glyph_index(c)?)?;
+            Some(bbox.y_max)
+        };
+
+        let italic_angle = face.italic_angle().unwrap_or(0.0);
+        let mut flags = 0;
+        if face.is_monospaced() {
+            flags |= FLAG_FIXED_PITCH;
+        }
+        if is_serif(face) {
+            flags |= FLAG_SERIF;
+        }
+        // fonts without latin letters, such as symbol fonts, don't use the standard latin set
+        flags |= if face.glyph_index('a').is_some() {
+            FLAG_NONSYMBOLIC
+        } else {
+            FLAG_SYMBOLIC
+        };
+        if face.is_italic() || face.is_oblique() || italic_angle != 0.0 {
+            flags |= FLAG_ITALIC;
+        }
+
+        // fonts don't record their stem width, so estimate it from the weight
+        let weight = face.weight().to_number() as f32;
+        let stem_v = 10.0 + 220.0 * ((weight - 50.0) / 900.0).powi(2);
+
+        let bbox = face.global_bounding_box();
+        Self {
+            ascent: scale(face.ascender()),
+            descent: scale(face.descender()),
+            cap_height: scale(
+                face.capital_height()
+                    .or_else(|| glyph_height('H'))
+                    .unwrap_or_else(|| face.ascender()),
+            ),
+            x_height: face.x_height().or_else(|| glyph_height('x')).map(scale),
+            italic_angle,
+            flags,
+            stem_v: stem_v as i64,
+            bbox: [
+                scale(bbox.x_min),
+                scale(bbox.y_min),
+                scale(bbox.x_max),
+                scale(bbox.y_max),
+            ],
+        }
     }
 }
 
-// type GlyphId = u32;
-type UnicodeCodePoint = u32;
-type CmapBlock = Vec<(u32, UnicodeCodePoint)>;
+/// Whether the font has serifs, from the PANOSE classification in the `OS/2` table,
+/// or failing that the IBM font family class
+fn is_serif(face: &ttf_parser::Face) -> bool {
+    let Some(os2) = face.raw_face().table(ttf_parser::Tag::from_bytes(b"OS/2")) else {
+        return false;
+    };
+    match os2.get(32..34) {
+        // latin text, with one of the serif styles or one of the sans serif styles
+        Some(&[2, 2..=10]) => return true,
+        Some(&[2, 11..=13]) => return false,
+        _ => {}
+    }
+    matches!(os2.get(30).copied(), Some(1..=5 | 7))
+}
+
+type CmapBlock = Vec<(u32, String)>;
 
 /// Generates a CMAP (character map) from valid cmap blocks
 fn generate_cid_to_unicode_map(face_name: String, all_cmap_blocks: Vec<CmapBlock>) -> String {
@@ -264,11 +456,13 @@ fn generate_cid_to_unicode_map(face_name: String, all_cmap_blocks: Vec<CmapBlock
 
     for cmap_block in all_cmap_blocks
         .into_iter()
-        .filter(|block| !block.is_empty() || block.len() < 100)
+        .filter(|block| !block.is_empty())
     {
         cid_to_unicode_map.push_str(format!("{} beginbfchar\r\n", cmap_block.len()).as_str());
-        for (glyph_id, unicode) in cmap_block {
-            cid_to_unicode_map.push_str(format!("<{glyph_id:04x}> <{unicode:04x}>\n").as_str());
+        for (glyph_id, text) in cmap_block {
+            // ligatures map to several characters, as UTF-16BE
+            let unicode: String = text.encode_utf16().map(|c| format!("{c:04x}")).collect();
+            cid_to_unicode_map.push_str(format!("<{glyph_id:04x}> <{unicode}>\n").as_str());
         }
         cid_to_unicode_map.push_str("endbfchar\r\n");
     }
@@ -284,6 +478,33 @@ fn generate_cid_to_unicode_map(face_name: String, all_cmap_blocks: Vec<CmapBlock
 //     }
 // }
 
+/// A font, instanced at the axis values if it's a variable font
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct FontInstance {
+    pub id: cosmic_text::fontdb::ID,
+    /// Axis tags and values, in tenths, that differ from the font's defaults
+    pub variations: Vec<([u8; 4], i32)>,
+    /// Whether glyphs are written top to bottom, with the `Identity-V` encoding
+    pub vertical: bool,
+}
+
+impl FontInstance {
+    /// The name of the font in the document: its PostScript name, followed by
+    /// the axis values of any instance but the default, eg `CascadiaCode-Regular-wght600`,
+    /// and `-V` if it's written vertically
+    pub fn name(&self, post_script_name: &str) -> String {
+        let mut name = post_script_name.to_owned();
+        for (tag, value) in &self.variations {
+            let tag = String::from_utf8_lossy(tag);
+            name.push_str(&format!("-{tag}{}", *value as f32 / 10.0));
+        }
+        if self.vertical {
+            name.push_str("-V");
+        }
+        name
+    }
+}
+
 /// Indexed reference to a font that was added to the document
 /// This is a "reference by postscript name"
 #[derive(Debug, Hash, Eq, Ord, Clone, PartialEq, PartialOrd)]
@@ -311,8 +532,7 @@ pub struct GlyphMetrics {
     pub height: u32,
 }
 
-fn glyph_ids(ttf: &Font) -> HashMap<u16, char> {
-    let face = ttf.rustybuzz();
+fn glyph_ids(face: &ttf_parser::Face) -> HashMap<u16, char> {
     let subtables = face
         .tables()
         .cmap
@@ -335,10 +555,38 @@ fn glyph_ids(ttf: &Font) -> HashMap<u16, char> {
     map
 }
 
-fn glyph_metrics(ttf: &Font, glyph_id: u16) -> Option<GlyphMetrics> {
+/// How a glyph is placed when written top to bottom, in font units
+struct VerticalGlyphMetrics {
+    advance: i32,
+    /// the vertical origin, from the horizontal origin
+    origin_x: i32,
+    origin_y: i32,
+}
+
+/// vertical metrics from the `vmtx` and `VORG` tables. Fonts without them are set
+/// a full em apart, with the top of each em at the font's ascender
+fn vertical_glyph_metrics(face: &ttf_parser::Face, glyph_id: u16) -> Option<VerticalGlyphMetrics> {
     let glyph_id = GlyphId(glyph_id);
+    let width = face.glyph_hor_advance(glyph_id)?;
+    let advance = face
+        .glyph_ver_advance(glyph_id)
+        .map_or(face.units_per_em() as i32, i32::from);
+    let origin_y = face
+        .glyph_y_origin(glyph_id)
+        .or_else(|| {
+            let bearing = face.glyph_ver_side_bearing(glyph_id)?;
+            Some(bearing + face.glyph_bounding_box(glyph_id)?.y_max)
+        })
+        .map_or(face.ascender() as i32, i32::from);
+    Some(VerticalGlyphMetrics {
+        advance,
+        origin_x: width as i32 / 2,
+        origin_y,
+    })
+}
 
-    let face = ttf.rustybuzz();
+fn glyph_metrics(face: &ttf_parser::Face, glyph_id: u16) -> Option<GlyphMetrics> {
+    let glyph_id = GlyphId(glyph_id);
 
     if let Some(width) = face.glyph_hor_advance(glyph_id) {
         let width = width as u32;
@@ -351,3 +599,62 @@ fn glyph_metrics(ttf: &Font, glyph_id: u16) -> Option<GlyphMetrics> {
         None
     }
 }
+
+#[test]
+fn test_cid_to_unicode_map() {
+    let blocks = vec![vec![
+        (3, "f".to_owned()),
+        (7, "ffi".to_owned()),
+        (9, "😀".to_owned()),
+    ]];
+    let map = generate_cid_to_unicode_map("Test".to_owned(), blocks);
+    assert!(
+        map.contains("3 beginbfchar\r\n<0003> <0066>\n<0007> <006600660069>\n<0009> <d83dde00>\n")
+    );
+}
+
+#[test]
+fn test_font_instance_name() {
+    let id = cosmic_text::fontdb::ID::dummy();
+    let default = FontInstance {
+        id,
+        variations: vec![],
+        vertical: false,
+    };
+    assert_eq!(default.name("CascadiaCode-Regular"), "CascadiaCode-Regular");
+    let instance = FontInstance {
+        id,
+        variations: vec![(*b"wght", 6000), (*b"opsz", 125)],
+        vertical: true,
+    };
+    assert_eq!(
+        instance.name("CascadiaCode-Regular"),
+        "CascadiaCode-Regular-wght600-opsz12.5-V"
+    );
+}
+
+#[test]
+fn test_descriptor_metrics() {
+    let metrics = |path: &str| {
+        let data = std::fs::read(format!("example/assets/fonts/{path}")).unwrap();
+        let face = ttf_parser::Face::parse(&data, 0).unwrap();
+        DescriptorMetrics::new(&face)
+    };
+
+    let italic = metrics("PT_Serif/PTSerif-Italic.ttf");
+    assert_eq!(italic.flags, FLAG_SERIF | FLAG_NONSYMBOLIC | FLAG_ITALIC);
+    assert_eq!(italic.italic_angle, -12.0);
+    assert_eq!((italic.ascent, italic.descent), (1039, -286));
+    assert_eq!((italic.cap_height, italic.x_height), (700, Some(507)));
+    assert_eq!(italic.bbox, [-274, -272, 1108, 992]);
+
+    let regular = metrics("PT_Sans/PTSans-Regular.ttf");
+    assert_eq!(regular.flags, FLAG_NONSYMBOLIC);
+    assert_eq!(regular.italic_angle, 0.0);
+    // the stem width is estimated from the weight, so bold is thicker
+    let bold = metrics("PT_Sans/PTSans-Bold.ttf");
+    assert_eq!((regular.stem_v, bold.stem_v), (43, 124));
+
+    let code = metrics("FiraCode/FiraCode-Regular.ttf");
+    assert_eq!(code.flags, FLAG_FIXED_PITCH | FLAG_NONSYMBOLIC);
+}
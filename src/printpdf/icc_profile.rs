@@ -14,7 +14,7 @@ pub enum IccProfileType {
 #[derive(Debug, Clone, PartialEq)]
 pub struct IccProfile {
     /// Binary Icc profile
-    icc: Vec<u8>,
+    pub(crate) icc: Vec<u8>,
     /// CMYK or RGB or LAB icc profile?
     icc_type: IccProfileType,
     /// Does the ICC profile have an "Alternate" version or not?
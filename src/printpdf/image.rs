@@ -1,15 +1,15 @@
 //! Abstraction class for images. Please use this class
 //! instead of adding `ImageXObjects` yourself
 
-use image::{DynamicImage, ImageDecoder, ImageError};
+use image::{DynamicImage, ImageDecoder, ImageError, ImageFormat};
 
-use crate::printpdf::{ImageXObject, Mm, Px};
+use crate::printpdf::{ImageXObject, Mm, Px, XObject};
 
-use super::{PdfLayerIndex, PdfPage};
+use super::{PdfDocument, PdfLayerIndex, PdfPage, PdfPageIndex};
 
 /// Image - wrapper around an `ImageXObject` to allow for more control
 /// within the library
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Image {
     /// The actual image
     pub image: ImageXObject,
@@ -32,6 +32,39 @@ impl<'a> Image {
             image: ImageXObject::from_dynamic_image(image),
         }
     }
+
+    /// Loads an encoded image file. JPEGs are embedded as-is,
+    /// anything else is decoded and losslessly re-compressed.
+    ///
+    /// Palette images are expanded to RGB, and only the first frame of
+    /// an animated GIF is kept. Embedded ICC profiles are preserved
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Self, ImageError> {
+        use image::codecs::{png::PngDecoder, tiff::TiffDecoder, webp::WebPDecoder};
+        use std::io::Cursor;
+
+        let format = image::guess_format(&bytes)?;
+        if format == ImageFormat::Jpeg {
+            return Ok(Self {
+                image: ImageXObject::from_jpeg(bytes)?,
+            });
+        }
+
+        let icc_profile = match format {
+            ImageFormat::Png => PngDecoder::new(Cursor::new(&bytes))?.icc_profile(),
+            ImageFormat::Tiff => TiffDecoder::new(Cursor::new(&bytes))?.icc_profile(),
+            ImageFormat::WebP => WebPDecoder::new(Cursor::new(&bytes))?.icc_profile(),
+            _ => None,
+        };
+
+        let image = image::load_from_memory_with_format(&bytes, format)?;
+        let image = ImageXObject::from_dynamic_image(&image);
+        Ok(Self {
+            image: match icc_profile {
+                Some(icc) => image.with_icc_profile(icc),
+                None => image,
+            },
+        })
+    }
 }
 
 /// Transform that is applied immediately before the
@@ -66,47 +99,79 @@ impl Image {
     /// You can use the "transform.dpi" parameter to specify a scaling -
     /// the default is 300dpi
     pub fn add_to_layer(self, page: &mut PdfPage, layer: PdfLayerIndex, transform: ImageTransform) {
-        use crate::printpdf::CurTransMat;
-        use crate::printpdf::Pt;
-
-        // PDF maps an image to a 1x1 square, we have to adjust the transform matrix
-        // to fix the distortion
-        let dpi = transform.dpi.unwrap_or(300.0);
-
-        //Image at the given dpi should 1px = 1pt
-        let image_w = self.image.width.into_pt(dpi);
-        let image_h = self.image.height.into_pt(dpi);
-
-        let image = page.add_image(self.image);
-
-        let scale_x = transform.scale_x.unwrap_or(1.0);
-        let scale_y = transform.scale_y.unwrap_or(1.0);
-        let image_w = image_w.0 * scale_x;
-        let image_h = image_h.0 * scale_y;
-
-        let mut transforms = Vec::new();
-
-        transforms.push(CurTransMat::Scale(image_w, image_h));
-
-        if let Some(rotate) = transform.rotate.as_ref() {
-            transforms.push(CurTransMat::Translate(
-                Pt(-rotate.rotation_center_x.into_pt(dpi).0),
-                Pt(-rotate.rotation_center_y.into_pt(dpi).0),
-            ));
-            transforms.push(CurTransMat::Rotate(rotate.angle_ccw_degrees));
-            transforms.push(CurTransMat::Translate(
-                rotate.rotation_center_x.into_pt(dpi),
-                rotate.rotation_center_y.into_pt(dpi),
-            ));
-        }
+        let (width, height) = (self.image.width, self.image.height);
+        add_xobject_to_layer(page, layer, self.image.into(), (width, height), transform);
+    }
 
-        if transform.translate_x.is_some() || transform.translate_y.is_some() {
-            transforms.push(CurTransMat::Translate(
-                transform.translate_x.unwrap_or(Mm(0.0)).into(),
-                transform.translate_y.unwrap_or(Mm(0.0)).into(),
-            ));
-        }
+    /// Same as `add_to_layer`, but the image is stored once on the document level
+    /// and the page only references it. Adding an image with the same content again,
+    /// on this or any other page, reuses the stored image
+    pub fn add_to_document(
+        self,
+        doc: &mut PdfDocument,
+        page: PdfPageIndex,
+        layer: PdfLayerIndex,
+        transform: ImageTransform,
+    ) {
+        let (width, height) = (self.image.width, self.image.height);
+        let shared = doc.add_shared_xobject(self.image.into());
+        add_xobject_to_layer(
+            doc.get_page(page),
+            layer,
+            shared,
+            (width, height),
+            transform,
+        );
+    }
+}
 
-        page.get_layer(layer).use_xobject(image, &transforms);
+fn add_xobject_to_layer(
+    page: &mut PdfPage,
+    layer: PdfLayerIndex,
+    xobject: XObject,
+    (width, height): (Px, Px),
+    transform: ImageTransform,
+) {
+    use crate::printpdf::CurTransMat;
+    use crate::printpdf::Pt;
+
+    // PDF maps an image to a 1x1 square, we have to adjust the transform matrix
+    // to fix the distortion
+    let dpi = transform.dpi.unwrap_or(300.0);
+
+    //Image at the given dpi should 1px = 1pt
+    let image_w = width.into_pt(dpi);
+    let image_h = height.into_pt(dpi);
+
+    let image = page.add_xobject(xobject);
+
+    let scale_x = transform.scale_x.unwrap_or(1.0);
+    let scale_y = transform.scale_y.unwrap_or(1.0);
+    let image_w = image_w.0 * scale_x;
+    let image_h = image_h.0 * scale_y;
+
+    let mut transforms = Vec::new();
+
+    transforms.push(CurTransMat::Scale(image_w, image_h));
+
+    if let Some(rotate) = transform.rotate.as_ref() {
+        transforms.push(CurTransMat::Translate(
+            Pt(-rotate.rotation_center_x.into_pt(dpi).0),
+            Pt(-rotate.rotation_center_y.into_pt(dpi).0),
+        ));
+        transforms.push(CurTransMat::Rotate(rotate.angle_ccw_degrees));
+        transforms.push(CurTransMat::Translate(
+            rotate.rotation_center_x.into_pt(dpi),
+            rotate.rotation_center_y.into_pt(dpi),
+        ));
     }
+
+    if transform.translate_x.is_some() || transform.translate_y.is_some() {
+        transforms.push(CurTransMat::Translate(
+            transform.translate_x.unwrap_or(Mm(0.0)).into(),
+            transform.translate_y.unwrap_or(Mm(0.0)).into(),
+        ));
+    }
+
+    page.get_layer(layer).use_xobject(image, &transforms);
 }
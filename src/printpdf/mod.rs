@@ -268,6 +268,7 @@
 
 pub use lopdf;
 
+pub mod annotation;
 pub mod color;
 pub mod ctm;
 pub mod date;
@@ -280,6 +281,7 @@ pub mod image;
 pub mod indices;
 pub mod line;
 pub mod ocg;
+pub mod page_label;
 pub mod pattern;
 pub mod pdf_conformance;
 pub mod pdf_document;
@@ -289,6 +291,7 @@ pub mod pdf_page;
 pub mod pdf_resources;
 pub mod point;
 pub mod scale;
+pub(crate) mod subsetting;
 pub mod svg;
 pub mod utils;
 pub mod xmp_metadata;
@@ -391,6 +394,8 @@ pub(crate) mod glob_defines {
     pub const OP_PATH_PAINT_END: &str = "n";
 }
 
+#[doc(inline)]
+pub use crate::printpdf::annotation::*;
 #[doc(inline)]
 pub use crate::printpdf::color::*;
 #[doc(inline)]
@@ -418,6 +423,8 @@ pub use crate::printpdf::line::*;
 #[doc(inline)]
 pub use crate::printpdf::ocg::*;
 #[doc(inline)]
+pub use crate::printpdf::page_label::*;
+#[doc(inline)]
 pub use crate::printpdf::pattern::*;
 #[doc(inline)]
 pub use crate::printpdf::pdf_conformance::*;
@@ -0,0 +1,149 @@
+//! Page labels, so that viewers can show "iv" or "12" instead of the physical page index
+
+use lopdf;
+
+/// Numbering style of a page label range
+///
+/// See PDF Reference 1.7 Page 595
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PageLabelStyle {
+    /// Decimal arabic numerals (1, 2, 3)
+    Decimal,
+    /// Uppercase roman numerals (I, II, III)
+    UpperRoman,
+    /// Lowercase roman numerals (i, ii, iii)
+    LowerRoman,
+    /// Uppercase letters (A to Z, then AA to ZZ)
+    UpperAlpha,
+    /// Lowercase letters (a to z, then aa to zz)
+    LowerAlpha,
+}
+
+impl From<PageLabelStyle> for &'static str {
+    fn from(val: PageLabelStyle) -> Self {
+        use self::PageLabelStyle::*;
+        match val {
+            Decimal => "D",
+            UpperRoman => "R",
+            LowerRoman => "r",
+            UpperAlpha => "A",
+            LowerAlpha => "a",
+        }
+    }
+}
+
+impl PageLabelStyle {
+    /// Formats the page number the same way a PDF viewer would
+    pub fn format(self, number: u32) -> String {
+        use self::PageLabelStyle::*;
+        match self {
+            Decimal => number.to_string(),
+            UpperRoman => to_roman(number),
+            LowerRoman => to_roman(number).to_lowercase(),
+            UpperAlpha => to_alpha(number),
+            LowerAlpha => to_alpha(number).to_lowercase(),
+        }
+    }
+}
+
+/// A page label range, starting at the page it is added to and ending at the
+/// next page that has a label
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PageLabel {
+    /// Numbering style. If `None`, the labels only consist of the prefix
+    pub style: Option<PageLabelStyle>,
+    /// Prefix for every label in the range
+    pub prefix: Option<String>,
+    /// Value of the numeric portion of the first label in the range. Defaults to 1
+    pub start: Option<u32>,
+}
+
+impl PageLabel {
+    /// Creates a new page label range that starts counting at 1
+    pub fn new(style: PageLabelStyle) -> Self {
+        Self {
+            style: Some(style),
+            prefix: None,
+            start: None,
+        }
+    }
+
+    /// Formats the label of the page `offset` pages into the range, the same way a PDF viewer would
+    pub fn format(&self, offset: u32) -> String {
+        let mut label = self.prefix.clone().unwrap_or_default();
+        if let Some(style) = self.style {
+            label.push_str(&style.format(self.start.unwrap_or(1) + offset));
+        }
+        label
+    }
+}
+
+impl From<PageLabel> for lopdf::Dictionary {
+    fn from(val: PageLabel) -> Self {
+        use lopdf::Object::*;
+        use lopdf::StringFormat::Literal;
+
+        let mut dict = lopdf::Dictionary::from_iter(vec![("Type", Name("PageLabel".into()))]);
+
+        if let Some(style) = val.style {
+            let s: &'static str = style.into();
+            dict.set("S", Name(s.into()));
+        }
+
+        if let Some(prefix) = val.prefix {
+            dict.set("P", String(prefix.into(), Literal));
+        }
+
+        if let Some(start) = val.start {
+            dict.set("St", Integer(start as i64));
+        }
+
+        dict
+    }
+}
+
+fn to_roman(mut number: u32) -> String {
+    const NUMERALS: [(u32, &str); 13] = [
+        (1000, "M"),
+        (900, "CM"),
+        (500, "D"),
+        (400, "CD"),
+        (100, "C"),
+        (90, "XC"),
+        (50, "L"),
+        (40, "XL"),
+        (10, "X"),
+        (9, "IX"),
+        (5, "V"),
+        (4, "IV"),
+        (1, "I"),
+    ];
+
+    let mut out = String::new();
+    for (value, numeral) in NUMERALS {
+        while number >= value {
+            out.push_str(numeral);
+            number -= value;
+        }
+    }
+    out
+}
+
+// PDF letters repeat rather than carry: 26 = "Z", 27 = "AA", 53 = "AAA"
+fn to_alpha(number: u32) -> String {
+    if number == 0 {
+        return String::new();
+    }
+    let letter = char::from(b'A' + ((number - 1) % 26) as u8);
+    let count = (number - 1) / 26 + 1;
+    std::iter::repeat(letter).take(count as usize).collect()
+}
+
+#[test]
+fn test_page_label_format() {
+    assert_eq!(PageLabelStyle::LowerRoman.format(4), "iv");
+    assert_eq!(PageLabelStyle::UpperRoman.format(1994), "MCMXCIV");
+    assert_eq!(PageLabelStyle::Decimal.format(12), "12");
+    assert_eq!(PageLabelStyle::UpperAlpha.format(26), "Z");
+    assert_eq!(PageLabelStyle::LowerAlpha.format(28), "bb");
+}
@@ -1,14 +1,16 @@
 //! A `PDFDocument` represents the whole content of the file
 
+use crate::printpdf::subsetting::{for_each_glyph_string, glyph_ids};
 use crate::printpdf::utils::random_character_string_32;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 
 use crate::printpdf::OffsetDateTime;
 use lopdf;
 
 use crate::printpdf::indices::*;
 use crate::printpdf::{
-    Error, ExternalFont, IccProfileList, Mm, PdfConformance, PdfMetadata, PdfPage,
+    Error, ExternalFont, FontInstance, IccProfileList, Mm, PageLabel, PdfConformance, PdfMetadata,
+    PdfPage, XObject,
 };
 
 /// PDF document
@@ -28,6 +30,12 @@ pub struct PdfDocument {
     pub metadata: PdfMetadata,
     /// The bookmarks in the document. A HashMap<Page Number, Bookmark Name>
     pub bookmarks: HashMap<usize, String>,
+    /// The page label ranges in the document. A BTreeMap<First page number, PageLabel>
+    pub page_labels: BTreeMap<usize, PageLabel>,
+    /// XObjects stored once for the whole document. A HashMap<Content key, Object ID>
+    pub(super) shared_xobjects: HashMap<Vec<u8>, lopdf::ObjectId>,
+    /// Whether pages are read from right to left, as in vertically written Japanese
+    pub right_to_left: bool,
 }
 
 // /// Marker struct for a document. Used to make the API a bit nicer.
@@ -60,6 +68,9 @@ impl PdfDocument {
             inner_doc: lopdf::Document::with_version("1.3"),
             metadata: PdfMetadata::new(document_title, 1, false, PdfConformance::default()),
             bookmarks: HashMap::new(),
+            page_labels: BTreeMap::new(),
+            shared_xobjects: HashMap::new(),
+            right_to_left: false,
         };
 
         let (initial_page, layer_index) = PdfPage::new(
@@ -83,6 +94,9 @@ impl PdfDocument {
             inner_doc: lopdf::Document::with_version("1.3"),
             metadata: PdfMetadata::new(document_title, 1, false, PdfConformance::X3_2002_PDF_1_3),
             bookmarks: HashMap::new(),
+            page_labels: BTreeMap::new(),
+            shared_xobjects: HashMap::new(),
+            right_to_left: false,
         }
     }
 }
@@ -160,6 +174,14 @@ impl PdfDocument {
         self
     }
 
+    /// Sets whether pages are read from right to left, so that viewers show the
+    /// next page to the left in two page spreads
+    #[inline]
+    pub fn with_right_to_left(mut self, right_to_left: bool) -> Self {
+        self.right_to_left = right_to_left;
+        self
+    }
+
     /// Set the trapping of the document
     #[inline]
     pub fn with_trapping(mut self, trapping: bool) -> Self {
@@ -244,6 +266,32 @@ impl PdfDocument {
         self.bookmarks.insert(page.0, name.into());
     }
 
+    /// Starts a new page label range at the given page.
+    /// If the page already starts a range, overwrites it.
+    #[inline]
+    pub fn add_page_label(&mut self, label: PageLabel, page: PdfPageIndex) {
+        self.page_labels.insert(page.0, label);
+    }
+
+    /// Adds an XObject to the document instead of a single page, so that it can be
+    /// shared between pages. XObjects with identical contents are only stored once.
+    ///
+    /// Add the returned `XObject::Shared` to every page that uses it, with `PdfPage::add_xobject`
+    pub fn add_shared_xobject(&mut self, xobj: XObject) -> XObject {
+        if let XObject::Shared(_) = xobj {
+            return xobj;
+        }
+
+        let id = *self
+            .shared_xobjects
+            .entry(xobj.content_key())
+            .or_insert_with(|| {
+                let obj = xobj.into_with_document(&mut self.inner_doc);
+                self.inner_doc.add_object(obj)
+            });
+        XObject::Shared(id)
+    }
+
     /// Returns the page (for inserting content)
     #[inline]
     pub fn get_page(&mut self, page: PdfPageIndex) -> &mut PdfPage {
@@ -269,7 +317,8 @@ impl PdfDocument {
     /// Save PDF document to bytes
     pub fn save_to_bytes(
         self,
-        fonts: impl IntoIterator<Item = cosmic_text::fontdb::ID>,
+        fonts: impl IntoIterator<Item = FontInstance>,
+        glyph_text: &HashMap<cosmic_text::fontdb::ID, HashMap<u16, String>>,
         db: &mut cosmic_text::FontSystem,
     ) -> Result<Vec<u8>, Error> {
         use lopdf::Object::*;
@@ -345,6 +394,30 @@ impl PdfDocument {
             catalog.set("Metadata", Reference(metadata_id));
         }
 
+        if doc.right_to_left {
+            catalog.set(
+                "ViewerPreferences",
+                Dictionary(LoDictionary::from_iter(vec![(
+                    "Direction",
+                    Name("R2L".into()),
+                )])),
+            );
+        }
+
+        // page label number tree, keyed by the index of the first page in each range
+        if !doc.page_labels.is_empty() {
+            let nums = std::mem::take(&mut doc.page_labels)
+                .into_iter()
+                .flat_map(|(page_index, label)| {
+                    [Integer(page_index as i64), Dictionary(label.into())]
+                })
+                .collect();
+            catalog.set(
+                "PageLabels",
+                Dictionary(LoDictionary::from_iter(vec![("Nums", Array(nums))])),
+            );
+        }
+
         let mut pages = LoDictionary::from_iter(vec![
             ("Type", "Pages".into()),
             ("Count", Integer(doc.pages.len() as i64)),
@@ -445,28 +518,66 @@ impl PdfDocument {
 
         let mut font_dict = lopdf::Dictionary::new();
 
-        for id in fonts {
-            let font = &*db.get_font(id).unwrap();
-            let face_info = db.db().face(id).unwrap().clone();
-            let name = face_info.post_script_name.clone();
-            let font = ExternalFont { font, face_info };
+        // the glyphs written with each font, by font name, so that only those are embedded
+        let mut used_glyphs = HashMap::<Vec<u8>, HashSet<u16>>::new();
+        for layer in doc.pages.iter_mut().flat_map(|page| &mut page.layers) {
+            for_each_glyph_string(&mut layer.operations, |font, glyphs| {
+                let used = used_glyphs.entry(font.to_vec()).or_default();
+                used.extend(glyph_ids(glyphs));
+            });
+        }
 
-            if let Some(font_dict_collected) = font.into_with_document(&mut doc.inner_doc) {
+        let mut gid_mappings = HashMap::new();
+        for instance in fonts {
+            let font = &*db.get_font(instance.id).unwrap();
+            let mut face_info = db.db().face(instance.id).unwrap().clone();
+            face_info.post_script_name = instance.name(&face_info.post_script_name);
+            let name = face_info.post_script_name.clone();
+            let used_glyphs = used_glyphs.remove(name.as_bytes()).unwrap_or_default();
+            let font = ExternalFont {
+                font,
+                face_info,
+                used_glyphs,
+                glyph_text: glyph_text.get(&instance.id).cloned().unwrap_or_default(),
+                variations: instance.variations,
+                vertical: instance.vertical,
+            };
+
+            if let Some(embedded) = font.into_with_document(&mut doc.inner_doc) {
                 let inner_obj = doc.inner_doc.new_object_id();
                 doc.inner_doc
                     .objects
-                    .insert(inner_obj, Dictionary(font_dict_collected));
+                    .insert(inner_obj, Dictionary(embedded.dictionary));
+                gid_mappings.insert(name.clone().into_bytes(), embedded.gid_mapping);
                 font_dict.set(name, Reference(inner_obj));
             }
         }
 
+        // the content streams use the glyph IDs of the subset fonts
+        for layer in doc.pages.iter_mut().flat_map(|page| &mut page.layers) {
+            for_each_glyph_string(&mut layer.operations, |font, glyphs| {
+                if let Some(mapping) = gid_mappings.get(font) {
+                    *glyphs = glyph_ids(glyphs)
+                        .flat_map(|gid| mapping.get(&gid).copied().unwrap_or(0).to_be_bytes())
+                        .collect();
+                }
+            });
+        }
+
         if !font_dict.is_empty() {
             font_dict_id = Some(doc.inner_doc.add_object(Dictionary(font_dict)));
         }
 
         let mut page_id_to_obj: HashMap<usize, (u32, u16)> = HashMap::new();
 
-        for (idx, page) in doc.pages.into_iter().enumerate() {
+        // page object IDs are allocated up front, so that links can point to later pages
+        let page_object_ids: Vec<lopdf::ObjectId> = doc
+            .pages
+            .iter()
+            .map(|_| doc.inner_doc.new_object_id())
+            .collect();
+
+        for (idx, mut page) in doc.pages.into_iter().enumerate() {
             let mut p = LoDictionary::from_iter(vec![
                 ("Type", "Page".into()),
                 ("Rotate", Integer(0)),
@@ -485,6 +596,14 @@ impl PdfDocument {
                 ("Parent", Reference(pages_id)),
             ]);
 
+            if !page.annotations.is_empty() {
+                let annotations = std::mem::take(&mut page.annotations)
+                    .into_iter()
+                    .map(|annotation| Dictionary(annotation.into_dictionary(&page_object_ids)))
+                    .collect();
+                p.set("Annots", Array(annotations));
+            }
+
             // this will collect the resources needed for rendering this page
             let layers_temp = ocg_list.iter().find(|e| e.0 == idx).unwrap();
             let (mut resources_page, layer_streams) =
@@ -510,7 +629,8 @@ impl PdfDocument {
             let page_content_id = doc.inner_doc.add_object(merged_layer_stream);
 
             p.set("Contents", Reference(page_content_id));
-            let page_obj = doc.inner_doc.add_object(p);
+            let page_obj = page_object_ids[idx];
+            doc.inner_doc.objects.insert(page_obj, Dictionary(p));
             if doc.bookmarks.contains_key(&idx) {
                 page_id_to_obj.insert(idx, page_obj);
             }
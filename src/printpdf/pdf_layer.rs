@@ -270,6 +270,58 @@ impl PdfLayer {
             .push(Operation::new("Tj", vec![String(bytes, Literal)]));
     }
 
+    /// Add text to the file at the current position like `write_codepoints`, moving the
+    /// glyph after each one back by its adjustment, in thousandths of an em, with `TJ`
+    pub fn write_positioned_codepoints<I>(&mut self, codepoints: I)
+    where
+        I: IntoIterator<Item = (u16, f32)>,
+    {
+        use lopdf::Object::*;
+        use lopdf::StringFormat::Literal;
+
+        let mut array = vec![];
+        let mut bytes = vec![];
+        for (codepoint, adjustment) in codepoints {
+            bytes.extend(codepoint.to_be_bytes());
+            if adjustment.abs() >= 0.01 {
+                array.push(String(std::mem::take(&mut bytes), Literal));
+                array.push(Real(adjustment));
+            }
+        }
+        if !bytes.is_empty() {
+            array.push(String(bytes, Literal));
+        }
+
+        self.operations
+            .push(Operation::new("TJ", vec![Array(array)]));
+    }
+
+    /// Begins a marked content span with the text that the following glyphs
+    /// stand for, used for copying and searching instead of the font's
+    /// `ToUnicode` map. Must be ended with `end_marked_content`
+    pub fn begin_actual_text(&mut self, text: &str) {
+        use lopdf::Object::*;
+        use lopdf::StringFormat::Hexadecimal;
+
+        // UTF-16BE, with a byte order mark
+        let bytes = [0xFE, 0xFF]
+            .into_iter()
+            .chain(text.encode_utf16().flat_map(u16::to_be_bytes))
+            .collect();
+        let properties =
+            lopdf::Dictionary::from_iter(vec![("ActualText", String(bytes, Hexadecimal))]);
+        self.operations.push(Operation::new(
+            "BDC",
+            vec![Name("Span".into()), Dictionary(properties)],
+        ));
+    }
+
+    /// Ends a marked content span
+    #[inline]
+    pub fn end_marked_content(&mut self) {
+        self.add_operation(Operation::new("EMC", Vec::new()));
+    }
+
     /// Saves the current graphic state
     #[inline]
     pub fn save_graphics_state(&mut self) {
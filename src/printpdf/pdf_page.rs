@@ -4,8 +4,8 @@ use lopdf;
 
 use crate::printpdf::indices::PdfLayerIndex;
 use crate::printpdf::{
-    ExtendedGraphicsState, ExtendedGraphicsStateRef, Mm, Pattern, PatternRef, PdfLayer,
-    PdfResources, Pt, XObject, XObjectRef,
+    ExtendedGraphicsState, ExtendedGraphicsStateRef, LinkAnnotation, Mm, Pattern, PatternRef,
+    PdfLayer, PdfResources, Pt, XObject, XObjectRef,
 };
 
 /// PDF page
@@ -21,6 +21,8 @@ pub struct PdfPage {
     pub layers: Vec<PdfLayer>,
     /// Resources used in this page
     pub(crate) resources: PdfResources,
+    /// Links on this page
+    pub(crate) annotations: Vec<LinkAnnotation>,
 }
 
 // /// A "reference" to the current page, allows for inner mutability
@@ -46,6 +48,7 @@ impl PdfPage {
             height: height.into(),
             layers: Vec::new(),
             resources: PdfResources::new(),
+            annotations: Vec::new(),
         };
 
         let initial_layer = PdfLayer::new(layer_name);
@@ -133,6 +136,12 @@ impl PdfPage {
         self.resources.add_pattern(pattern)
     }
 
+    /// Adds a clickable link to the page
+    #[inline]
+    pub fn add_link(&mut self, annotation: LinkAnnotation) {
+        self.annotations.push(annotation);
+    }
+
     /// __STUB__: Adds an XObject to the pages resources.
     /// __NOTE__: Watch out for scaling. Your XObject might be invisible or only 1pt x 1pt big
     #[inline]
@@ -2,9 +2,10 @@ use allsorts::{
     binary::read::ReadScope,
     font::read_cmap_subtable,
     font_data::FontData,
-    tables::{cmap::Cmap, FontTableProvider},
+    tables::{cmap::Cmap, Fixed, FontTableProvider},
     tag,
 };
+use lopdf::content::Operation;
 use std::{
     collections::{HashMap, HashSet},
     error::Error,
@@ -28,11 +29,10 @@ pub(crate) fn subset(
         read_cmap_subtable(&cmap)?.ok_or(allsorts::error::ParseError::MissingValue)?;
 
     // Prevent `allsorts` from using MacRoman encoding by using a non supported character
-    let gid_eur = cmap_subtable
-        .map_glyph('€' as u32)?
-        .ok_or(allsorts::error::ParseError::MissingValue)?;
+    if let Some(gid_eur) = cmap_subtable.map_glyph('€' as u32)? {
+        used_glyphs.insert(gid_eur);
+    }
     used_glyphs.insert(0);
-    used_glyphs.insert(gid_eur);
 
     let mut glyph_ids: Vec<u16> = used_glyphs.iter().copied().collect();
 
@@ -50,3 +50,124 @@ pub(crate) fn subset(
         gid_mapping,
     })
 }
+
+/// Instances a variable font at the axis values, given in the order of its `fvar` axes
+pub(crate) fn instance(font_bytes: &[u8], values: &[f32]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let font_file = ReadScope::new(font_bytes).read::<FontData<'_>>()?;
+    let provider = font_file.table_provider(0)?;
+    let user_instance: Vec<Fixed> = values.iter().map(|&value| Fixed::from(value)).collect();
+    // the tuple of normalized coordinates that comes with the font isn't needed
+    let (instance, _) = allsorts::variations::instance(&provider, &user_instance)?;
+    Ok(instance)
+}
+
+/// The six letter tag that marks a subset font, as in `ABCDEF+PTSerif-Regular`.
+///
+/// Derived from the font and its glyphs with 64-bit FNV-1a, which unlike `DefaultHasher`
+/// never changes between Rust releases, so that rebuilding the same book gives the same names
+pub(crate) fn subset_tag(font_name: &str, glyphs: &HashSet<u16>) -> String {
+    let mut glyphs: Vec<u16> = glyphs.iter().copied().collect();
+    glyphs.sort_unstable();
+
+    // the name can't hold a 0xFF byte, so it ends where the glyphs start
+    let bytes = font_name
+        .bytes()
+        .chain([0xFF])
+        .chain(glyphs.iter().flat_map(|glyph| glyph.to_be_bytes()));
+    let mut hash = bytes.fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    });
+
+    (0..6)
+        .map(|_| {
+            let letter = b'A' + (hash % 26) as u8;
+            hash /= 26;
+            letter as char
+        })
+        .collect()
+}
+
+/// Calls `f` with the name of the current font and the glyph IDs of every `Tj` string and
+/// `TJ` array string in the content stream, as written by `PdfLayer::write_codepoints` and
+/// `PdfLayer::write_positioned_codepoints`
+pub(crate) fn for_each_glyph_string(
+    operations: &mut [Operation],
+    mut f: impl FnMut(&[u8], &mut Vec<u8>),
+) {
+    let mut font = Vec::new();
+    for operation in operations {
+        match (
+            operation.operator.as_str(),
+            operation.operands.as_mut_slice(),
+        ) {
+            ("Tf", [lopdf::Object::Name(name), ..]) => font = name.clone(),
+            ("Tj", [lopdf::Object::String(glyphs, _)]) => f(&font, glyphs),
+            ("TJ", [lopdf::Object::Array(array)]) => {
+                for element in array {
+                    if let lopdf::Object::String(glyphs, _) = element {
+                        f(&font, glyphs)
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The big-endian glyph IDs of a `Tj` string
+pub(crate) fn glyph_ids(glyphs: &[u8]) -> impl Iterator<Item = u16> + '_ {
+    glyphs
+        .chunks_exact(2)
+        .map(|c| u16::from_be_bytes([c[0], c[1]]))
+}
+
+#[test]
+fn test_for_each_glyph_string() {
+    use lopdf::{Object, StringFormat};
+
+    let mut operations = vec![
+        Operation::new("Tf", vec!["PTSerif-Regular".into(), 12.into()]),
+        Operation::new(
+            "Tj",
+            vec![Object::String(vec![0, 5, 1, 2], StringFormat::Literal)],
+        ),
+        Operation::new(
+            "TJ",
+            vec![Object::Array(vec![
+                Object::String(vec![0, 5], StringFormat::Literal),
+                Object::Real(-12.5),
+                Object::String(vec![1, 2], StringFormat::Literal),
+            ])],
+        ),
+    ];
+    let mapping: HashMap<u16, u16> = HashMap::from([(5, 1), (258, 2)]);
+    for_each_glyph_string(&mut operations, |font, glyphs| {
+        assert_eq!(font, b"PTSerif-Regular");
+        *glyphs = glyph_ids(glyphs)
+            .flat_map(|gid| mapping[&gid].to_be_bytes())
+            .collect();
+    });
+    match &operations[1].operands[0] {
+        Object::String(glyphs, _) => assert_eq!(glyphs, &[0, 1, 0, 2]),
+        _ => unreachable!(),
+    }
+    // only the strings of a `TJ` array are glyphs
+    match &operations[2].operands[0] {
+        Object::Array(array) => match array.as_slice() {
+            [Object::String(first, _), Object::Real(_), Object::String(second, _)] => {
+                assert_eq!(first, &[0, 1]);
+                assert_eq!(second, &[0, 2]);
+            }
+            _ => unreachable!(),
+        },
+        _ => unreachable!(),
+    }
+
+    let glyphs = HashSet::from([1, 2, 3]);
+    let tag = subset_tag("PTSerif-Regular", &glyphs);
+    assert_eq!(tag.len(), 6);
+    assert!(tag.bytes().all(|b| b.is_ascii_uppercase()));
+    assert_eq!(tag, subset_tag("PTSerif-Regular", &glyphs));
+    // the tag is fixed by the font and glyphs alone
+    assert_eq!(tag, "AGDUOS");
+}
@@ -5,11 +5,11 @@ use crate::printpdf::{Pt, Px, XObject, XObjectRef};
 use lopdf::{Object, Stream};
 use std::{error, fmt};
 
-use super::{PdfLayer, PdfLayerIndex, PdfPage};
+use super::{PdfDocument, PdfLayer, PdfLayerIndex, PdfPage, PdfPageIndex};
 
 /// SVG - wrapper around an `XObject` to allow for more
 /// control within the library
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Svg {
     /// The PDF document, converted from SVG using svg2pdf
     svg_xobject: Stream,
@@ -17,6 +17,30 @@ pub struct Svg {
     pub width: Px,
     /// Height of the rendered SVG content
     pub height: Px,
+    /// Text elements of the SVG. svg2pdf converts text to paths, so these
+    /// are kept around to overlay as invisible (but selectable) text
+    pub texts: Vec<SvgText>,
+}
+
+/// A run of text within an SVG, positioned in the same pixel space as `width` and `height`
+#[derive(Debug, Clone, PartialEq)]
+pub struct SvgText {
+    pub text: String,
+    /// Position of the start of the baseline, from the top-left of the SVG
+    pub x: f32,
+    pub y: f32,
+    pub font_size: f32,
+    pub anchor: SvgTextAnchor,
+    /// First family of the `font-family`, if it names one rather than a generic family
+    pub font_family: Option<String>,
+    pub bold: bool,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SvgTextAnchor {
+    Start,
+    Middle,
+    End,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -63,7 +87,7 @@ pub struct SvgRotation {
     pub rotation_center_y: Pt,
 }
 
-fn export_svg_to_xobject_pdf(svg: &str) -> Result<Stream, String> {
+fn export_svg_to_xobject_pdf(svg: &str, options: &usvg::Options) -> Result<Stream, String> {
     use pdf_writer::{Content, Finish, Name, PdfWriter, Rect, Ref};
 
     // Allocate the indirect reference IDs and names.
@@ -94,8 +118,8 @@ fn export_svg_to_xobject_pdf(svg: &str) -> Result<Stream, String> {
 
     // Let's add an SVG graphic to this file.
     // We need to load its source first and manually parse it into a usvg Tree.
-    let tree = usvg::Tree::from_str(svg, &usvg::Options::default().to_ref())
-        .map_err(|err| format!("usvg parse: {err}"))?;
+    let tree =
+        usvg::Tree::from_str(svg, &options.to_ref()).map_err(|err| format!("usvg parse: {err}"))?;
 
     // Then, we will write it to the page as the 6th indirect object.
     //
@@ -178,9 +202,21 @@ impl Svg {
     /// (using lopdf), then extracts the SVG XObject.
     ///
     /// I wish there was a more direct way, but handling SVG is very tricky.
+    ///
+    /// Text elements are skipped, since the default options have no fonts.
+    /// Use [`Svg::parse_with_options`] to supply a font database.
     pub fn parse(svg_string: &str) -> Result<Self, SvgParseError> {
+        Self::parse_with_options(svg_string, &usvg::Options::default())
+    }
+
+    /// Same as [`Svg::parse`], but with the given usvg options,
+    /// eg to render the text with the fonts in `options.fontdb`
+    pub fn parse_with_options(
+        svg_string: &str,
+        options: &usvg::Options,
+    ) -> Result<Self, SvgParseError> {
         // SVG -> PDF bytes
-        let svg_xobject = export_svg_to_xobject_pdf(svg_string).map_err(|err| {
+        let svg_xobject = export_svg_to_xobject_pdf(svg_string, options).map_err(|err| {
             SvgParseError::Svg2PdfConversionError(format!("create xobject from svg: {err}"))
         })?;
 
@@ -217,10 +253,16 @@ impl Svg {
             )),
         }?;
 
+        let texts =
+            extract_texts(svg_string, width_px as f32, height_px as f32).map_err(|err| {
+                SvgParseError::Svg2PdfConversionError(format!("find svg text: {err}"))
+            })?;
+
         Ok(Self {
             svg_xobject,
             width: Px(width_px.max(0) as usize),
             height: Px(height_px.max(0) as usize),
+            texts,
         })
     }
 
@@ -251,4 +293,208 @@ impl Svg {
         let xobject = self.into_xobject(page);
         xobject.add_to_layer(page.get_layer(layer), transform);
     }
+
+    /// Same as `add_to_layer`, but the SVG is stored once on the document level
+    /// and the page only references it. Adding an SVG with the same content again,
+    /// on this or any other page, reuses the stored XObject
+    pub fn add_to_document(
+        self,
+        doc: &mut PdfDocument,
+        page: PdfPageIndex,
+        layer: PdfLayerIndex,
+        transform: SvgTransform,
+    ) {
+        let shared = doc.add_shared_xobject(XObject::External(self.svg_xobject));
+        let page = doc.get_page(page);
+        let xobject = SvgXObjectRef {
+            xobject_ref: page.add_xobject(shared),
+            width: self.width,
+            height: self.height,
+        };
+        xobject.add_to_layer(page.get_layer(layer), transform);
+    }
+}
+
+/// Finds the text elements in the SVG, mapped from the viewBox into the
+/// `width` by `height` pixel space of the converted XObject.
+///
+/// svg2pdf only gives back the outlines, so this reads the text from the SVG itself
+/// and can only place simple text. Text is skipped rather than overlaid in the wrong
+/// place if it is under a transform other than `translate`, in a nested `<svg>` or
+/// on a `<textPath>`, if it or any `<tspan>` in it has its own position or rotation,
+/// or if its position or font size is in a unit other than `px`
+fn extract_texts(svg: &str, width: f32, height: f32) -> Result<Vec<SvgText>, roxmltree::Error> {
+    let document = roxmltree::Document::parse(svg)?;
+    let root = document.root_element();
+
+    let view_box: Vec<f32> = root
+        .attribute("viewBox")
+        .map(|v| {
+            v.split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|s| !s.is_empty())
+                .filter_map(|s| s.parse().ok())
+                .collect()
+        })
+        .unwrap_or_default();
+    let (min_x, min_y, scale_x, scale_y) = match view_box.as_slice() {
+        &[x, y, w, h] if w > 0.0 && h > 0.0 => (x, y, width / w, height / h),
+        _ => (0.0, 0.0, 1.0, 1.0),
+    };
+
+    let mut texts = vec![];
+    for node in root.descendants().filter(|n| n.has_tag_name("text")) {
+        let Some((dx, dy)) = translation(node, root) else {
+            continue;
+        };
+        let positioned = node.descendants().skip(1).any(|n| {
+            n.has_tag_name("textPath")
+                || ["x", "y", "dx", "dy", "rotate"]
+                    .iter()
+                    .any(|&a| n.attribute(a).is_some())
+        });
+        if positioned
+            || ["dx", "dy", "rotate"]
+                .iter()
+                .any(|&a| node.attribute(a).is_some())
+        {
+            continue;
+        }
+
+        let position = |name| node.attribute(name).map_or(Some(0.0), parse_length);
+        let font_size = property(node, "font-size").map_or(Some(12.0), parse_length);
+        let (Some(x), Some(y), Some(font_size)) = (position("x"), position("y"), font_size) else {
+            continue;
+        };
+        let anchor = match property(node, "text-anchor") {
+            Some("middle") => SvgTextAnchor::Middle,
+            Some("end") => SvgTextAnchor::End,
+            _ => SvgTextAnchor::Start,
+        };
+        let font_family = property(node, "font-family")
+            .and_then(|families| families.split(',').next())
+            .map(|family| family.trim().trim_matches(|c| c == '\'' || c == '"'))
+            .filter(|family| {
+                !["serif", "sans-serif", "monospace", "cursive", "fantasy"].contains(family)
+            })
+            .map(str::to_owned);
+        let bold = match property(node, "font-weight") {
+            Some("bold" | "bolder") => true,
+            Some(weight) => weight.parse::<u16>().map_or(false, |weight| weight >= 600),
+            None => false,
+        };
+
+        let text: String = node
+            .descendants()
+            .filter(|n| n.is_text())
+            .filter_map(|n| n.text())
+            .collect();
+        let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+        if text.is_empty() {
+            continue;
+        }
+
+        texts.push(SvgText {
+            text,
+            x: (x + dx - min_x) * scale_x,
+            y: (y + dy - min_y) * scale_y,
+            font_size: font_size * scale_y,
+            anchor,
+            font_family,
+            bold,
+        });
+    }
+
+    Ok(texts)
+}
+
+/// the offset of the node from the root, if every transform from it up to the root
+/// is a `translate` and it isn't in a nested `<svg>`
+fn translation(node: roxmltree::Node, root: roxmltree::Node) -> Option<(f32, f32)> {
+    let mut offset = (0.0, 0.0);
+    for ancestor in node.ancestors().filter(|n| n.is_element()) {
+        if ancestor != root && ancestor.has_tag_name("svg") {
+            return None;
+        }
+        if let Some(transform) = ancestor.attribute("transform") {
+            let (x, y) = parse_translate(transform)?;
+            offset = (offset.0 + x, offset.1 + y);
+        }
+    }
+    Some(offset)
+}
+
+/// the value of an inherited property, from the nearest element that sets it as an
+/// attribute or in its `style`
+fn property<'a>(node: roxmltree::Node<'a, '_>, name: &str) -> Option<&'a str> {
+    node.ancestors().filter(|n| n.is_element()).find_map(|n| {
+        let style = n.attribute("style").and_then(|style| {
+            style.split(';').find_map(|declaration| {
+                let (key, value) = declaration.split_once(':')?;
+                (key.trim() == name).then(|| value.trim())
+            })
+        });
+        style.or_else(|| n.attribute(name))
+    })
+}
+
+/// parses `translate(x)` or `translate(x, y)`
+fn parse_translate(transform: &str) -> Option<(f32, f32)> {
+    let args = transform
+        .trim()
+        .strip_prefix("translate(")?
+        .strip_suffix(')')?;
+    let mut args = args
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|s| !s.is_empty())
+        .map(|s| s.parse::<f32>().ok());
+    let x = args.next()??;
+    let y = args.next().unwrap_or(Some(0.0))?;
+    Some((x, y))
+}
+
+/// parses a single number with an optional `px` unit. Other units, and lists of
+/// positions, are not supported
+fn parse_length(length: &str) -> Option<f32> {
+    length.trim().trim_end_matches("px").parse().ok()
+}
+
+#[test]
+fn test_extract_texts() {
+    let svg = r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 100 50">
+        <g transform="translate(10, 5)" font-size="8" font-family="'PT Sans', sans-serif">
+            <text x="5" y="10" text-anchor="middle">Event <tspan>loop</tspan></text>
+            <text x="5px" y="20" style="font-size: 6px; font-weight: bold">Waker</text>
+        </g>
+        <text transform="rotate(90)">skipped</text>
+        <text transform="matrix(1 0 0 1 5 5)">skipped</text>
+        <text x="5" y="10" font-size="1.5em">skipped</text>
+        <text x="5 10 15" y="10">skipped</text>
+        <text x="5" y="10">skipped <tspan dy="4">too</tspan></text>
+        <svg x="10" viewBox="0 0 10 10"><text>skipped</text></svg>
+    </svg>"#;
+
+    let texts = extract_texts(svg, 200.0, 100.0).unwrap();
+    assert_eq!(
+        texts,
+        vec![
+            SvgText {
+                text: "Event loop".to_owned(),
+                x: 30.0,
+                y: 30.0,
+                font_size: 16.0,
+                anchor: SvgTextAnchor::Middle,
+                font_family: Some("PT Sans".to_owned()),
+                bold: false,
+            },
+            SvgText {
+                text: "Waker".to_owned(),
+                x: 30.0,
+                y: 50.0,
+                font_size: 12.0,
+                anchor: SvgTextAnchor::Start,
+                font_family: Some("PT Sans".to_owned()),
+                bold: true,
+            },
+        ]
+    );
 }
@@ -1,9 +1,9 @@
 use crate::printpdf::OffsetDateTime;
-use crate::printpdf::{ColorBits, ColorSpace, CurTransMat, Px};
+use crate::printpdf::{ColorBits, ColorSpace, CurTransMat, IccProfile, IccProfileType, Px};
 use image::{DynamicImage, GenericImageView, ImageDecoder, ImageError};
 use lopdf;
 use lopdf::Stream as LoPdfStream;
-use std::collections::HashMap;
+use std::{borrow::Cow, collections::HashMap};
 
 /* Parent: Resources dictionary of the page */
 /// External object that gets reference outside the PDF content stream
@@ -32,6 +32,11 @@ pub enum XObject {
     /// by `add_xobject()` is the unique name that can be used to invoke
     /// the `/Do` operator (by the `use_xobject`)
     External(LoPdfStream),
+    /// XObject that lives on the document level, shared between pages.
+    ///
+    /// Created by `PdfDocument::add_shared_xobject()`. The page /Resources
+    /// only hold a reference to the object.
+    Shared(lopdf::ObjectId),
 }
 
 impl From<ImageXObject> for XObject {
@@ -46,6 +51,47 @@ impl XObject {
         let _ = stream.compress();
         stream
     }
+
+    /// Same as `Into<lopdf::Object>`, but images may need to add
+    /// their soft mask to the document first
+    pub fn into_with_document(self, doc: &mut lopdf::Document) -> lopdf::Object {
+        match self {
+            XObject::Image(image) => lopdf::Object::Stream(image.into_with_document(doc)),
+            object => object.into(),
+        }
+    }
+
+    /// The contents as bytes, used to store identical XObjects only once. Two XObjects
+    /// with the same key are written the same way
+    pub fn content_key(&self) -> Vec<u8> {
+        use std::hash::Hash;
+
+        let mut key = ContentKey(vec![]);
+        match self {
+            XObject::Image(image) => image.hash_content(&mut key),
+            XObject::External(stream) => {
+                format!("{:?}", stream.dict).hash(&mut key);
+                stream.content.hash(&mut key);
+            }
+            XObject::Shared(id) => id.hash(&mut key),
+            other => format!("{other:?}").hash(&mut key),
+        }
+        key.0
+    }
+}
+
+/// A `Hasher` that keeps every byte written to it instead of hashing them, so that
+/// contents can be compared in full rather than by a hash that might collide
+struct ContentKey(Vec<u8>);
+
+impl std::hash::Hasher for ContentKey {
+    fn finish(&self) -> u64 {
+        unreachable!("content keys are compared by their bytes")
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.extend_from_slice(bytes);
+    }
 }
 
 impl From<XObject> for lopdf::Object {
@@ -58,6 +104,7 @@ impl From<XObject> for lopdf::Object {
             }
             XObject::PostScript(ps) => lopdf::Object::Stream(XObject::compress_stream(ps.into())),
             XObject::External(stream) => lopdf::Object::Stream(XObject::compress_stream(stream)),
+            XObject::Shared(id) => lopdf::Object::Reference(id),
         }
     }
 }
@@ -91,10 +138,14 @@ impl XObjectList {
     pub fn into_with_document(self, doc: &mut lopdf::Document) -> lopdf::Dictionary {
         self.objects
             .into_iter()
-            .map(|(name, object)| {
-                let obj: lopdf::Object = object.into();
-                let obj_ref = doc.add_object(obj);
-                (name, lopdf::Object::Reference(obj_ref))
+            .map(|(name, object)| match object {
+                // already added to the document
+                XObject::Shared(id) => (name, lopdf::Object::Reference(id)),
+                object => {
+                    let obj = object.into_with_document(doc);
+                    let obj_ref = doc.add_object(obj);
+                    (name, lopdf::Object::Reference(obj_ref))
+                }
             })
             .collect()
     }
@@ -135,6 +186,10 @@ pub struct ImageXObject {
     pub image_data: Vec<u8>,
     /// Decompression filter for `image_data`, if `None` assumes uncompressed raw pixels in the expected color format.
     pub image_filter: Option<ImageFilter>,
+    /// Greyscale image with the alpha channel, written as the `/SMask` of this image
+    pub smask: Option<Box<ImageXObject>>,
+    /// Embedded color profile, written as an `/ICCBased` color space
+    pub icc_profile: Option<IccProfile>,
     /* /BBox << dictionary >> */
     /* todo: find out if this is really required */
     /// Required bounds to clip the image, in unit space
@@ -166,56 +221,353 @@ impl ImageXObject {
     //     }
     // }
 
-    pub fn try_from<'a, T: ImageDecoder<'a>>(image: T) -> Result<Self, ImageError> {
-        use image::error::{LimitError, LimitErrorKind};
-        use std::usize;
+    /// Decodes the image, keeping any embedded ICC profile
+    pub fn try_from<'a, T: ImageDecoder<'a>>(mut image: T) -> Result<Self, ImageError> {
+        let icc_profile = image.icc_profile();
+        let image = DynamicImage::from_decoder(image)?;
 
-        let dim = image.dimensions();
-        let color_type = image.color_type();
-        let num_image_bytes = image.total_bytes();
+        let image = Self::from_dynamic_image(&image);
+        Ok(match icc_profile {
+            Some(icc) => image.with_icc_profile(icc),
+            None => image,
+        })
+    }
 
-        if num_image_bytes > usize::MAX as u64 {
-            return Err(ImageError::Limits(LimitError::from_kind(
-                LimitErrorKind::InsufficientMemory,
-            )));
+    pub fn from_dynamic_image(image: &DynamicImage) -> Self {
+        use image::ColorType::*;
+
+        match image.color() {
+            L8 | La8 | Rgb8 | Rgba8 | L16 | La16 | Rgb16 | Rgba16 => {
+                Self::from_pixels(image.dimensions(), image.color(), image.as_bytes())
+            }
+            // floating point images, such as HDR TIFFs, are stored with 16 bits per component
+            Rgb32F => Self::from_dynamic_image(&DynamicImage::ImageRgb16(image.to_rgb16())),
+            _ => Self::from_dynamic_image(&DynamicImage::ImageRgba16(image.to_rgba16())),
         }
+    }
 
-        let mut image_data = vec![0; num_image_bytes as usize];
-        image.read_image(&mut image_data)?;
+    /// Attaches an ICC profile to the image. Ignored for color spaces that
+    /// can't have one
+    pub fn with_icc_profile(mut self, icc: Vec<u8>) -> Self {
+        let icc_type = match self.color_space {
+            ColorSpace::Rgb => IccProfileType::Rgb,
+            ColorSpace::Greyscale => IccProfileType::Greyscale,
+            ColorSpace::Cmyk => IccProfileType::Cmyk,
+            _ => return self,
+        };
+        self.icc_profile = Some(IccProfile::new(icc, icc_type));
+        self
+    }
 
-        let color_bits = ColorBits::from(color_type);
-        let color_space = ColorSpace::from(color_type);
+    /// Embeds a JPEG file as-is, using the `DCTDecode` filter,
+    /// rather than storing the decoded pixels
+    pub fn from_jpeg(data: Vec<u8>) -> Result<Self, ImageError> {
+        use image::error::{DecodingError, ImageFormatHint};
+
+        let header = JpegHeader::parse(&data).ok_or_else(|| {
+            ImageError::Decoding(DecodingError::new(
+                ImageFormatHint::Exact(image::ImageFormat::Jpeg),
+                "missing or unsupported start of frame",
+            ))
+        })?;
+
+        let color_space = match header.components {
+            1 => ColorSpace::Greyscale,
+            3 => ColorSpace::Rgb,
+            4 => ColorSpace::Cmyk,
+            _ => {
+                return Err(ImageError::Decoding(DecodingError::new(
+                    ImageFormatHint::Exact(image::ImageFormat::Jpeg),
+                    "unsupported number of color components",
+                )))
+            }
+        };
 
-        Ok(Self {
-            width: Px(dim.0 as usize),
-            height: Px(dim.1 as usize),
+        let image = Self {
+            width: Px(header.width as usize),
+            height: Px(header.height as usize),
             color_space,
-            bits_per_component: color_bits,
-            image_data,
+            bits_per_component: ColorBits::Bit8,
+            image_data: data,
             interpolate: true,
-            image_filter: None,
+            image_filter: Some(ImageFilter::DCT {
+                inverted: header.components == 4 && header.adobe,
+            }),
+            smask: None,
+            icc_profile: None,
             clipping_bbox: None,
+        };
+        Ok(match header.icc_profile {
+            Some(icc) => image.with_icc_profile(icc),
+            None => image,
         })
     }
 
-    pub fn from_dynamic_image(image: &DynamicImage) -> Self {
-        let dim = image.dimensions();
-        let color_type = image.color();
-        let data = image.as_bytes().to_vec();
-        let color_bits = ColorBits::from(color_type);
-        let color_space = ColorSpace::from(color_type);
+    /// Losslessly compresses the raw pixels with `FlateDecode` and PNG predictors.
+    /// Any alpha channel is split off into a soft mask.
+    fn from_pixels((width, height): (u32, u32), color_type: image::ColorType, data: &[u8]) -> Self {
+        let bits_per_component = ColorBits::from(color_type);
+        let bytes_per_component = match bits_per_component {
+            ColorBits::Bit16 => 2,
+            _ => 1,
+        };
+
+        // 16 bit samples are decoded in native byte order, but PDF expects big-endian
+        let data: Cow<[u8]> = if bytes_per_component == 2 {
+            data.chunks_exact(2)
+                .flat_map(|c| u16::from_ne_bytes([c[0], c[1]]).to_be_bytes())
+                .collect::<Vec<u8>>()
+                .into()
+        } else {
+            data.into()
+        };
+
+        let (color_space, data, alpha) = match ColorSpace::from(color_type) {
+            ColorSpace::Rgba => {
+                let (color, alpha) = split_alpha(&data, 4, bytes_per_component);
+                (ColorSpace::Rgb, color, alpha)
+            }
+            ColorSpace::GreyscaleAlpha => {
+                let (color, alpha) = split_alpha(&data, 2, bytes_per_component);
+                (ColorSpace::Greyscale, color, alpha)
+            }
+            color_space => (color_space, data.to_vec(), None),
+        };
+
+        let smask = alpha.map(|alpha| {
+            Box::new(Self::compressed(
+                (width, height),
+                ColorSpace::Greyscale,
+                bits_per_component,
+                &alpha,
+            ))
+        });
+
+        Self {
+            smask,
+            ..Self::compressed((width, height), color_space, bits_per_component, &data)
+        }
+    }
+
+    fn compressed(
+        (width, height): (u32, u32),
+        color_space: ColorSpace,
+        bits_per_component: ColorBits,
+        data: &[u8],
+    ) -> Self {
+        use flate2::{write::ZlibEncoder, Compression};
+        use std::io::Write;
+
+        let colors = color_space.components();
+        let bytes_per_pixel = (colors * i64::from(bits_per_component) as usize / 8).max(1);
+        let predicted = png_predict(data, bytes_per_pixel, bytes_per_pixel * width as usize);
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
+        encoder.write_all(&predicted).unwrap();
+        let image_data = encoder.finish().unwrap();
 
         Self {
-            width: Px(dim.0 as usize),
-            height: Px(dim.1 as usize),
+            width: Px(width as usize),
+            height: Px(height as usize),
             color_space,
-            bits_per_component: color_bits,
-            image_data: data,
+            bits_per_component,
+            image_data,
             interpolate: true,
-            image_filter: None,
+            image_filter: Some(ImageFilter::Flate),
+            smask: None,
+            icc_profile: None,
             clipping_bbox: None,
         }
     }
+
+    fn hash_content<H: std::hash::Hasher>(&self, state: &mut H) {
+        use std::hash::Hash;
+
+        self.width.0.hash(state);
+        self.height.0.hash(state);
+        <&'static str>::from(self.color_space).hash(state);
+        i64::from(self.bits_per_component).hash(state);
+        self.interpolate.hash(state);
+        format!("{:?}", self.image_filter).hash(state);
+        self.image_data.hash(state);
+        if let Some(smask) = &self.smask {
+            smask.hash_content(state);
+        }
+        if let Some(icc_profile) = &self.icc_profile {
+            icc_profile.icc.hash(state);
+        }
+    }
+
+    /// Converts the image into a stream, adding the soft mask and ICC profile
+    /// to the document first since they must be referenced indirectly
+    pub fn into_with_document(mut self, doc: &mut lopdf::Document) -> lopdf::Stream {
+        use lopdf::Object::*;
+
+        let smask = self.smask.take();
+        let icc_profile = self.icc_profile.take();
+        let mut stream: lopdf::Stream = self.into();
+        if let Some(smask) = smask {
+            let smask = smask.into_with_document(doc);
+            let smask_id = doc.add_object(smask);
+            stream.dict.set("SMask", Reference(smask_id));
+        }
+        if let Some(icc_profile) = icc_profile {
+            let icc_id = doc.add_object(lopdf::Stream::from(icc_profile));
+            stream.dict.set(
+                "ColorSpace",
+                Array(vec![Name("ICCBased".into()), Reference(icc_id)]),
+            );
+        }
+        XObject::compress_stream(stream)
+    }
+}
+
+/// Splits interleaved pixels into the color channels and the alpha channel.
+/// Returns no alpha channel if every pixel is fully opaque
+fn split_alpha(
+    data: &[u8],
+    channels: usize,
+    bytes_per_component: usize,
+) -> (Vec<u8>, Option<Vec<u8>>) {
+    let pixel_len = channels * bytes_per_component;
+    let color_len = pixel_len - bytes_per_component;
+
+    let mut color = Vec::with_capacity(data.len() / pixel_len * color_len);
+    let mut alpha = Vec::with_capacity(data.len() / pixel_len * bytes_per_component);
+    for pixel in data.chunks_exact(pixel_len) {
+        color.extend_from_slice(&pixel[..color_len]);
+        alpha.extend_from_slice(&pixel[color_len..]);
+    }
+
+    let opaque = alpha.iter().all(|&a| a == u8::MAX);
+    (color, (!opaque).then_some(alpha))
+}
+
+/// Applies the PNG row filters, as expected by `/Predictor 15`. Each row picks
+/// the filter with the smallest sum of absolute differences, as suggested by the PNG spec
+fn png_predict(data: &[u8], bytes_per_pixel: usize, row_len: usize) -> Vec<u8> {
+    if row_len == 0 {
+        return vec![];
+    }
+
+    let mut out = Vec::with_capacity(data.len() + data.len() / row_len);
+    let zeros = vec![0; row_len];
+    let mut prev = zeros.as_slice();
+    let mut candidates = [(); 5].map(|_| vec![0u8; row_len]);
+
+    for row in data.chunks(row_len) {
+        for (filter, buf) in candidates.iter_mut().enumerate() {
+            for i in 0..row.len() {
+                let a = if i >= bytes_per_pixel {
+                    row[i - bytes_per_pixel]
+                } else {
+                    0
+                };
+                let b = prev[i];
+                let c = if i >= bytes_per_pixel {
+                    prev[i - bytes_per_pixel]
+                } else {
+                    0
+                };
+                let predicted = match filter {
+                    0 => 0,
+                    1 => a,
+                    2 => b,
+                    3 => ((a as u16 + b as u16) / 2) as u8,
+                    _ => paeth(a, b, c),
+                };
+                buf[i] = row[i].wrapping_sub(predicted);
+            }
+        }
+
+        let (filter, best) = candidates
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, buf)| {
+                buf[..row.len()]
+                    .iter()
+                    .map(|&x| (x as i8).unsigned_abs() as u32)
+                    .sum::<u32>()
+            })
+            .unwrap();
+        out.push(filter as u8);
+        out.extend_from_slice(&best[..row.len()]);
+        prev = row;
+    }
+    out
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let pa = (p - a as i16).abs();
+    let pb = (p - b as i16).abs();
+    let pc = (p - c as i16).abs();
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+/// The parts of a JPEG header that the PDF image dictionary needs
+struct JpegHeader {
+    width: u16,
+    height: u16,
+    components: u8,
+    /// Adobe APP14 marker, which means CMYK data is stored inverted
+    adobe: bool,
+    /// ICC profile from the APP2 markers
+    icc_profile: Option<Vec<u8>>,
+}
+
+impl JpegHeader {
+    fn parse(data: &[u8]) -> Option<Self> {
+        let mut data = data.strip_prefix(&[0xFF, 0xD8])?;
+        let mut adobe = false;
+        // the profile may be split over several markers, each with its sequence number
+        let mut icc_chunks = vec![];
+        loop {
+            let [0xFF, marker, len_hi, len_lo, rest @ ..] = data else {
+                return None;
+            };
+            let len = u16::from_be_bytes([*len_hi, *len_lo]) as usize;
+            let segment = rest.get(..len.checked_sub(2)?)?;
+            match marker {
+                0xEE => adobe |= segment.starts_with(b"Adobe"),
+                0xE2 => {
+                    if let Some([seq, _count, chunk @ ..]) = segment.strip_prefix(b"ICC_PROFILE\0")
+                    {
+                        icc_chunks.push((*seq, chunk));
+                    }
+                }
+                // start of frame, except for DHT (C4), JPG (C8) and DAC (CC)
+                0xC0..=0xCF if !matches!(marker, 0xC4 | 0xC8 | 0xCC) => {
+                    let [_precision, h1, h0, w1, w0, components, ..] = segment else {
+                        return None;
+                    };
+                    icc_chunks.sort_by_key(|(seq, _)| *seq);
+                    let icc_profile = (!icc_chunks.is_empty()).then(|| {
+                        icc_chunks
+                            .iter()
+                            .flat_map(|(_, chunk)| *chunk)
+                            .copied()
+                            .collect()
+                    });
+                    return Some(Self {
+                        width: u16::from_be_bytes([*w1, *w0]),
+                        height: u16::from_be_bytes([*h1, *h0]),
+                        components: *components,
+                        adobe,
+                        icc_profile,
+                    });
+                }
+                _ => {}
+            }
+            data = &rest[len - 2..];
+        }
+    }
 }
 
 impl From<ImageXObject> for lopdf::Stream {
@@ -240,13 +592,28 @@ impl From<ImageXObject> for lopdf::Stream {
             let params = match filter {
                 // TODO technically we could use multiple filters,
                 // DCT as an exception!
-                ImageFilter::DCT => {
+                ImageFilter::DCT { inverted } => {
+                    let mut params =
+                        vec![("Filter", Array(vec![Name("DCTDecode".as_bytes().to_vec())]))];
+                    if inverted {
+                        params.push((
+                            "Decode",
+                            Array([1, 0, 1, 0, 1, 0, 1, 0].map(Integer).to_vec()),
+                        ));
+                    }
+                    params
+                }
+                ImageFilter::Flate => {
                     vec![
-                        ("Filter", Array(vec![Name("DCTDecode".as_bytes().to_vec())])),
-                        // not necessary, unless missing in the jpeg header
+                        ("Filter", Name("FlateDecode".as_bytes().to_vec())),
                         (
-                            "DecodeParams",
-                            Dictionary(lopdf::dictionary!("ColorTransform" => Integer(0))),
+                            "DecodeParms",
+                            Dictionary(lopdf::dictionary!(
+                                "Predictor" => Integer(15),
+                                "Colors" => Integer(img.color_space.components() as i64),
+                                "BitsPerComponent" => Integer(img.bits_per_component.into()),
+                                "Columns" => Integer(img.width.0 as i64)
+                            )),
                         ),
                     ]
                 }
@@ -276,7 +643,10 @@ pub enum ImageFilter {
     /// Lempel Ziv Welch compression, i.e. zip
     Lzw,
     /// Discrete Cosinus Transform, JPEG Baseline.
-    DCT,
+    /// CMYK JPEGs written by Adobe software store the components inverted
+    DCT { inverted: bool },
+    /// zlib/deflate compression, with PNG predictors
+    Flate,
     /// JPEG2000 aka JPX wavelet based compression.
     JPX,
 }
@@ -575,3 +945,67 @@ impl From<PostScriptXObject> for lopdf::Stream {
         lopdf::Stream::new(lopdf::Dictionary::new(), Vec::new())
     }
 }
+
+#[test]
+fn test_png_predict() {
+    // a gradient is best predicted from the pixel to the left
+    assert_eq!(png_predict(&[1, 2, 3, 4], 1, 4), vec![1, 1, 1, 1, 1]);
+    // a repeated row is best predicted from the row above
+    assert_eq!(
+        png_predict(&[9, 3, 7, 9, 3, 7], 1, 3),
+        vec![3, 9, 255, 6, 2, 0, 0, 0]
+    );
+}
+
+#[test]
+fn test_jpeg_icc_profile() {
+    let mut jpeg = vec![0xFF, 0xD8];
+    // the profile is split over two markers, stored out of order
+    for (seq, chunk) in [(2, b"def"), (1, b"abc")] {
+        jpeg.extend_from_slice(&[0xFF, 0xE2, 0, 2 + 12 + 2 + 3]);
+        jpeg.extend_from_slice(b"ICC_PROFILE\0");
+        jpeg.extend_from_slice(&[seq, 2]);
+        jpeg.extend_from_slice(chunk);
+    }
+    // baseline start of frame: 8 bit precision, 20x10 pixels, 3 components
+    jpeg.extend_from_slice(&[0xFF, 0xC0, 0, 8, 8, 0, 10, 0, 20, 3]);
+
+    let header = JpegHeader::parse(&jpeg).unwrap();
+    assert_eq!(
+        (header.width, header.height, header.components),
+        (20, 10, 3)
+    );
+    assert_eq!(header.icc_profile.as_deref(), Some(&b"abcdef"[..]));
+}
+
+#[test]
+fn test_shared_xobjects() {
+    let image = |image_data: Vec<u8>| {
+        XObject::Image(ImageXObject {
+            width: Px(2),
+            height: Px(1),
+            color_space: ColorSpace::Greyscale,
+            bits_per_component: ColorBits::Bit8,
+            interpolate: false,
+            image_data,
+            image_filter: None,
+            smask: None,
+            icc_profile: None,
+            clipping_bbox: None,
+        })
+    };
+    let shared_id = |xobject| match xobject {
+        XObject::Shared(id) => id,
+        _ => unreachable!(),
+    };
+
+    let mut doc = crate::printpdf::PdfDocument::empty("shared");
+    let first = shared_id(doc.add_shared_xobject(image(vec![0, 255])));
+    let again = shared_id(doc.add_shared_xobject(image(vec![0, 255])));
+    assert_eq!(first, again);
+    assert_eq!(doc.inner_doc.objects.len(), 1);
+
+    let other = shared_id(doc.add_shared_xobject(image(vec![255, 0])));
+    assert_ne!(first, other);
+    assert_eq!(doc.inner_doc.objects.len(), 2);
+}
@@ -0,0 +1,380 @@
+//! Punctuation and spacing of the text. comrak's smart punctuation turns straight quotes
+//! into English curly quotes, which are then swapped for the configured style.
+//!
+//! Lines are broken where UAX #14 allows, which in Chinese and Japanese is between almost
+//! any two characters. Kinsoku rules are stricter than that, so the breaks they don't
+//! allow are taken out with word joiners
+
+use comrak::nodes::{AstNode, NodeValue};
+
+use crate::{
+    config::{QuoteStyle, TypographyConfig},
+    math,
+};
+
+const NBSP: char = '\u{A0}';
+/// narrow no-break space, which French sets inside quotes and before `;:!?`
+const NNBSP: char = '\u{202F}';
+/// word joiner, an invisible character that there's no line break either side of
+const WJ: char = '\u{2060}';
+const THIN_SPACE: char = '\u{2009}';
+
+/// closing brackets, punctuation, small kana and iteration and prolonged sound marks,
+/// which can't start a line
+const NO_LINE_START: &str = "、。，．・：；？！‼⁇⁈⁉）」』】〕〉》｝］〙〗〟’”ヽヾゝゞ々〻ーぁぃぅぇぉっゃゅょゎゕゖァィゥェォッャュョヮヵヶㇰㇱㇲㇳㇴㇵㇶㇷㇸㇹㇺㇻㇼㇽㇾㇿ";
+/// opening brackets, which can't end a line
+const NO_LINE_END: &str = "（「『【〔〈《｛［〘〖〝‘“";
+/// full-width punctuation drawn in the left half of its em, eg `」`
+const CLOSING_PUNCTUATION: &str = "、。，．：；）」』】〕〉》｝］〙〗";
+/// full-width punctuation drawn in the right half of its em, eg `「`
+const OPENING_PUNCTUATION: &str = "（「『【〔〈《｛［〘〖";
+
+/// units that are kept on the same line as the number before them, eg "10 ms"
+const UNITS: &[&str] = &[
+    "%", "‰", "°", "°C", "°F", "K", "nm", "µm", "mm", "cm", "m", "km", "in", "ft", "mi", "mg", "g",
+    "kg", "t", "ml", "l", "L", "ns", "µs", "us", "ms", "s", "min", "h", "Hz", "kHz", "MHz", "GHz",
+    "b", "B", "kB", "KB", "MB", "GB", "TB", "KiB", "MiB", "GiB", "TiB", "bit", "bits", "px", "pt",
+    "V", "mV", "A", "mA", "W", "kW", "Ω",
+];
+
+impl QuoteStyle {
+    /// opening and closing double quotes, then opening and closing single quotes
+    fn marks(self) -> [&'static str; 4] {
+        match self {
+            QuoteStyle::English => ["“", "”", "‘", "’"],
+            QuoteStyle::British => ["‘", "’", "“", "”"],
+            QuoteStyle::German => ["„", "“", "‚", "‘"],
+            QuoteStyle::French => ["«\u{202F}", "\u{202F}»", "‹\u{202F}", "\u{202F}›"],
+        }
+    }
+}
+
+/// joins adjacent text nodes, as comrak splits text around brackets such as `[@fig:loop]`
+/// and around smart punctuation, along with the lines of a formula that runs over several
+pub fn merge_text_nodes<'a>(node: &'a AstNode<'a>) {
+    let mut child = node.first_child();
+    while let Some(current) = child {
+        let Some(next) = current.next_sibling() else {
+            break;
+        };
+        let merged = match (
+            &mut current.data.borrow_mut().value,
+            &next.data.borrow().value,
+        ) {
+            (NodeValue::Text(text), NodeValue::Text(next)) => {
+                text.push_str(next);
+                true
+            }
+            (NodeValue::Text(text), NodeValue::SoftBreak | NodeValue::LineBreak)
+                if math::in_formula(text) =>
+            {
+                text.push('\n');
+                true
+            }
+            _ => false,
+        };
+        if merged {
+            next.detach();
+        } else {
+            child = Some(next);
+        }
+    }
+}
+
+/// applies the quote style and non-breaking spaces to all the text in the document.
+/// `language` picks the quotes unless they're configured
+pub fn apply<'a>(root: &'a AstNode<'a>, config: &TypographyConfig, language: &str) {
+    let quotes = config
+        .quotes
+        .unwrap_or_else(|| QuoteStyle::for_language(language));
+    for node in root.descendants() {
+        merge_text_nodes(node);
+        if let NodeValue::Text(text) = &mut node.data.borrow_mut().value {
+            *text = around_formulas(text, |mut text| {
+                if config.smart_punctuation {
+                    text = localize_quotes(&text, quotes);
+                }
+                text = non_breaking_spaces(&text, config);
+                if config.kinsoku || config.cjk_latin_spacing {
+                    text = cjk_spacing(&text, config);
+                }
+                text
+            });
+        }
+    }
+}
+
+/// applies `f` to the text with each formula swapped for a lone mark, so that the quotes and
+/// spaces within formulas are left alone
+fn around_formulas(text: &str, f: impl FnOnce(String) -> String) -> String {
+    let formulas = math::find_formulas(text);
+    let mut outside = String::with_capacity(text.len());
+    let mut last = 0;
+    for (range, _) in &formulas {
+        outside.push_str(&text[last..range.start]);
+        outside.push(math::INLINE_START);
+        last = range.end;
+    }
+    outside.push_str(&text[last..]);
+
+    let outside = f(outside);
+    let mut pieces = outside.split(math::INLINE_START);
+    let mut joined = pieces.next().unwrap_or_default().to_owned();
+    for ((range, _), piece) in formulas.into_iter().zip(pieces) {
+        joined.push_str(&text[range]);
+        joined.push_str(piece);
+    }
+    joined
+}
+
+fn localize_quotes(text: &str, style: QuoteStyle) -> String {
+    let [open_double, close_double, open_single, close_single] = style.marks();
+    let mut localized = String::with_capacity(text.len());
+    let mut open_singles = 0;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        let next = chars.peek().copied();
+        match c {
+            '“' => localized.push_str(open_double),
+            '”' => localized.push_str(close_double),
+            '‘' => {
+                open_singles += 1;
+                localized.push_str(open_single);
+            }
+            // otherwise it's an apostrophe, as in "don’t"
+            '’' if open_singles > 0 && !next.map_or(false, char::is_alphanumeric) => {
+                open_singles -= 1;
+                localized.push_str(close_single);
+            }
+            ';' | ':' | '!' | '?'
+                if style == QuoteStyle::French
+                    && next.map_or(true, |c| c.is_whitespace() || matches!(c, '”' | '’')) =>
+            {
+                let trimmed = localized.trim_end_matches([' ', NBSP]).len();
+                localized.truncate(trimmed);
+                if !localized.is_empty() {
+                    localized.push(NNBSP);
+                }
+                localized.push(c);
+            }
+            c => localized.push(c),
+        }
+    }
+    localized
+}
+
+fn non_breaking_spaces(text: &str, config: &TypographyConfig) -> String {
+    let mut spaced = String::with_capacity(text.len());
+    let mut words = text.split(' ').peekable();
+    while let Some(word) = words.next() {
+        spaced.push_str(word);
+        let Some(next) = words.peek() else { break };
+        let unit = config.nbsp_before_units
+            && word.ends_with(|c: char| c.is_ascii_digit())
+            && is_unit(next);
+        let short = is_short_word(word, config.nbsp_after_short_words);
+        spaced.push(if unit || short { NBSP } else { ' ' });
+    }
+    spaced
+}
+
+/// applies the kinsoku rules to CJK text, and spaces it from Latin text
+fn cjk_spacing(text: &str, config: &TypographyConfig) -> String {
+    let mut spaced = String::with_capacity(text.len());
+    let mut previous = None;
+    for c in text.chars() {
+        if let Some(previous) = previous {
+            let latin = |a: char, b: char| is_cjk_letter(a) && b.is_ascii_alphanumeric();
+            if config.kinsoku
+                && (is_cjk(c) || is_cjk(previous))
+                && (NO_LINE_START.contains(c) || NO_LINE_END.contains(previous))
+            {
+                spaced.push(WJ);
+            } else if config.cjk_latin_spacing && (latin(previous, c) || latin(c, previous)) {
+                spaced.push(THIN_SPACE);
+            }
+        }
+        spaced.push(c);
+        previous = Some(c);
+    }
+    spaced
+}
+
+/// Takes out the blank half of full-width punctuation followed by other punctuation, or of
+/// opening brackets after punctuation that wasn't compressed. Takes the character and
+/// advance of each glyph of a line, and returns how far back each one moves along with how
+/// much shorter the line gets. Word joiners between the punctuation don't keep it apart
+pub fn compress_punctuation(glyphs: &[(char, f32)]) -> (Vec<f32>, f32) {
+    // whether the character is opening punctuation, or None if it isn't punctuation at all
+    let punctuation = |c: char| {
+        let opening = OPENING_PUNCTUATION.contains(c);
+        (opening || CLOSING_PUNCTUATION.contains(c)).then_some(opening)
+    };
+    let mut shifts = Vec::with_capacity(glyphs.len());
+    let mut shift = 0.0;
+    let mut previous = None;
+    let mut compressed = false;
+    for (i, &(c, width)) in glyphs.iter().enumerate() {
+        shifts.push(shift);
+        if c == WJ {
+            continue;
+        }
+        let this = punctuation(c);
+        let next = glyphs[i + 1..]
+            .iter()
+            .map(|&(c, _)| c)
+            .find(|&c| c != WJ)
+            .and_then(punctuation);
+        compressed = match this {
+            Some(false) => next.is_some(),
+            Some(true) => previous.is_some() && !compressed,
+            None => false,
+        };
+        if compressed {
+            if this == Some(true) {
+                shifts[i] += width / 2.0;
+            }
+            shift += width / 2.0;
+        }
+        previous = this;
+    }
+    (shifts, shift)
+}
+
+/// whether the character is Chinese, Japanese or Korean, including full-width punctuation
+pub fn is_cjk(c: char) -> bool {
+    matches!(
+        c,
+        '\u{1100}'..='\u{11FF}'
+            | '\u{2E80}'..='\u{2FDF}'
+            | '\u{3000}'..='\u{31FF}'
+            | '\u{3400}'..='\u{4DBF}'
+            | '\u{4E00}'..='\u{9FFF}'
+            | '\u{AC00}'..='\u{D7AF}'
+            | '\u{F900}'..='\u{FAFF}'
+            | '\u{FE30}'..='\u{FE4F}'
+            | '\u{FF00}'..='\u{FFEF}'
+            | '\u{20000}'..='\u{3FFFF}'
+    )
+}
+
+/// ideographs, kana and hangul, but not punctuation
+fn is_cjk_letter(c: char) -> bool {
+    is_cjk(c) && c.is_alphanumeric()
+}
+
+/// whether the word starts with a unit, ignoring punctuation after it, eg "ms,"
+fn is_unit(word: &str) -> bool {
+    let end = word
+        .find(|c: char| !(c.is_alphanumeric() || matches!(c, '%' | '‰' | '°')))
+        .unwrap_or(word.len());
+    UNITS.contains(&&word[..end])
+}
+
+/// whether the word has at most `max` letters, ignoring punctuation before it, eg "(a"
+fn is_short_word(word: &str, max: usize) -> bool {
+    let word = word.trim_start_matches(|c: char| !c.is_alphanumeric());
+    !word.is_empty() && word.chars().all(char::is_alphabetic) && word.chars().count() <= max
+}
+
+#[test]
+fn test_localize_quotes() {
+    let text = "“Don’t ‘poll’ it,” she said.";
+    assert_eq!(
+        localize_quotes(text, QuoteStyle::German),
+        "„Don’t ‚poll‘ it,“ she said."
+    );
+    assert_eq!(
+        localize_quotes("“Quoi ?” Oui!", QuoteStyle::French),
+        "«\u{202F}Quoi\u{202F}?\u{202F}» Oui\u{202F}!"
+    );
+    assert_eq!(QuoteStyle::for_language("de-AT"), QuoteStyle::German);
+    assert_eq!(QuoteStyle::for_language("en-GB"), QuoteStyle::British);
+    assert_eq!(QuoteStyle::for_language("en-US"), QuoteStyle::English);
+}
+
+#[test]
+fn test_apply_quotes() {
+    use comrak::{parse_document, Arena, ComrakOptions};
+
+    let mut options = ComrakOptions::default();
+    options.parse.smart = true;
+    let quoted = |config: &TypographyConfig, language: &str| {
+        let arena = Arena::new();
+        let root = parse_document(&arena, "\"Hallo\", sagte sie.", &options);
+        apply(root, config, language);
+        let paragraph = root.first_child().unwrap();
+        let text = match &paragraph.first_child().unwrap().data.borrow().value {
+            NodeValue::Text(text) => text.clone(),
+            _ => panic!("expected the paragraph's text"),
+        };
+        text
+    };
+
+    // a German book gets German quotes, unless others are configured
+    let config = TypographyConfig::default();
+    assert_eq!(quoted(&config, "de"), "„Hallo“, sagte sie.");
+    assert_eq!(quoted(&config, "en-US"), "“Hallo”, sagte sie.");
+    let config = TypographyConfig {
+        quotes: Some(QuoteStyle::British),
+        ..Default::default()
+    };
+    assert_eq!(quoted(&config, "de"), "‘Hallo’, sagte sie.");
+}
+
+#[test]
+fn test_cjk_spacing() {
+    let config = TypographyConfig {
+        cjk_latin_spacing: true,
+        ..TypographyConfig::default()
+    };
+    assert_eq!(
+        cjk_spacing("「Rustの本」です。", &config),
+        "「\u{2060}Rust\u{2009}の本\u{2060}」です\u{2060}。"
+    );
+    // left alone in English
+    assert_eq!(cjk_spacing("“Hi!”", &config), "“Hi!”");
+}
+
+#[test]
+fn test_compress_punctuation() {
+    // the full stop loses its blank right half, so the bracket after it moves back
+    assert_eq!(
+        compress_punctuation(&[('。', 12.0), ('」', 12.0)]),
+        (vec![0.0, 6.0], 6.0)
+    );
+    // the comma is compressed, so the bracket after it keeps its blank left half
+    assert_eq!(
+        compress_punctuation(&[('、', 12.0), ('「', 12.0), ('本', 12.0)]),
+        (vec![0.0, 6.0, 6.0], 6.0)
+    );
+    // an opening bracket after one that wasn't compressed loses its blank left half
+    assert_eq!(
+        compress_punctuation(&[('「', 12.0), ('「', 12.0), ('本', 12.0)]),
+        (vec![0.0, 6.0, 6.0], 6.0)
+    );
+    // kinsoku word joiners don't keep punctuation apart
+    assert_eq!(
+        compress_punctuation(&[
+            ('す', 12.0),
+            (WJ, 0.0),
+            ('。', 12.0),
+            (WJ, 0.0),
+            ('」', 12.0)
+        ]),
+        (vec![0.0, 0.0, 0.0, 6.0, 6.0], 6.0)
+    );
+    assert_eq!(
+        compress_punctuation(&[('本', 12.0), ('「', 12.0)]),
+        (vec![0.0, 0.0], 0.0)
+    );
+}
+
+#[test]
+fn test_non_breaking_spaces() {
+    let config = TypographyConfig::default();
+    assert_eq!(
+        non_breaking_spaces("I waited 10 ms, then a 5 second timeout", &config),
+        "I\u{A0}waited 10\u{A0}ms, then a\u{A0}5 second timeout"
+    );
+}